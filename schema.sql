@@ -1,8 +1,36 @@
+-- MySQL schema; the SQLite and PostgreSQL backends create these tables on startup
+-- info_hash is the 40-character hex form of the torrent's SHA-1 digest.
+-- Rows left in the older raw 20-byte form are rewritten on startup.
 CREATE TABLE IF NOT EXISTS torrents (
         info_hash VARCHAR(50) NOT NULL UNIQUE,
         complete INT NOT NULL,
         downloaded INT NOT NULL,
         incomplete INT NOT NULL,
-        balance BIGINT NOT NULL,
+        balance BIGINT UNSIGNED NOT NULL,
         PRIMARY KEY (info_hash)
 ) ENGINE = InnoDB;
+
+-- Tables created while balance was still signed need it widened by hand:
+-- ALTER TABLE torrents MODIFY balance BIGINT UNSIGNED NOT NULL;
+
+-- Only used when running as a private tracker
+CREATE TABLE IF NOT EXISTS users (
+        id INT UNSIGNED NOT NULL,
+        passkey VARCHAR(32) NOT NULL UNIQUE,
+        revoked BOOLEAN NOT NULL DEFAULT FALSE,
+        uploaded BIGINT UNSIGNED NOT NULL DEFAULT 0,
+        downloaded BIGINT UNSIGNED NOT NULL DEFAULT 0,
+        PRIMARY KEY (id)
+) ENGINE = InnoDB;
+
+-- One row per torrent and snatcher, recording the first completion only.
+-- Private trackers record kind 'user' with the user's ID; public trackers
+-- record kind 'peer' with the hex peer ID. The other column is unused.
+CREATE TABLE IF NOT EXISTS snatches (
+        info_hash VARCHAR(50) NOT NULL,
+        kind VARCHAR(4) NOT NULL,
+        user_id INT UNSIGNED NOT NULL DEFAULT 0,
+        peer_id VARCHAR(40) NOT NULL DEFAULT '',
+        completed_at BIGINT UNSIGNED NOT NULL,
+        PRIMARY KEY (info_hash, kind, user_id, peer_id)
+) ENGINE = InnoDB;
@@ -1,5 +1,5 @@
-use crate::bittorrent::{AnnounceResponse, ScrapeFile, ScrapeResponse};
-use bendy::encoding::{Error, SingleItemEncoder, ToBencode};
+use crate::bittorrent::{AnnounceResponse, DictionaryPeer, ScrapeFile, ScrapeResponse};
+use bendy::encoding::{AsString, Error, SingleItemEncoder, ToBencode};
 
 impl ToBencode for ScrapeFile {
     const MAX_DEPTH: usize = 1;
@@ -7,9 +7,9 @@ impl ToBencode for ScrapeFile {
     // bendy's emit methods return a result, which isn't immediately clear
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
         encoder.emit_dict(|mut e| {
-            e.emit_pair(b"complete", &self.complete)?;
-            e.emit_pair(b"downloaded", &self.downloaded)?;
-            e.emit_pair(b"incomplete", &self.incomplete)?;
+            e.emit_pair(b"complete", self.complete)?;
+            e.emit_pair(b"downloaded", self.downloaded)?;
+            e.emit_pair(b"incomplete", self.incomplete)?;
 
             if let Some(name) = &self.name {
                 e.emit_pair(b"name", name)?;
@@ -22,6 +22,26 @@ impl ToBencode for ScrapeFile {
     }
 }
 
+impl ToBencode for DictionaryPeer {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"ip", self.ip.to_string())?;
+
+            if let Some(peer_id) = &self.peer_id {
+                e.emit_pair(b"peer id", AsString(peer_id))?;
+            }
+
+            e.emit_pair(b"port", self.port)?;
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+}
+
 impl ToBencode for AnnounceResponse {
     const MAX_DEPTH: usize = 5;
 
@@ -31,7 +51,7 @@ impl ToBencode for AnnounceResponse {
         match &self.failure_reason {
             Some(reason) => {
                 encoder.emit_dict(|mut e| {
-                    e.emit_pair(b"failure_reason", reason)?;
+                    e.emit_pair(b"failure reason", reason)?;
 
                     Ok(())
                 })?;
@@ -39,17 +59,29 @@ impl ToBencode for AnnounceResponse {
 
             None => {
                 encoder.emit_dict(|mut e| {
-                    e.emit_pair(b"complete", &self.complete)?;
-                    e.emit_pair(b"incomplete", &self.incomplete)?;
-                    e.emit_pair(b"interval", &self.interval)?;
+                    e.emit_pair(b"complete", self.complete)?;
+                    e.emit_pair(b"incomplete", self.incomplete)?;
+                    e.emit_pair(b"interval", self.interval)?;
 
                     if let Some(min_interval) = &self.min_interval {
-                        e.emit_pair(b"min_interval", min_interval)?;
+                        e.emit_pair(b"min interval", min_interval)?;
                     }
 
-                    e.emit_pair(b"peers", &self.peersv4_as_compact())?;
-                    e.emit_pair(b"peers6", &self.peersv6_as_compact())?;
-                    e.emit_pair(b"tracker_id", &self.tracker_id)?;
+                    match &self.dictionary_peers {
+                        // BEP 03: non-compact peers are a list of dictionaries
+                        Some(peers) => e.emit_pair(b"peers", peers)?,
+
+                        // BEP 23 and BEP 07: compact peers are
+                        // byte strings, not lists of integers
+                        None => {
+                            e.emit_pair(b"peers", AsString(self.peersv4_as_compact()))?;
+                            e.emit_pair(b"peers6", AsString(self.peersv6_as_compact()))?;
+                        }
+                    }
+
+                    if !self.tracker_id.is_empty() {
+                        e.emit_pair(b"tracker id", &self.tracker_id)?;
+                    }
 
                     Ok(())
                 })?;
@@ -69,7 +101,7 @@ impl ToBencode for ScrapeResponse {
         match &self.failure_reason {
             Some(reason) => {
                 encoder.emit_dict(|mut e| {
-                    e.emit_pair(b"failure_reason", reason)?;
+                    e.emit_pair(b"failure reason", reason)?;
 
                     Ok(())
                 })?;
@@ -99,9 +131,11 @@ pub fn encode_scrape_response(response: ScrapeResponse) -> Vec<u8> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::bittorrent::{AnnounceResponse, CompactPeerv4, CompactPeerv6, ScrapeResponse};
-    use std::net::{Ipv4Addr, Ipv6Addr};
-    use std::time::Instant;
+    use crate::bittorrent::{
+        AnnounceResponse, CompactPeerv4, CompactPeerv6, DictionaryPeer, InfoHash, PeerId,
+        ScrapeResponse,
+    };
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
     #[test]
     fn announce_response_encoding() {
@@ -114,9 +148,7 @@ mod tests {
             port: 6894,
         };
 
-        let mut peers: Vec<CompactPeerv4> = Vec::new();
-        peers.push(peerv4_1);
-        peers.push(peerv4_2);
+        let peers: Vec<CompactPeerv4> = vec![peerv4_1, peerv4_2];
 
         let peerv6_1 = CompactPeerv6 {
             ip: Ipv6Addr::new(
@@ -131,15 +163,19 @@ mod tests {
             port: 6699,
         };
 
-        let mut peers6: Vec<CompactPeerv6> = Vec::new();
-        peers6.push(peerv6_1);
-        peers6.push(peerv6_2);
+        let peers6: Vec<CompactPeerv6> = vec![peerv6_1, peerv6_2];
 
         let response = AnnounceResponse::new(60, 100, 23, peers, peers6).unwrap();
 
         let encoded = encode_announce_response(response);
 
-        assert_eq!(encoded.as_slice(), &b"d8:completei100e10:incompletei23e8:intervali60e5:peersli127ei0ei0ei1ei26ei237ei255ei255ei255ei255ei26ei238ee6:peers6li32ei1ei13ei184ei133ei163ei0ei0ei0ei0ei138ei46ei3ei112ei115ei52ei26ei25ei254ei128ei0ei0ei0ei0ei0ei0ei2ei2ei179ei255ei254ei30ei131ei41ei26ei43ee10:tracker_id0:e"[..]);
+        assert_eq!(
+            encoded.as_slice(),
+            &b"d8:completei100e10:incompletei23e8:intervali60e\
+               5:peers12:\x7f\x00\x00\x01\x1a\xed\xff\xff\xff\xff\x1a\xee\
+               6:peers636:\x20\x01\x0d\xb8\x85\xa3\x00\x00\x00\x00\x8a\x2e\x03\x70\x73\x34\x1a\x19\
+               \xfe\x80\x00\x00\x00\x00\x00\x00\x02\x02\xb3\xff\xfe\x1e\x83\x29\x1a\x2be"[..]
+        );
     }
 
     #[test]
@@ -149,13 +185,13 @@ mod tests {
 
         let encoded = encode_announce_response(failure);
 
-        assert_eq!(encoded.as_slice(), b"d14:failure_reason4:ouche");
+        assert_eq!(encoded.as_slice(), b"d14:failure reason4:ouche");
     }
 
     #[test]
     fn scrape_response_encoding() {
         let file1 = ScrapeFile {
-            info_hash: "blah".to_string(),
+            info_hash: InfoHash(*b"ABCDEFGHIJKLMNOPQRST"),
             complete: 1,
             downloaded: 2,
             incomplete: 3,
@@ -163,7 +199,7 @@ mod tests {
         };
 
         let file2 = ScrapeFile {
-            info_hash: "test".to_string(),
+            info_hash: InfoHash(*b"TSRQPONMLKJIHGFEDCBA"),
             complete: 4000,
             downloaded: 5678,
             incomplete: 785,
@@ -171,11 +207,154 @@ mod tests {
         };
 
         let mut scrape_response = ScrapeResponse::new().unwrap();
-        scrape_response.add_file("ABCDEFGHIJKLMNOPQRST".to_string(), file1);
-        scrape_response.add_file("TSRQPONMLKJIHGFEDCBA".to_string(), file2);
+        scrape_response.add_file(file1.info_hash, file1);
+        scrape_response.add_file(file2.info_hash, file2);
 
         let encoded = encode_scrape_response(scrape_response);
 
         assert_eq!(encoded.as_slice(), &b"d5:filesd20:ABCDEFGHIJKLMNOPQRSTd8:completei1e10:downloadedi2e10:incompletei3e4:name4:teste20:TSRQPONMLKJIHGFEDCBAd8:completei4000e10:downloadedi5678e10:incompletei785e4:name11:Reflectionseee"[..]);
     }
+
+    // The following are built by hand to cover the key spellings and
+    // peer encodings described in BEP 3, 7, 23 and 48. Only the last
+    // one is an example taken verbatim from a BEP.
+
+    #[test]
+    fn failure_reason_key() {
+        let failure = AnnounceResponse::failure("Unregistered torrent".to_string());
+
+        assert_eq!(
+            encode_announce_response(failure).as_slice(),
+            &b"d14:failure reason20:Unregistered torrente"[..]
+        );
+    }
+
+    #[test]
+    fn optional_announce_keys() {
+        let mut response = AnnounceResponse::new(1800, 1, 2, vec![], vec![]).unwrap();
+        response.min_interval = Some(900);
+        response.tracker_id = "tyto".to_string();
+
+        assert_eq!(
+            encode_announce_response(response).as_slice(),
+            &b"d8:completei1e10:incompletei2e8:intervali1800e12:min intervali900e\
+               5:peers0:6:peers60:10:tracker id4:tytoe"[..]
+        );
+    }
+
+    #[test]
+    fn empty_tracker_id_omitted() {
+        let response = AnnounceResponse::new(60, 0, 0, vec![], vec![]).unwrap();
+
+        assert_eq!(
+            encode_announce_response(response).as_slice(),
+            &b"d8:completei0e10:incompletei0e8:intervali60e5:peers0:6:peers60:e"[..]
+        );
+    }
+
+    #[test]
+    fn compact_peers_are_byte_strings() {
+        let peers = vec![CompactPeerv4 {
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 6881,
+        }];
+        let response = AnnounceResponse::new(60, 1, 0, peers, vec![]).unwrap();
+
+        assert_eq!(
+            encode_announce_response(response).as_slice(),
+            &b"d8:completei1e10:incompletei0e8:intervali60e\
+               5:peers6:\x0a\x00\x00\x01\x1a\xe16:peers60:e"[..]
+        );
+    }
+
+    #[test]
+    fn compact_peers6_are_byte_strings() {
+        let peers6 = vec![CompactPeerv6 {
+            ip: Ipv6Addr::LOCALHOST,
+            port: 6881,
+        }];
+        let response = AnnounceResponse::new(60, 0, 1, vec![], peers6).unwrap();
+
+        assert_eq!(
+            encode_announce_response(response).as_slice(),
+            &b"d8:completei0e10:incompletei1e8:intervali60e5:peers0:\
+               6:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1e"[..]
+        );
+    }
+
+    #[test]
+    fn dictionary_peers() {
+        let peers = vec![
+            DictionaryPeer {
+                peer_id: Some(PeerId(*b"-TR2940-k1pz1l3pbd00")),
+                ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                port: 6881,
+            },
+            DictionaryPeer {
+                peer_id: None,
+                ip: IpAddr::V6(Ipv6Addr::LOCALHOST),
+                port: 51413,
+            },
+        ];
+        let response = AnnounceResponse::non_compact(60, 1, 1, peers);
+
+        assert_eq!(
+            encode_announce_response(response).as_slice(),
+            &b"d8:completei1e10:incompletei1e8:intervali60e\
+               5:peersld2:ip8:10.0.0.17:peer id20:-TR2940-k1pz1l3pbd004:porti6881ee\
+               d2:ip3:::14:porti51413eeee"[..]
+        );
+    }
+
+    #[test]
+    fn scrape_binary_info_hash() {
+        let info_hash = InfoHash(*b"\x90\x28\x9f\xd3M\xfc\x1c\xf8\xf3\x16\xa2h\xad\xd85L\x853DX");
+        let file = ScrapeFile {
+            info_hash,
+            complete: 5,
+            downloaded: 50,
+            incomplete: 10,
+            name: None,
+        };
+
+        let mut scrape_response = ScrapeResponse::new().unwrap();
+        scrape_response.add_file(info_hash, file);
+
+        assert_eq!(
+            encode_scrape_response(scrape_response).as_slice(),
+            &b"d5:filesd20:\x90\x28\x9f\xd3M\xfc\x1c\xf8\xf3\x16\xa2h\xad\xd85L\x853DX\
+               d8:completei5e10:downloadedi50e10:incompletei10eeee"[..]
+        );
+    }
+
+    #[test]
+    fn scrape_failure_reason_key() {
+        let failure = ScrapeResponse::failure("Malformed scrape request".to_string());
+
+        assert_eq!(
+            encode_scrape_response(failure).as_slice(),
+            &b"d14:failure reason24:Malformed scrape requeste"[..]
+        );
+    }
+
+    #[test]
+    fn bep48_scrape_example() {
+        let info_hash = InfoHash(*b"....................");
+        let file = ScrapeFile {
+            info_hash,
+            complete: 5,
+            downloaded: 50,
+            incomplete: 10,
+            name: None,
+        };
+
+        let mut scrape_response = ScrapeResponse::new().unwrap();
+        scrape_response.add_file(info_hash, file);
+
+        assert_eq!(
+            encode_scrape_response(scrape_response).as_slice(),
+            &b"d5:filesd20:....................d8:completei5e10:downloadedi50e10:incompletei10eeee"
+                [..]
+        );
+    }
 }
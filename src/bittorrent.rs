@@ -3,17 +3,83 @@
 // https://wiki.theory.org/index.php/BitTorrentSpecification
 
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::Instant;
 
 use bytes::BufMut;
-use percent_encoding;
 use regex::Regex;
-use url::form_urlencoded;
+use serde::{Deserialize, Serialize};
 
 use crate::errors::ClientError;
-use crate::util::{string_to_event, Event};
+use crate::storage::UserId;
+use crate::util::{from_hex, parse_query, string_to_event, to_hex, Event};
+
+// Info hashes are raw SHA-1 digests and are very likely to contain
+// bytes that are not valid UTF-8, so they are kept as plain bytes.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct InfoHash(pub [u8; 20]);
+
+impl InfoHash {
+    // Accepts either the 20 raw bytes sent by clients or
+    // the 40-character hex form used by operators and databases
+    pub fn from_bytes(bytes: &[u8]) -> Option<InfoHash> {
+        match bytes.len() {
+            20 => Some(InfoHash(bytes.try_into().ok()?)),
+            40 => Some(InfoHash(from_hex(bytes)?.as_slice().try_into().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+impl AsRef<[u8]> for InfoHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&to_hex(&self.0))
+    }
+}
+
+impl fmt::Debug for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InfoHash({})", self)
+    }
+}
+
+// Peer IDs usually start with a readable client prefix,
+// but the remainder is free to be arbitrary bytes.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    pub fn from_bytes(bytes: &[u8]) -> Option<PeerId> {
+        Some(PeerId(bytes.try_into().ok()?))
+    }
+}
+
+impl AsRef<[u8]> for PeerId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&to_hex(&self.0))
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerId({})", self)
+    }
+}
 
 trait Compact {
     fn compact(&self) -> Vec<u8>;
@@ -23,18 +89,24 @@ trait Compact {
 // with a trait, but there's only two types right now, so it's not a lot of work
 #[derive(Clone, Eq, Ord, PartialOrd, Debug)]
 pub struct Peerv4 {
-    pub peer_id: String,
+    pub peer_id: PeerId,
     pub ip: Ipv4Addr,
     pub port: u16,
     pub last_announced: Instant,
+    pub user_id: Option<UserId>,
+    pub uploaded: u64,   // Last total reported by the client
+    pub downloaded: u64, // Last total reported by the client
 }
 
 #[derive(Clone, Eq, Ord, PartialOrd, Debug)]
 pub struct Peerv6 {
-    pub peer_id: String,
+    pub peer_id: PeerId,
     pub ip: Ipv6Addr,
     pub port: u16,
     pub last_announced: Instant,
+    pub user_id: Option<UserId>,
+    pub uploaded: u64,   // Last total reported by the client
+    pub downloaded: u64, // Last total reported by the client
 }
 
 impl Compact for Peerv4 {
@@ -64,9 +136,8 @@ impl Compact for Peerv6 {
     }
 }
 
-// Compact responses are the default response type anyways, but the creation
-// of a CompactPeer type allows for the get_peers function to return peers
-// without cloning as strings are not included in peer responses.
+// Compact responses are the default response type anyways, and the
+// CompactPeer type keeps peer IDs and announce times out of them.
 #[derive(Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Debug)]
 pub struct CompactPeerv4 {
     pub ip: Ipv4Addr,
@@ -111,7 +182,8 @@ impl Compact for CompactPeerv6 {
  * depends upon it. Peer reaping requires a last_announced time, which
  * can't be used for hashing as the same client with a different announce
  * time would be considered different. Thus, a non-derived implementation
- * of Hash (and its required trait PartialEq) are necessary.
+ * of Hash (and its required trait PartialEq) are necessary. The user that a
+ * peer belongs to on a private tracker is left out for the same reason.
  */
 impl Hash for Peerv4 {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -147,6 +219,44 @@ pub enum Peer {
     V6(Peerv6),
 }
 
+impl Peer {
+    pub fn peer_id(&self) -> PeerId {
+        match self {
+            Peer::V4(p) => p.peer_id,
+            Peer::V6(p) => p.peer_id,
+        }
+    }
+
+    pub fn user_id(&self) -> Option<UserId> {
+        match self {
+            Peer::V4(p) => p.user_id,
+            Peer::V6(p) => p.user_id,
+        }
+    }
+
+    pub fn last_announced(&self) -> Instant {
+        match self {
+            Peer::V4(p) => p.last_announced,
+            Peer::V6(p) => p.last_announced,
+        }
+    }
+
+    // The totals the client reported in its last announce
+    pub fn transferred(&self) -> (u64, u64) {
+        match self {
+            Peer::V4(p) => (p.uploaded, p.downloaded),
+            Peer::V6(p) => (p.uploaded, p.downloaded),
+        }
+    }
+
+    pub fn set_user_id(&mut self, user_id: Option<UserId>) {
+        match self {
+            Peer::V4(p) => p.user_id = user_id,
+            Peer::V6(p) => p.user_id = user_id,
+        }
+    }
+}
+
 impl Compact for Peer {
     fn compact(&self) -> Vec<u8> {
         match self {
@@ -173,12 +283,12 @@ impl Compact for CompactPeer {
 
 #[derive(Debug)]
 pub struct AnnounceRequest {
-    pub info_hash: String,
+    pub info_hash: InfoHash,
     pub peer: Peer,
     pub port: u16,
-    pub uploaded: u32,
-    pub downloaded: u32,
-    pub left: u32,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
     pub compact: bool,
     pub no_peer_id: bool,
     pub event: Event,
@@ -189,19 +299,21 @@ pub struct AnnounceRequest {
 }
 
 impl AnnounceRequest {
+    #[allow(clippy::result_large_err)]
     pub fn new(
         url_string: &str,
         req_ip: Option<&str>,
     ) -> Result<AnnounceRequest, AnnounceResponse> {
-        let request_kv_pairs = form_urlencoded::parse(url_string.as_bytes()).into_owned();
+        let request_kv_pairs = parse_query(url_string);
 
-        let mut info_hash: String = "".to_string();
-        let mut peer_string: String = "".to_string();
+        let mut info_hash = None;
+        let mut peer_id = None;
         let mut port = 0;
         let mut uploaded = 0;
         let mut downloaded = 0;
         let mut left = 0;
-        let mut compact = false;
+        // Clients that don't say otherwise get the compact response
+        let mut compact = true;
         let mut no_peer_id = false;
         let mut event = Event::None;
         let mut ip = None;
@@ -211,19 +323,25 @@ impl AnnounceRequest {
 
         // If any request does not properly encode these paramters,
         // return an AnnounceFailure to be sent to the client
-        for (k, value) in request_kv_pairs {
+        for (k, raw_value) in request_kv_pairs {
+            let value = String::from_utf8_lossy(&raw_value).into_owned();
             match k.as_str() {
-                "info_hash" => {
-                    match percent_encoding::percent_decode(value.as_bytes()).decode_utf8() {
-                        Ok(s) => info_hash = s.to_string(),
-                        _ => {
-                            return Err(AnnounceResponse::failure(
-                                ClientError::MalformedAnnounce.text(),
-                            ))
-                        }
+                "info_hash" => match InfoHash::from_bytes(&raw_value) {
+                    Some(h) => info_hash = Some(h),
+                    _ => {
+                        return Err(AnnounceResponse::failure(
+                            ClientError::MalformedAnnounce.text(),
+                        ))
                     }
-                }
-                "peer_id" => peer_string = value,
+                },
+                "peer_id" => match PeerId::from_bytes(&raw_value) {
+                    Some(id) => peer_id = Some(id),
+                    _ => {
+                        return Err(AnnounceResponse::failure(
+                            ClientError::MalformedAnnounce.text(),
+                        ))
+                    }
+                },
                 "port" => match value.parse::<u16>() {
                     Ok(n) => port = n,
                     _ => {
@@ -232,7 +350,7 @@ impl AnnounceRequest {
                         ))
                     }
                 },
-                "uploaded" => match value.parse::<u32>() {
+                "uploaded" => match value.parse::<u64>() {
                     Ok(n) => uploaded = n,
                     _ => {
                         return Err(AnnounceResponse::failure(
@@ -240,7 +358,7 @@ impl AnnounceRequest {
                         ))
                     }
                 },
-                "downloaded" => match value.parse::<u32>() {
+                "downloaded" => match value.parse::<u64>() {
                     Ok(n) => downloaded = n,
                     _ => {
                         return Err(AnnounceResponse::failure(
@@ -248,7 +366,7 @@ impl AnnounceRequest {
                         ))
                     }
                 },
-                "left" => match value.parse::<u32>() {
+                "left" => match value.parse::<u64>() {
                     Ok(n) => left = n,
                     _ => {
                         return Err(AnnounceResponse::failure(
@@ -298,12 +416,15 @@ impl AnnounceRequest {
             }
         }
 
-        // This should not be the default value
-        if info_hash == "" {
-            return Err(AnnounceResponse::failure(
-                ClientError::MalformedAnnounce.text(),
-            ));
-        }
+        // Both of these are required to place the peer in a swarm
+        let (info_hash, peer_id) = match (info_hash, peer_id) {
+            (Some(h), Some(id)) => (h, id),
+            _ => {
+                return Err(AnnounceResponse::failure(
+                    ClientError::MalformedAnnounce.text(),
+                ))
+            }
+        };
 
         // Digusting unwrap sequence, but whatever.
         if ip.is_none() {
@@ -322,16 +443,22 @@ impl AnnounceRequest {
 
         let peer = match ip.unwrap() {
             IpAddr::V4(i) => Peer::V4(Peerv4 {
-                peer_id: peer_string,
+                peer_id,
                 ip: i,
                 port,
                 last_announced: Instant::now(),
+                user_id: None,
+                uploaded,
+                downloaded,
             }),
             IpAddr::V6(i) => Peer::V6(Peerv6 {
-                peer_id: peer_string,
+                peer_id,
                 ip: i,
                 port,
                 last_announced: Instant::now(),
+                user_id: None,
+                uploaded,
+                downloaded,
             }),
         };
 
@@ -353,8 +480,33 @@ impl AnnounceRequest {
     }
 }
 
+// The original dictionary model of a peer, used when a client asks
+// for a non-compact response. Both address families share one list.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DictionaryPeer {
+    pub peer_id: Option<PeerId>,
+    pub ip: IpAddr,
+    pub port: u16,
+}
+
+impl DictionaryPeer {
+    pub fn new(peer: &Peer, no_peer_id: bool) -> DictionaryPeer {
+        let (peer_id, ip, port) = match peer {
+            Peer::V4(p) => (p.peer_id, IpAddr::V4(p.ip), p.port),
+            Peer::V6(p) => (p.peer_id, IpAddr::V6(p.ip), p.port),
+        };
+
+        DictionaryPeer {
+            peer_id: if no_peer_id { None } else { Some(peer_id) },
+            ip,
+            port,
+        }
+    }
+}
+
 // Peer types are functionally the same, but due to different
-// byte lengths, they should be separated for client compatibility
+// byte lengths, they should be separated for client compatibility.
+// If dictionary_peers is present, it replaces both compact lists.
 #[derive(Default, Debug)]
 pub struct AnnounceResponse {
     pub failure_reason: Option<String>,
@@ -365,6 +517,7 @@ pub struct AnnounceResponse {
     pub incomplete: u32,
     pub peers: Vec<CompactPeerv4>,
     pub peers6: Vec<CompactPeerv6>,
+    pub dictionary_peers: Option<Vec<DictionaryPeer>>,
 }
 
 impl AnnounceResponse {
@@ -384,9 +537,25 @@ impl AnnounceResponse {
             incomplete,
             peers,
             peers6,
+            dictionary_peers: None,
         })
     }
 
+    pub fn non_compact(
+        interval: u32,
+        complete: u32,
+        incomplete: u32,
+        dictionary_peers: Vec<DictionaryPeer>,
+    ) -> AnnounceResponse {
+        AnnounceResponse {
+            interval,
+            complete,
+            incomplete,
+            dictionary_peers: Some(dictionary_peers),
+            ..Default::default()
+        }
+    }
+
     pub fn failure(reason: String) -> AnnounceResponse {
         AnnounceResponse {
             failure_reason: Some(reason),
@@ -413,7 +582,7 @@ impl AnnounceResponse {
 
 #[derive(Debug, Default)]
 pub struct ScrapeFile {
-    pub info_hash: String,
+    pub info_hash: InfoHash,
     pub complete: u32,
     pub downloaded: u32,
     pub incomplete: u32,
@@ -421,22 +590,20 @@ pub struct ScrapeFile {
 }
 
 pub struct ScrapeRequest {
-    pub info_hashes: Vec<String>,
+    pub info_hashes: Vec<InfoHash>,
 }
 
 impl ScrapeRequest {
     pub fn new(url_string: &str) -> Result<ScrapeRequest, ScrapeResponse> {
-        let request_kv_pairs = form_urlencoded::parse(url_string.as_bytes()).into_owned();
+        let request_kv_pairs = parse_query(url_string);
         let mut info_hashes = Vec::new();
 
+        // Info hashes are decoded the same way as in announces
+        // so that both requests agree on the torrent's key
         for (key, value) in request_kv_pairs {
-            match key.as_str() {
-                "info_hash" => info_hashes.push(value),
-                _ => {
-                    return Err(ScrapeResponse::failure(
-                        "Malformed scrape request".to_string(),
-                    ))
-                }
+            match (key.as_str(), InfoHash::from_bytes(&value)) {
+                ("info_hash", Some(h)) => info_hashes.push(h),
+                _ => return Err(ScrapeResponse::failure(ClientError::MalformedScrape.text())),
             }
         }
 
@@ -447,10 +614,11 @@ impl ScrapeRequest {
 #[derive(Default, Debug)]
 pub struct ScrapeResponse {
     pub failure_reason: Option<String>,
-    pub files: HashMap<String, ScrapeFile>,
+    pub files: HashMap<InfoHash, ScrapeFile>,
 }
 
 impl ScrapeResponse {
+    #[allow(clippy::result_unit_err)]
     pub fn new() -> Result<ScrapeResponse, ()> {
         Ok(ScrapeResponse {
             failure_reason: None,
@@ -465,7 +633,7 @@ impl ScrapeResponse {
         }
     }
 
-    pub fn add_file(&mut self, info_hash: String, scrape_file: ScrapeFile) {
+    pub fn add_file(&mut self, info_hash: InfoHash, scrape_file: ScrapeFile) {
         self.files.insert(info_hash, scrape_file);
     }
 }
@@ -491,6 +659,83 @@ mod tests {
         );
     }
 
+    #[test]
+    fn announce_binary_info_hash() {
+        let url_string = "info_hash=%90%28%9F%D3M%FC%1C%F8%F3%16%A2h%AD%D85L%853DX\
+             &peer_id=-TR2940-%FF%FEk1pz1l3pbd&port=6881&uploaded=0&downloaded=0\
+             &left=727955456&event=started&numwant=100&no_peer_id=1&compact=1";
+
+        let announce = AnnounceRequest::new(url_string, Some("127.0.0.1:6881")).unwrap();
+        assert_eq!(
+            announce.info_hash.to_string(),
+            "90289fd34dfc1cf8f316a268add8354c85334458"
+        );
+
+        match announce.peer {
+            Peer::V4(p) => assert_eq!(&p.peer_id.0[..10], b"-TR2940-\xff\xfe"),
+            Peer::V6(_) => panic!("Peer should be IPv4"),
+        }
+    }
+
+    #[test]
+    fn announce_bad_info_hash_length() {
+        let url_string = "info_hash=tooshort&peer_id=ABCDEFGHIJKLMNOPQRST&port=6881\
+             &uploaded=0&downloaded=0&left=0&compact=1";
+
+        assert!(AnnounceRequest::new(url_string, Some("127.0.0.1:6881")).is_err());
+    }
+
+    #[test]
+    fn announce_multi_terabyte_counters() {
+        // 6 TiB up and 5 TiB down of a 12 TiB torrent
+        let url_string = "info_hash=aaaaaaaaaaaaaaaaaaaa&peer_id=ABCDEFGHIJKLMNOPQRST&port=6881\
+             &uploaded=6597069766656&downloaded=5497558138880&left=7696581394432";
+
+        let announce = AnnounceRequest::new(url_string, Some("127.0.0.1:6881")).unwrap();
+        assert_eq!(announce.uploaded, 6 << 40);
+        assert_eq!(announce.downloaded, 5 << 40);
+        assert_eq!(announce.left, 7 << 40);
+        assert_eq!(announce.peer.transferred(), (6 << 40, 5 << 40));
+    }
+
+    #[test]
+    fn announce_compact_defaults() {
+        let base = "info_hash=aaaaaaaaaaaaaaaaaaaa&peer_id=ABCDEFGHIJKLMNOPQRST&port=6881&left=0";
+
+        let implicit = AnnounceRequest::new(base, Some("127.0.0.1:6881")).unwrap();
+        assert!(implicit.compact);
+        assert!(!implicit.no_peer_id);
+
+        let explicit = AnnounceRequest::new(
+            &format!("{}&compact=0&no_peer_id=1", base),
+            Some("127.0.0.1:6881"),
+        )
+        .unwrap();
+        assert!(!explicit.compact);
+        assert!(explicit.no_peer_id);
+    }
+
+    #[test]
+    fn dictionary_peer_no_peer_id() {
+        let peer = Peer::V6(Peerv6 {
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+            ip: Ipv6Addr::LOCALHOST,
+            port: 6881,
+            last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
+        });
+
+        let with_id = DictionaryPeer::new(&peer, false);
+        let without_id = DictionaryPeer::new(&peer, true);
+
+        assert_eq!(with_id.peer_id, Some(PeerId(*b"ABCDEFGHIJKLMNOPQRST")));
+        assert_eq!(without_id.peer_id, None);
+        assert_eq!(without_id.ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(without_id.port, 6881);
+    }
+
     #[test]
     fn announce_failure_return() {
         let failure_reason = "It's not you...no, it's just you".to_string();
@@ -512,9 +757,7 @@ mod tests {
             port: 6894,
         };
 
-        let mut peers: Vec<CompactPeerv4> = Vec::new();
-        peers.push(peerv4_1);
-        peers.push(peerv4_2);
+        let peers: Vec<CompactPeerv4> = vec![peerv4_1, peerv4_2];
 
         let peerv6_1 = CompactPeerv6 {
             ip: Ipv6Addr::new(
@@ -529,9 +772,7 @@ mod tests {
             port: 6699,
         };
 
-        let mut peers6: Vec<CompactPeerv6> = Vec::new();
-        peers6.push(peerv6_1);
-        peers6.push(peerv6_2);
+        let peers6: Vec<CompactPeerv6> = vec![peerv6_1, peerv6_2];
 
         let response = AnnounceResponse::new(60, 100, 23, peers, peers6);
 
@@ -541,10 +782,13 @@ mod tests {
     #[test]
     fn peerv4_compact_transform() {
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6681,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
         let mut localhost_port_byte_string = vec![];
@@ -559,17 +803,20 @@ mod tests {
     #[test]
     fn peerv6_compact_transform() {
         let peer = Peer::V6(Peerv6 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv6Addr::new(
                 0x2001, 0x0db8, 0x85a3, 0x0000, 0x0000, 0x8a2e, 0x0370, 0x7334,
             ),
             port: 6681,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
         let mut localhost_port_byte_string = vec![];
-        let localhost_decimal = 42540766452641154071740215577757643572 as u128;
-        let port = 6681 as u16;
+        let localhost_decimal = 42540766452641154071740215577757643572_u128;
+        let port = 6681_u16;
         localhost_port_byte_string.put_slice(&localhost_decimal.to_be_bytes());
         localhost_port_byte_string.put_slice(&port.to_be_bytes());
 
@@ -595,13 +842,41 @@ mod tests {
         assert_eq!(
             scrape.info_hashes,
             vec![
-                "aaaaaaaaaaaaaaaaaaaa",
-                "bbbbbbbbbbbbbbbbbbbb",
-                "cccccccccccccccccccc"
+                InfoHash(*b"aaaaaaaaaaaaaaaaaaaa"),
+                InfoHash(*b"bbbbbbbbbbbbbbbbbbbb"),
+                InfoHash(*b"cccccccccccccccccccc")
             ]
         );
     }
 
+    #[test]
+    fn scrape_agrees_with_announce() {
+        let info_hash = "%90%28%9F%D3M%FC%1C%F8%F3%16%A2h%AD%D85L%853DX";
+        let announce = AnnounceRequest::new(
+            &format!(
+                "info_hash={}&peer_id=ABCDEFGHIJKLMNOPQRST&port=6881&left=0",
+                info_hash
+            ),
+            Some("127.0.0.1:6881"),
+        )
+        .unwrap();
+        let scrape = ScrapeRequest::new(&format!("info_hash={}", info_hash)).unwrap();
+
+        assert_eq!(scrape.info_hashes, vec![announce.info_hash]);
+    }
+
+    #[test]
+    fn info_hash_hex_form() {
+        let raw = InfoHash::from_bytes(
+            b"\x2f\xa9\x0c\x59\xc8\x07\x2c\x5a\x4c\x54\xc1\xf1\x30\x7d\xac\xae\xb4\xc8\x2f\x0f",
+        );
+        let hex = InfoHash::from_bytes(b"2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f");
+
+        assert!(raw.is_some());
+        assert_eq!(raw, hex);
+        assert!(InfoHash::from_bytes(b"2fa90c59c8072c5a4c54c1f1307dacaeb4c82fzz").is_none());
+    }
+
     #[test]
     fn scrape_bad_request_creation() {
         let url_string = "info_hash=aaaaaaaaaaaaaaaaaaaa&info_bash=bbbbbbbbbbbbbbbbbbbb&info_slash=cccccccccccccccccccc";
@@ -616,7 +891,7 @@ mod tests {
     fn scrape_response_add_file() {
         let file = ScrapeFile::default();
         let mut scrape_response = ScrapeResponse::new().unwrap();
-        scrape_response.add_file("test".to_string(), file);
+        scrape_response.add_file(file.info_hash, file);
 
         assert_eq!(scrape_response.files.len(), 1);
     }
@@ -1,9 +1,13 @@
 use std::fs::File;
 use std::io::Read;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
+use hashbrown::HashSet;
 use serde::Deserialize;
 use toml;
 
+use crate::bittorrent::InfoHash;
 use crate::errors::InternalError;
 
 #[derive(Default, Deserialize, Clone)]
@@ -12,18 +16,26 @@ pub struct Config {
     pub storage: Storage,
     pub bt: BitTorrent,
     pub client_approval: ClientApproval,
+    #[serde(default)]
+    pub torrent_approval: TorrentApproval,
+    #[serde(default)]
+    pub reload: Reload,
+    #[serde(default)]
+    pub admin: Admin,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, PartialEq)]
 pub struct Network {
     pub binding: String,
+    pub udp_binding: Option<String>,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, PartialEq)]
 pub struct Storage {
     pub backend: String,
     pub path: String,
     pub password: Option<String>,
+    pub peer_snapshot: Option<String>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -32,9 +44,21 @@ pub struct BitTorrent {
     pub peer_timeout: u64,
     pub reap_interval: u64,
     pub flush_interval: u64,
+    #[serde(default)]
+    pub compact_only: bool,
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub open: bool,
+    #[serde(default = "default_max_torrents")]
+    pub max_torrents: usize,
 }
 
-#[derive(Deserialize, Clone)]
+fn default_max_torrents() -> usize {
+    100_000
+}
+
+#[derive(Default, Deserialize, Clone)]
 pub struct ClientApproval {
     pub enabled: bool,
     pub blacklist_style: bool,
@@ -42,10 +66,47 @@ pub struct ClientApproval {
     pub client_list: Vec<String>,
 }
 
+#[derive(Default, Deserialize, Clone)]
+#[serde(default)]
+pub struct TorrentApproval {
+    pub enabled: bool,
+    pub blacklist_style: bool,
+    pub source: TorrentSource,
+    pub torrent_list: Vec<String>,
+    // Parsed from torrent_list when the config is read
+    #[serde(skip)]
+    pub info_hashes: HashSet<InfoHash>,
+}
+
+// Where the approved (or prohibited) torrents come from: the
+// torrent_list above, or every torrent registered in the storage backend
+#[derive(Default, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TorrentSource {
+    #[default]
+    Config,
+    Backend,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Reload {
+    pub watch: bool,
+    pub watch_interval: u64,
+}
+
+#[derive(Default, Deserialize, Clone)]
+#[serde(default)]
+pub struct Admin {
+    pub enabled: bool,
+    pub token: String,
+}
+
 impl Default for Network {
     fn default() -> Self {
         Network {
             binding: "0.0.0.0:8585".to_string(),
+            udp_binding: None,
         }
     }
 }
@@ -56,6 +117,7 @@ impl Default for Storage {
             backend: "memory".to_string(),
             path: "".to_string(),
             password: None,
+            peer_snapshot: None,
         }
     }
 }
@@ -67,62 +129,407 @@ impl Default for BitTorrent {
             peer_timeout: 7200,
             reap_interval: 1800,
             flush_interval: 900,
+            compact_only: false,
+            private: false,
+            open: false,
+            max_torrents: default_max_torrents(),
         }
     }
 }
 
-impl Default for ClientApproval {
-    fn default() -> ClientApproval {
-        ClientApproval {
-            enabled: false,
-            blacklist_style: false,
-            versioned: false,
-            client_list: Vec::new(),
+impl Default for Reload {
+    fn default() -> Self {
+        Reload {
+            watch: false,
+            watch_interval: 10,
         }
     }
 }
 
 impl Config {
-    pub fn load_config(path: String) -> Config {
+    fn read(path: &str) -> Result<Config, InternalError> {
         let mut config_toml = String::new();
 
-        let mut file = match File::open(&path) {
-            Ok(file) => file,
-            _ => {
-                error!("{}", InternalError::ConfigFileOpen.text());
-                return Config::default();
-            }
-        };
+        let mut file = File::open(path).map_err(|_| InternalError::ConfigFileOpen)?;
 
         if file.read_to_string(&mut config_toml).is_err() {
-            error!("{}", InternalError::ConfigFileRead.text());
-            return Config::default();
+            return Err(InternalError::ConfigFileRead);
         };
 
-        let config: Config = match toml::from_str(&config_toml) {
-            Ok(t) => t,
-            _ => {
-                error!("{}", InternalError::ConfigParse.text());
-                Config::default()
+        let mut config: Config = toml::from_str(&config_toml).map_err(|e| {
+            error!("Config: {}", e);
+            InternalError::ConfigParse
+        })?;
+
+        // Entries may be given in either raw or hex form. Invalid ones
+        // are left out here, but validate() rejects the whole config
+        // over them, both at startup and on reload.
+        config.torrent_approval.info_hashes = config
+            .torrent_approval
+            .torrent_list
+            .iter()
+            .filter_map(|h| InfoHash::from_bytes(h.as_bytes()))
+            .collect();
+
+        Ok(config)
+    }
+
+    // Catches settings that would parse fine but break the tracker
+    pub fn validate(&self) -> Result<(), String> {
+        let intervals = [
+            ("announce_rate", self.bt.announce_rate),
+            ("peer_timeout", self.bt.peer_timeout),
+            ("reap_interval", self.bt.reap_interval),
+            ("flush_interval", self.bt.flush_interval),
+            ("watch_interval", self.reload.watch_interval),
+        ];
+        if let Some((name, _)) = intervals.iter().find(|(_, secs)| *secs == 0) {
+            return Err(format!("'{}' must be greater than zero", name));
+        }
+
+        let client_len = if self.client_approval.versioned { 6 } else { 2 };
+        if let Some(client) = self
+            .client_approval
+            .client_list
+            .iter()
+            .find(|c| c.len() != client_len)
+        {
+            return Err(format!(
+                "Client '{}' should be {} characters long",
+                client, client_len
+            ));
+        }
+
+        if let Some(info_hash) = self
+            .torrent_approval
+            .torrent_list
+            .iter()
+            .find(|h| InfoHash::from_bytes(h.as_bytes()).is_none())
+        {
+            return Err(format!("Torrent '{}' is not a valid info hash", info_hash));
+        }
+
+        // Every registered torrent would be prohibited
+        if self.torrent_approval.blacklist_style
+            && self.torrent_approval.source == TorrentSource::Backend
+        {
+            return Err("A torrent blacklist can't come from the backend".to_string());
+        }
+
+        if self.admin.enabled && self.admin.token.is_empty() {
+            return Err("The admin API needs a 'token' to be enabled".to_string());
+        }
+
+        Ok(())
+    }
+
+    // A config that can't be read falls back to the defaults, but one that
+    // breaks the rules is refused. Falling back there could turn a private
+    // or whitelisted tracker into an open one over a single bad entry.
+    pub fn load_config(path: String) -> Result<Config, InternalError> {
+        let config = match Config::read(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{}", e.text());
+                return Ok(Config::default());
             }
         };
 
+        // Held to the same rules as a reload; a zero interval
+        // would otherwise have the Janitor spinning in place
+        if let Err(reason) = config.validate() {
+            error!("Config: {}", reason);
+            return Err(InternalError::ConfigInvalid);
+        }
+
         info!("Binding to address: {}", &config.network.binding);
+        if let Some(udp_binding) = &config.network.udp_binding {
+            info!("Binding UDP listener to address: {}", udp_binding);
+        }
         info!(
             "Utilizing {} storage backend located at {}",
             &config.storage.backend, &config.storage.path
         );
+        if let Some(peer_snapshot) = &config.storage.peer_snapshot {
+            info!("Snapshotting peers to {}", peer_snapshot);
+        }
         info!("Announce interval: {} secs", &config.bt.announce_rate);
         info!(
-            "Clearing peers older than {} secs at {}-sec interval",
+            "Clearing peers older than {} secs, reconciling counts at {}-sec interval",
             &config.bt.peer_timeout, &config.bt.reap_interval
         );
         info!(
             "Flushing torrents to disk every {} secs",
             &config.bt.flush_interval
         );
+        if config.bt.compact_only {
+            info!("Rejecting non-compact announces");
+        }
+        if config.bt.private {
+            info!("Running as a private tracker");
+        }
+        if config.bt.open {
+            info!(
+                "Registering new torrents on first announce, up to {}",
+                &config.bt.max_torrents
+            );
+        }
         info!("Client list: {:?}", &config.client_approval.client_list);
+        if config.torrent_approval.enabled {
+            info!(
+                "Torrent {} from {:?}",
+                if config.torrent_approval.blacklist_style {
+                    "blacklist"
+                } else {
+                    "whitelist"
+                },
+                &config.torrent_approval.source
+            );
+        }
+        if config.admin.enabled {
+            info!("Serving the admin API under /admin");
+        }
+        if config.reload.watch {
+            info!(
+                "Watching for config changes every {} secs",
+                &config.reload.watch_interval
+            );
+        }
+
+        Ok(config)
+    }
+}
+
+// The active configuration, shared between the server, the Janitor
+// and the reloader. Readers take the whole config at once, so
+// a reload is never seen half-applied.
+#[derive(Clone)]
+pub struct SharedConfig {
+    inner: Arc<RwLock<Arc<Config>>>,
+}
+
+impl SharedConfig {
+    pub fn new(config: Config) -> SharedConfig {
+        SharedConfig {
+            inner: Arc::new(RwLock::new(Arc::new(config))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    // Re-reads the config file and swaps in everything that can change
+    // while running. Sockets and storage are fixed for the life of the
+    // process, so those sections are carried over from the old config.
+    pub fn reload(&self, path: &str) -> Result<(), InternalError> {
+        let current = self.current();
+
+        let mut config = Config::read(path).map_err(|e| {
+            error!("{}", e.text());
+            error!("{}", InternalError::ConfigReload.text());
+            InternalError::ConfigReload
+        })?;
+
+        if let Err(reason) = config.validate() {
+            error!("Config: {}", reason);
+            error!("{}", InternalError::ConfigReload.text());
+            return Err(InternalError::ConfigReload);
+        }
+
+        if config.network != current.network || config.storage != current.storage {
+            warn!("Network and storage changes will not apply until restart");
+        }
+        config.network = current.network.clone();
+        config.storage = current.storage.clone();
+
+        *self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(config);
+
+        info!("Configuration reloaded from {}", path);
+        Ok(())
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Reloads the config on SIGHUP and, if enabled,
+// whenever the file's modification time changes
+#[cfg(unix)]
+pub async fn watch(config: SharedConfig, path: String) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            error!("Could not listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    let mut last_modified = modified(&path);
+
+    loop {
+        let reload = config.current().reload.clone();
+
+        let should_reload = if reload.watch {
+            tokio::select! {
+                _ = sighup.recv() => true,
+                _ = tokio::time::delay_for(Duration::from_secs(reload.watch_interval)) => {
+                    modified(&path) != last_modified
+                }
+            }
+        } else {
+            sighup.recv().await;
+            true
+        };
+
+        if should_reload {
+            last_modified = modified(&path);
+            let _ = config.reload(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::util::temp_path;
+
+    const CONFIG: &str = "
+[network]
+binding = '0.0.0.0:8585'
+
+[storage]
+backend = 'memory'
+path = ''
+
+[bt]
+announce_rate = 1800
+peer_timeout = 7200
+reap_interval = 1800
+flush_interval = 900
+
+[client_approval]
+enabled = false
+blacklist_style = false
+versioned = false
+client_list = ['DE']
+";
+
+    fn temp_config(name: &str, contents: &str) -> String {
+        let path = temp_path(name, "toml");
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn reload_swaps_reloadable_parts() {
+        let path = temp_config("reload", CONFIG);
+        let config = SharedConfig::new(Config::load_config(path.clone()).unwrap());
+
+        let changed = CONFIG
+            .replace("0.0.0.0:8585", "0.0.0.0:9999")
+            .replace("announce_rate = 1800", "announce_rate = 600")
+            .replace("enabled = false", "enabled = true")
+            .replace("['DE']", "['DE', 'qB']");
+        let torrent_approval = "
+[torrent_approval]
+enabled = true
+torrent_list = ['2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f']
+";
+        std::fs::write(&path, format!("{}{}", changed, torrent_approval)).unwrap();
+
+        assert!(config.reload(&path).is_ok());
+
+        let current = config.current();
+        assert_eq!(current.bt.announce_rate, 600);
+        assert!(current.client_approval.enabled);
+        assert_eq!(current.client_approval.client_list, vec!["DE", "qB"]);
+        assert_eq!(current.torrent_approval.source, TorrentSource::Config);
+        assert!(current
+            .torrent_approval
+            .info_hashes
+            .contains(&InfoHash::from_bytes(b"2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f").unwrap()));
+
+        // Bindings can't change without a restart
+        assert_eq!(current.network.binding, "0.0.0.0:8585");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reload_keeps_old_config_on_error() {
+        let path = temp_config("reload-invalid", CONFIG);
+        let config = SharedConfig::new(Config::load_config(path.clone()).unwrap());
+
+        std::fs::write(&path, CONFIG.replace("[bt]", "[bt")).unwrap();
+        assert_eq!(config.reload(&path), Err(InternalError::ConfigReload));
+
+        std::fs::write(
+            &path,
+            CONFIG.replace("flush_interval = 900", "flush_interval = 0"),
+        )
+        .unwrap();
+        assert_eq!(config.reload(&path), Err(InternalError::ConfigReload));
+
+        std::fs::write(&path, CONFIG.replace("['DE']", "['DE1234']")).unwrap();
+        assert_eq!(config.reload(&path), Err(InternalError::ConfigReload));
+
+        let torrent_approval = "
+[torrent_approval]
+enabled = true
+blacklist_style = true
+source = 'backend'
+";
+        std::fs::write(&path, format!("{}{}", CONFIG, torrent_approval)).unwrap();
+        assert_eq!(config.reload(&path), Err(InternalError::ConfigReload));
+
+        let admin = "
+[admin]
+enabled = true
+";
+        std::fs::write(&path, format!("{}{}", CONFIG, admin)).unwrap();
+        assert_eq!(config.reload(&path), Err(InternalError::ConfigReload));
+
+        assert_eq!(config.current().bt.flush_interval, 900);
+        assert_eq!(config.current().client_approval.client_list, vec!["DE"]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn load_rejects_invalid_config() {
+        let path = temp_config(
+            "load-invalid",
+            &CONFIG.replace("reap_interval = 1800", "reap_interval = 0"),
+        );
+
+        assert_eq!(
+            Config::load_config(path.clone()).err(),
+            Some(InternalError::ConfigInvalid)
+        );
+
+        // Bad torrent list entries fail the config rather than being skipped
+        let torrent_approval = "
+[torrent_approval]
+enabled = true
+torrent_list = ['2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f', 'not-an-info-hash']
+";
+        std::fs::write(&path, format!("{}{}", CONFIG, torrent_approval)).unwrap();
+
+        assert_eq!(
+            Config::load_config(path.clone()).err(),
+            Some(InternalError::ConfigInvalid)
+        );
 
-        config
+        let _ = std::fs::remove_file(&path);
     }
 }
@@ -1,33 +1,54 @@
 // This is a list of errors that are available to send back to the client.
 #[derive(Debug)]
 pub enum ClientError {
+    InvalidAction,
+    InvalidConnectionId,
+    InvalidPasskey,
     MalformedAnnounce,
     MalformedScrape,
     NotCompact,
     ResourceDoesNotExist,
+    ShuttingDown,
+    TorrentLimitReached,
     UnapprovedClient,
     UnapprovedTorrent,
 }
 
 // This is a list of errors that are internal to the tracker,
 // and may possibly show up in the logs.
+#[derive(Debug, PartialEq)]
 pub enum InternalError {
     ConfigFileOpen,
     ConfigFileRead,
+    ConfigInvalid,
     ConfigParse,
     ConfigReload,
+    StorageBackendInit,
+    StorageBackendUnknown,
+    StoragePeerLoad,
+    StoragePeerSave,
+    StorageSnatchFlush,
+    StorageSnatchLoad,
+    StorageTorrentDelete,
     StorageTorrentFetchNew,
     StorageTorrentFlush,
     StorageTorrentLoad,
+    StorageUserFlush,
+    StorageUserLoad,
 }
 
 impl ClientError {
     pub fn text(&self) -> String {
         match *self {
+            ClientError::InvalidAction => "Invalid action".to_string(),
+            ClientError::InvalidConnectionId => "Invalid or expired connection ID".to_string(),
+            ClientError::InvalidPasskey => "Invalid or revoked passkey".to_string(),
             ClientError::MalformedAnnounce => "Malformed announce request".to_string(),
             ClientError::MalformedScrape => "Malformed scrape request".to_string(),
             ClientError::NotCompact => "Announces must be in compact format".to_string(),
             ClientError::ResourceDoesNotExist => "Resource does not exist".to_string(),
+            ClientError::ShuttingDown => "Tracker is shutting down".to_string(),
+            ClientError::TorrentLimitReached => "Tracker is not accepting new torrents".to_string(),
             ClientError::UnapprovedClient => "Unapproved client".to_string(),
             ClientError::UnapprovedTorrent => "Unapproved torrent".to_string(),
         }
@@ -43,11 +64,21 @@ impl InternalError {
             InternalError::ConfigFileRead => {
                 "Could not read config file! Loading default config..."
             }
+            InternalError::ConfigInvalid => "Configuration is invalid! Refusing to start...",
             InternalError::ConfigParse => "Could not parse config file! Loading default config...",
             InternalError::ConfigReload => "Could not reload configuration! Keeping old config...",
+            InternalError::StorageBackendInit => "Could not initialize storage backend!",
+            InternalError::StorageBackendUnknown => "Unknown storage backend in config!",
+            InternalError::StoragePeerLoad => "Could not load peer snapshot from disk!",
+            InternalError::StoragePeerSave => "Could not save peer snapshot to disk!",
+            InternalError::StorageSnatchFlush => "Could not flush snatches to disk!",
+            InternalError::StorageSnatchLoad => "Could not load snatches from disk!",
+            InternalError::StorageTorrentDelete => "Could not delete torrents from disk!",
             InternalError::StorageTorrentFetchNew => "Could not fetch new torrents from disk!",
             InternalError::StorageTorrentFlush => "Could not flush torrents to disk!",
             InternalError::StorageTorrentLoad => "Could not load torrents from disk!",
+            InternalError::StorageUserFlush => "Could not flush user transfers to disk!",
+            InternalError::StorageUserLoad => "Could not load users from disk!",
         }
     }
 }
@@ -2,21 +2,21 @@ pub mod bencode;
 pub mod bittorrent;
 pub mod config;
 pub mod errors;
+pub mod metrics;
 pub mod network;
 pub mod state;
 pub mod statistics;
 pub mod storage;
 pub mod util;
 
+use std::sync::atomic::Ordering;
+
 use actix::prelude::*;
-use actix_rt;
-use actix_web::{middleware, web, App, HttpResponse, HttpServer};
+use actix_web::{web, App, HttpResponse, HttpServer};
 use clap::{App as ClapApp, Arg};
 use config::Config;
-use mysql;
-use pretty_env_logger;
 use state::State;
-use storage::janitor::Janitor;
+use storage::janitor::{self, Janitor};
 
 #[macro_use]
 extern crate log;
@@ -43,53 +43,226 @@ async fn main() -> std::io::Result<()> {
         .get_matches();
 
     // Parse arguments and attempt to parse configuration file
-    let config_path = matches.value_of("config");
-    let config = match config_path {
-        Some(path) => Config::load_config(path.to_string()),
-        None => Config::load_config("config.toml".to_string()),
-    };
+    let config_path = matches
+        .value_of("config")
+        .unwrap_or("config.toml")
+        .to_string();
+    let config = Config::load_config(config_path.clone()).map_err(|e| {
+        error!("{}", e.text());
+        std::io::Error::other(e.text())
+    })?;
 
     // Copy and cloning up here to avoid errors for moved values
     let binding = config.network.binding.clone();
+    let udp_binding = config.network.udp_binding.clone();
 
-    // TODO: abstract into a general loading function
-    // TODO: add support to pass mysql password
     // Collect torrents from desired storage
     // backend and instantiate data stores.
-    let pool = mysql::Pool::new(&config.storage.path).unwrap();
-    let torrents = storage::mysql::get_torrents(pool.clone()).unwrap();
+    let backend = storage::create_backend(&config.storage).map_err(|e| {
+        error!("{}", e.text());
+        std::io::Error::other(e.text())
+    })?;
+    let torrents = backend.load_torrents().map_err(|e| {
+        error!("{}", e.text());
+        std::io::Error::other(e.text())
+    })?;
     info!("Number of torrents loaded: {}", torrents.len());
 
     let torrent_records = storage::TorrentStore::new(torrents);
-    let state = web::Data::new(State::new(config.clone(), torrent_records));
+    // Private trackers need their users before any announces come in
+    let users = if config.bt.private {
+        let users = backend.load_users().map_err(|e| {
+            error!("{}", e.text());
+            std::io::Error::other(e.text())
+        })?;
+        info!("Number of users loaded: {}", users.len());
+        users
+    } else {
+        storage::UserRecords::new()
+    };
+
+    // Earlier snatches keep repeated completions from being counted again
+    let snatches = backend.load_snatches().map_err(|e| {
+        error!("{}", e.text());
+        std::io::Error::other(e.text())
+    })?;
+    info!("Number of snatches loaded: {}", snatches.len());
+
+    let mut state = State::with_backend(config.clone(), torrent_records, backend);
+    state.user_store = storage::UserStore::new(users);
+    state.snatch_store = storage::SnatchStore::new(snatches);
+
+    // Bring back the swarms from the last run, if there are any
+    if let Some(path) = &config.storage.peer_snapshot {
+        let peer_timeout = std::time::Duration::from_secs(config.bt.peer_timeout);
+        if let Err(e) = storage::snapshot::restore(&mut state, path, peer_timeout).await {
+            error!("{}", e.text());
+        }
+    }
+
+    let state = web::Data::new(state);
     let janitor_state_clone = state.clone();
+    let shutdown_state_clone = state.clone();
+    let state_config_clone = state.config.clone();
+    let udp_state_clone = state.clone();
 
     let server = HttpServer::new(move || {
         App::new()
             .app_data(state.clone())
             // Log all requests to stdout
             //.wrap(middleware::Logger::default())
-            // If enabled, filter requests
-            // by client ID and reject or accept
-            .wrap(middleware::Condition::new(
-                config.client_approval.enabled,
-                network::middleware::ClientApproval::new(
-                    config.client_approval.blacklist_style,
-                    config.client_approval.versioned,
-                    config.client_approval.client_list.clone(),
-                ),
-            ))
-            .service(web::scope("announce").route("", web::get().to(network::parse_announce)))
-            .service(web::scope("scrape").route("", web::get().to(network::parse_scrape)))
-            .service(web::scope("stats").route("", web::get().to(network::get_stats)))
-            .service(web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())))
+            // Operators authenticate with a token instead, so the
+            // admin API is kept clear of the approval filters below
+            .service(
+                web::scope("admin")
+                    .wrap(network::middleware::AdminAuth::new(state.config.clone()))
+                    .configure(network::admin::routes),
+            )
+            // Prometheus has no peer ID to pass client approval with.
+            // Rendering only reads running totals, so scrapes are cheap
+            // enough to leave open.
+            .service(web::scope("metrics").route("", web::get().to(network::get_metrics)))
+            .service(
+                web::scope("")
+                    // If enabled, filter requests by
+                    // info hash and reject or accept
+                    .wrap(network::middleware::TorrentApproval::new(state.clone()))
+                    // If enabled, filter requests
+                    // by client ID and reject or accept
+                    .wrap(network::middleware::ClientApproval::new(
+                        state.config.clone(),
+                        state.metrics.clone(),
+                        state.stats.clone(),
+                    ))
+                    .service(
+                        web::scope("announce").route("", web::get().to(network::parse_announce)),
+                    )
+                    .service(web::scope("scrape").route("", web::get().to(network::parse_scrape)))
+                    .service(
+                        web::scope("stats")
+                            .route("", web::get().to(network::get_stats))
+                            .route(
+                                "/torrent/{info_hash}",
+                                web::get().to(network::get_torrent_stats),
+                            )
+                            .route("/top", web::get().to(network::get_top_swarms)),
+                    )
+                    .route(
+                        "/{passkey}/announce",
+                        web::get().to(network::parse_private_announce),
+                    )
+                    .route(
+                        "/{passkey}/scrape",
+                        web::get().to(network::parse_private_scrape),
+                    )
+                    .service(
+                        web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed)),
+                    ),
+            )
     })
     .bind(binding)?
+    // Signals are handled below so that shutdown can be coordinated
+    .disable_signals()
     .run();
 
+    // Serve the UDP tracker protocol alongside HTTP
+    if let Some(udp_binding) = udp_binding {
+        let addr: std::net::SocketAddr = udp_binding
+            .parse()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let socket = tokio::net::UdpSocket::bind(addr).await?;
+        actix_rt::spawn(network::udp::serve(socket, udp_state_clone));
+    }
+
+    // Pick up config changes on SIGHUP or when the file changes
+    #[cfg(unix)]
+    actix_rt::spawn(config::watch(state_config_clone, config_path));
+
     // Start janitor in its own thread
-    Janitor::create(|_ctx: &mut Context<Janitor>| Janitor::new(janitor_state_clone, pool));
+    let janitor = Janitor::create(|_ctx: &mut Context<Janitor>| Janitor::new(janitor_state_clone));
+
+    // Stop taking announces on SIGTERM or SIGINT, then let
+    // in-flight requests finish before the server stops
+    let server_handle = server.clone();
+    let signal_state_clone = shutdown_state_clone.clone();
+    actix_rt::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down...");
+        signal_state_clone
+            .shutting_down
+            .store(true, Ordering::SeqCst);
+        server_handle.stop(true).await;
+    });
+
+    // Start server. Its result is held on to so that
+    // the flushes below happen even if it failed.
+    let served = server.await;
+    if let Err(e) = &served {
+        error!("Server stopped with an error: {}", e);
+    }
+
+    // The Janitor goes first so that its flushes can't interleave with ours
+    if let Err(e) = janitor.send(janitor::Stop).await {
+        error!("Janitor could not be stopped: {}", e);
+    }
+
+    // The server has stopped, so nothing is changing the swarms anymore
+    if let Some(path) = &shutdown_state_clone.config.current().storage.peer_snapshot {
+        match storage::snapshot::save(&shutdown_state_clone.peer_store, path).await {
+            Ok(num_peers) => info!("Snapshotted {} peers.", num_peers),
+            Err(e) => error!("{}", e.text()),
+        }
+    }
+
+    // Anything changed since the Janitor's last flush would
+    // otherwise be lost, so the exit status depends on this
+    info!("Flushing torrents to database...");
+    let torrents = janitor::flush_torrents(&shutdown_state_clone).await;
+    if let Ok(num_torrents) = torrents {
+        info!("Flushed {} torrents.", num_torrents);
+    }
+
+    let users = janitor::flush_user_transfers(&shutdown_state_clone).await;
+    if let Ok(num_users) = users {
+        info!("Flushed transfers for {} users.", num_users);
+    }
+
+    let snatches = janitor::flush_snatches(&shutdown_state_clone).await;
+    if let Ok(num_snatches) = snatches {
+        info!("Flushed {} snatches.", num_snatches);
+    }
+
+    // Any of them failing means something was lost
+    let flushed = torrents.and(users).and(snatches).map(|_| ()).map_err(|e| {
+        error!("{}", e.text());
+        std::io::Error::other(e.text())
+    });
+
+    match (served, flushed) {
+        (Ok(()), flushed) => flushed,
+        (Err(e), Ok(())) => Err(e),
+        (Err(e), Err(flush_error)) => Err(std::io::Error::new(
+            e.kind(),
+            format!("{}; {}", e, flush_error),
+        )),
+    }
+}
+
+// Resolves once the tracker has been asked to stop
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Could not listen for SIGINT");
+
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    }
+}
 
-    // Start server
-    server.await
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -0,0 +1,315 @@
+// Prometheus metrics served on /metrics. Counters and histograms are
+// updated as requests come in. Peer gauges are read off the totals the
+// peer store keeps as peers move, and swarms are counted shard by shard,
+// so a scrape never walks the peers themselves.
+
+use std::time::Duration;
+
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::errors::ClientError;
+use crate::statistics::GlobalStatistics;
+use crate::storage::PeerStore;
+use crate::util::Event;
+
+pub struct Metrics {
+    registry: Registry,
+    announces: IntCounterVec,
+    scrapes: IntCounterVec,
+    failures: IntCounterVec,
+    peers: IntGaugeVec,
+    swarms: IntGauge,
+    janitor_runs: IntCounterVec,
+    janitor_duration: HistogramVec,
+    reaped_peers: IntCounterVec,
+    pruned_swarms: IntCounter,
+    request_duration: HistogramVec,
+}
+
+// Requests that failed before their event could be parsed are labelled "unknown"
+fn event_label(event: Option<&Event>) -> &'static str {
+    match event {
+        Some(Event::Started) => "started",
+        Some(Event::Stopped) => "stopped",
+        Some(Event::Completed) => "completed",
+        Some(Event::None) => "none",
+        None => "unknown",
+    }
+}
+
+fn outcome_label(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let announces = IntCounterVec::new(
+            Opts::new("tyto_announces_total", "Announces by event and outcome"),
+            &["event", "outcome"],
+        )
+        .unwrap();
+        let scrapes = IntCounterVec::new(
+            Opts::new("tyto_scrapes_total", "Scrapes by outcome"),
+            &["outcome"],
+        )
+        .unwrap();
+        let failures = IntCounterVec::new(
+            Opts::new("tyto_failures_total", "Errors sent back to clients"),
+            &["error"],
+        )
+        .unwrap();
+        let peers = IntGaugeVec::new(
+            Opts::new("tyto_peers", "Peers in all swarms"),
+            &["kind", "family"],
+        )
+        .unwrap();
+        let swarms = IntGauge::new("tyto_swarms", "Swarms with at least one peer").unwrap();
+        let janitor_runs = IntCounterVec::new(
+            Opts::new("tyto_janitor_runs_total", "Janitor task runs"),
+            &["task"],
+        )
+        .unwrap();
+        let janitor_duration = HistogramVec::new(
+            HistogramOpts::new("tyto_janitor_duration_seconds", "Janitor task durations")
+                .buckets(exponential_buckets(0.001, 4.0, 10).unwrap()),
+            &["task"],
+        )
+        .unwrap();
+        let reaped_peers = IntCounterVec::new(
+            Opts::new(
+                "tyto_reaped_peers_total",
+                "Peers dropped for not announcing",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let pruned_swarms = IntCounter::new(
+            "tyto_pruned_swarms_total",
+            "Empty swarms removed when their last peer expired",
+        )
+        .unwrap();
+        // Announces are usually answered well within a millisecond
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("tyto_request_duration_seconds", "Request latencies")
+                .buckets(exponential_buckets(0.0001, 2.0, 15).unwrap()),
+            &["protocol", "request"],
+        )
+        .unwrap();
+
+        // Registration only fails for duplicate names
+        let registry = Registry::new();
+        registry.register(Box::new(announces.clone())).unwrap();
+        registry.register(Box::new(scrapes.clone())).unwrap();
+        registry.register(Box::new(failures.clone())).unwrap();
+        registry.register(Box::new(peers.clone())).unwrap();
+        registry.register(Box::new(swarms.clone())).unwrap();
+        registry.register(Box::new(janitor_runs.clone())).unwrap();
+        registry
+            .register(Box::new(janitor_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(reaped_peers.clone())).unwrap();
+        registry.register(Box::new(pruned_swarms.clone())).unwrap();
+
+        Metrics {
+            registry,
+            announces,
+            scrapes,
+            failures,
+            peers,
+            swarms,
+            janitor_runs,
+            janitor_duration,
+            reaped_peers,
+            pruned_swarms,
+            request_duration,
+        }
+    }
+
+    pub fn announce(&self, event: Option<&Event>, success: bool) {
+        self.announces
+            .with_label_values(&[event_label(event), outcome_label(success)])
+            .inc();
+    }
+
+    pub fn scrape(&self, success: bool) {
+        self.scrapes
+            .with_label_values(&[outcome_label(success)])
+            .inc();
+    }
+
+    // Labelled with the variant name, e.g. "UnapprovedClient"
+    pub fn failure(&self, error: &ClientError) {
+        self.failures
+            .with_label_values(&[&format!("{:?}", error)])
+            .inc();
+    }
+
+    pub fn janitor_run(&self, task: &str, duration: Duration) {
+        self.janitor_runs.with_label_values(&[task]).inc();
+        self.janitor_duration
+            .with_label_values(&[task])
+            .observe(duration.as_secs_f64());
+    }
+
+    // Called once per reap pass with what it cleared away
+    pub fn reaped(&self, seeders: usize, leechers: usize, swarms: usize) {
+        self.reaped_peers
+            .with_label_values(&["seeder"])
+            .inc_by(seeders as u64);
+        self.reaped_peers
+            .with_label_values(&["leecher"])
+            .inc_by(leechers as u64);
+        self.pruned_swarms.inc_by(swarms as u64);
+    }
+
+    // The latency is recorded when the timer is dropped
+    pub fn request_timer(&self, protocol: &str, request: &str) -> HistogramTimer {
+        self.request_duration
+            .with_label_values(&[protocol, request])
+            .start_timer()
+    }
+
+    // Renders everything in the Prometheus text format
+    pub async fn render(&self, stats: &GlobalStatistics, peer_store: &PeerStore) -> String {
+        let counts = stats.peer_counts();
+        // Empty swarms are dropped as soon as their last peer leaves
+        let mut num_swarms = 0;
+        for shard in peer_store.shards() {
+            num_swarms += shard.read().await.records.len();
+        }
+
+        for (kind, kind_label) in ["seeder", "leecher"].iter().enumerate() {
+            for (family, family_label) in ["ipv4", "ipv6"].iter().enumerate() {
+                self.peers
+                    .with_label_values(&[kind_label, family_label])
+                    .set(counts[kind][family]);
+            }
+        }
+        self.swarms.set(num_swarms as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::Instant;
+
+    use crate::bittorrent::{InfoHash, Peer, PeerId, Peerv4, Peerv6};
+    use crate::storage::{TorrentRecords, TorrentStore};
+
+    #[actix_rt::test]
+    async fn metrics_render_counts_and_gauges() {
+        let metrics = Metrics::new();
+        metrics.announce(Some(&Event::Started), true);
+        metrics.announce(None, false);
+        metrics.failure(&ClientError::MalformedAnnounce);
+        metrics.scrape(true);
+        metrics.janitor_run("reap", Duration::from_millis(3));
+        metrics.reaped(0, 2, 1);
+
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer_store = PeerStore::new();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stats = GlobalStatistics::new();
+        peer_store
+            .announce(
+                info_hash,
+                Peer::V4(Peerv4 {
+                    peer_id: PeerId(*b"-TR2940-000000000001"),
+                    ip: Ipv4Addr::new(10, 0, 0, 1),
+                    port: 6881,
+                    last_announced: Instant::now(),
+                    user_id: None,
+                    uploaded: 0,
+                    downloaded: 0,
+                }),
+                &Event::Completed,
+                0,
+                &torrent_store,
+                &stats,
+            )
+            .await;
+        peer_store
+            .announce(
+                info_hash,
+                Peer::V6(Peerv6 {
+                    peer_id: PeerId(*b"-TR2940-000000000002"),
+                    ip: Ipv6Addr::LOCALHOST,
+                    port: 6881,
+                    last_announced: Instant::now(),
+                    user_id: None,
+                    uploaded: 0,
+                    downloaded: 0,
+                }),
+                &Event::Started,
+                1000,
+                &torrent_store,
+                &stats,
+            )
+            .await;
+
+        let rendered = metrics.render(&stats, &peer_store).await;
+        for line in [
+            "tyto_announces_total{event=\"started\",outcome=\"success\"} 1",
+            "tyto_announces_total{event=\"unknown\",outcome=\"failure\"} 1",
+            "tyto_failures_total{error=\"MalformedAnnounce\"} 1",
+            "tyto_scrapes_total{outcome=\"success\"} 1",
+            "tyto_janitor_runs_total{task=\"reap\"} 1",
+            "tyto_reaped_peers_total{kind=\"leecher\"} 2",
+            "tyto_pruned_swarms_total 1",
+            "tyto_peers{family=\"ipv4\",kind=\"seeder\"} 1",
+            "tyto_peers{family=\"ipv6\",kind=\"leecher\"} 1",
+            "tyto_peers{family=\"ipv4\",kind=\"leecher\"} 0",
+            "tyto_swarms 1",
+        ] {
+            assert!(rendered.contains(line), "missing {}", line);
+        }
+
+        // The gauges follow peers out of their swarms as well
+        peer_store
+            .announce(
+                info_hash,
+                Peer::V6(Peerv6 {
+                    peer_id: PeerId(*b"-TR2940-000000000002"),
+                    ip: Ipv6Addr::LOCALHOST,
+                    port: 6881,
+                    last_announced: Instant::now(),
+                    user_id: None,
+                    uploaded: 0,
+                    downloaded: 0,
+                }),
+                &Event::Stopped,
+                1000,
+                &torrent_store,
+                &stats,
+            )
+            .await;
+        let rendered = metrics.render(&stats, &peer_store).await;
+        assert!(rendered.contains("tyto_peers{family=\"ipv6\",kind=\"leecher\"} 0"));
+        assert!(rendered.contains("tyto_peers{family=\"ipv4\",kind=\"seeder\"} 1"));
+    }
+}
@@ -0,0 +1,496 @@
+// Runtime control over torrents, swarms and the Janitor for operators.
+// Everything in here sits behind middleware::AdminAuth and speaks JSON.
+
+use std::net::IpAddr;
+
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+
+use crate::bittorrent::{InfoHash, Peer, PeerId};
+use crate::state::State;
+use crate::storage::{janitor, Torrent, UserId};
+use crate::util::from_hex;
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/torrents", web::get().to(list_torrents))
+        .route("/torrents/{info_hash}", web::post().to(add_torrent))
+        .route("/torrents/{info_hash}", web::delete().to(delete_torrent))
+        .route("/swarms/{info_hash}", web::get().to(get_swarm))
+        .route(
+            "/swarms/{info_hash}/peers/{peer_id}",
+            web::delete().to(kick_peer),
+        )
+        .route("/janitor/reap", web::post().to(reap))
+        .route("/janitor/flush", web::post().to(flush));
+}
+
+#[derive(Serialize)]
+struct TorrentInfo {
+    info_hash: String,
+    complete: u32,
+    downloaded: u32,
+    incomplete: u32,
+    balance: u64,
+}
+
+impl From<&Torrent> for TorrentInfo {
+    fn from(torrent: &Torrent) -> TorrentInfo {
+        TorrentInfo {
+            info_hash: torrent.info_hash.to_string(),
+            complete: torrent.complete,
+            downloaded: torrent.downloaded,
+            incomplete: torrent.incomplete,
+            balance: torrent.balance,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PeerInfo {
+    peer_id: String,
+    ip: IpAddr,
+    port: u16,
+    last_announced: u64, // Seconds ago
+    user_id: Option<UserId>,
+    uploaded: u64,
+    downloaded: u64,
+}
+
+impl From<&Peer> for PeerInfo {
+    fn from(peer: &Peer) -> PeerInfo {
+        let (ip, port, last_announced) = match peer {
+            Peer::V4(p) => (IpAddr::V4(p.ip), p.port, p.last_announced),
+            Peer::V6(p) => (IpAddr::V6(p.ip), p.port, p.last_announced),
+        };
+        let (uploaded, downloaded) = peer.transferred();
+
+        PeerInfo {
+            peer_id: peer.peer_id().to_string(),
+            ip,
+            port,
+            last_announced: last_announced.elapsed().as_secs(),
+            user_id: peer.user_id(),
+            uploaded,
+            downloaded,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SwarmInfo {
+    info_hash: String,
+    seeders: Vec<PeerInfo>,
+    leechers: Vec<PeerInfo>,
+}
+
+#[derive(Serialize)]
+struct Flushed {
+    torrents: usize,
+    users: usize,
+    snatches: usize,
+}
+
+fn invalid_info_hash() -> HttpResponse {
+    HttpResponse::BadRequest().body("Invalid info hash")
+}
+
+// Peer IDs are free to hold any bytes, so only their hex form is
+// accepted here; it's also the form the swarm listing hands out
+fn parse_peer_id(peer_id: &str) -> Option<PeerId> {
+    PeerId::from_bytes(&from_hex(peer_id.as_bytes())?)
+}
+
+async fn list_torrents(data: web::Data<State>) -> HttpResponse {
+    let torrents = data.torrent_store.torrents.read().await;
+    let mut listed: Vec<TorrentInfo> = torrents.values().map(TorrentInfo::from).collect();
+    listed.sort_by(|a, b| a.info_hash.cmp(&b.info_hash));
+
+    HttpResponse::Ok().json(listed)
+}
+
+// Added torrents reach the backend with the next flush
+async fn add_torrent(data: web::Data<State>, info_hash: web::Path<String>) -> HttpResponse {
+    let info_hash = match InfoHash::from_bytes(info_hash.as_bytes()) {
+        Some(info_hash) => info_hash,
+        None => return invalid_info_hash(),
+    };
+
+    let mut torrents = data.torrent_store.torrents.write().await;
+    if torrents.contains_key(&info_hash) {
+        return HttpResponse::Conflict().finish();
+    }
+
+    let torrent = Torrent::new(info_hash, 0, 0, 0, 0);
+    let info = TorrentInfo::from(&torrent);
+    torrents.insert(info_hash, torrent);
+    info!("Added torrent {} through the admin API", info_hash);
+
+    HttpResponse::Created().json(info)
+}
+
+// The torrent goes from the backend first, or the next
+// fetch of new torrents would simply bring it back
+async fn delete_torrent(data: web::Data<State>, info_hash: web::Path<String>) -> HttpResponse {
+    let info_hash = match InfoHash::from_bytes(info_hash.as_bytes()) {
+        Some(info_hash) => info_hash,
+        None => return invalid_info_hash(),
+    };
+
+    if !data
+        .torrent_store
+        .torrents
+        .read()
+        .await
+        .contains_key(&info_hash)
+    {
+        return HttpResponse::NotFound().finish();
+    }
+
+    if let Err(e) = data.backend.delete_torrents(vec![info_hash]) {
+        error!("{}", e.text());
+        return HttpResponse::InternalServerError().body(e.text());
+    }
+
+    data.torrent_store.remove(info_hash).await;
+    data.snatch_store.remove(info_hash).await;
+    data.peer_store
+        .remove_swarm(info_hash, &data.torrent_store, &data.stats)
+        .await;
+    info!("Deleted torrent {} through the admin API", info_hash);
+
+    HttpResponse::NoContent().finish()
+}
+
+async fn get_swarm(data: web::Data<State>, info_hash: web::Path<String>) -> HttpResponse {
+    let info_hash = match InfoHash::from_bytes(info_hash.as_bytes()) {
+        Some(info_hash) => info_hash,
+        None => return invalid_info_hash(),
+    };
+
+    match data.peer_store.get_swarm(info_hash).await {
+        Some(swarm) => HttpResponse::Ok().json(SwarmInfo {
+            info_hash: info_hash.to_string(),
+            seeders: swarm.seeders.iter().map(PeerInfo::from).collect(),
+            leechers: swarm.leechers.iter().map(PeerInfo::from).collect(),
+        }),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// A kicked client is free to announce again; this only
+// drops it from the swarm until it does
+async fn kick_peer(data: web::Data<State>, path: web::Path<(String, String)>) -> HttpResponse {
+    let info_hash = match InfoHash::from_bytes(path.0.as_bytes()) {
+        Some(info_hash) => info_hash,
+        None => return invalid_info_hash(),
+    };
+    let peer_id = match parse_peer_id(&path.1) {
+        Some(peer_id) => peer_id,
+        None => return HttpResponse::BadRequest().body("Invalid peer ID"),
+    };
+
+    match data
+        .peer_store
+        .remove_peer_id(info_hash, peer_id, &data.torrent_store, &data.stats)
+        .await
+    {
+        (0, 0) => HttpResponse::NotFound().finish(),
+        _ => {
+            info!(
+                "Kicked peer {} from {} through the admin API",
+                peer_id, info_hash
+            );
+
+            HttpResponse::NoContent().finish()
+        }
+    }
+}
+
+async fn reap(data: web::Data<State>) -> HttpResponse {
+    let reaped = janitor::reap_peers(&data).await;
+    info!(
+        "Cleared {} seeders and {} leechers, removed {} empty swarms through the admin API.",
+        reaped.seeders, reaped.leechers, reaped.swarms
+    );
+
+    HttpResponse::Ok().json(reaped)
+}
+
+async fn flush(data: web::Data<State>) -> HttpResponse {
+    let torrents = match janitor::flush_torrents(&data).await {
+        Ok(torrents) => torrents,
+        Err(e) => {
+            error!("{}", e.text());
+            return HttpResponse::InternalServerError().body(e.text());
+        }
+    };
+    let users = match janitor::flush_user_transfers(&data).await {
+        Ok(users) => users,
+        Err(e) => {
+            error!("{}", e.text());
+            return HttpResponse::InternalServerError().body(e.text());
+        }
+    };
+    let snatches = match janitor::flush_snatches(&data).await {
+        Ok(snatches) => snatches,
+        Err(e) => {
+            error!("{}", e.text());
+            return HttpResponse::InternalServerError().body(e.text());
+        }
+    };
+    info!(
+        "Flushed {} torrents, transfers for {} users and {} snatches through the admin API.",
+        torrents, users, snatches
+    );
+
+    HttpResponse::Ok().json(Flushed {
+        torrents,
+        users,
+        snatches,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    use crate::bittorrent::Peerv4;
+    use crate::config::Config;
+    use crate::network::middleware::AdminAuth;
+    use crate::storage::{TorrentRecords, TorrentStore};
+    use crate::util::Event;
+
+    const INFO_HASH: &str = "2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f";
+
+    fn admin_state() -> web::Data<State> {
+        let mut config = Config::default();
+        config.admin.enabled = true;
+        config.admin.token = "letmein".to_string();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        web::Data::new(State::new(config, torrent_store))
+    }
+
+    fn peer(peer_id: &[u8; 20], last_announced: Instant) -> Peer {
+        Peer::V4(Peerv4 {
+            peer_id: PeerId(*peer_id),
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 6881,
+            last_announced,
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
+        })
+    }
+
+    #[actix_rt::test]
+    async fn admin_requires_token() {
+        let stores = admin_state();
+        let mut app = test::init_service(
+            App::new().app_data(stores.clone()).service(
+                web::scope("admin")
+                    .wrap(AdminAuth::new(stores.config.clone()))
+                    .configure(routes),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/admin/torrents").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::with_uri("/admin/torrents")
+            .header("Authorization", "Bearer letmeout")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::with_uri("/admin/torrents")
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn admin_disabled_is_hidden() {
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(Config::default(), torrent_store));
+        let mut app = test::init_service(
+            App::new().app_data(stores.clone()).service(
+                web::scope("admin")
+                    .wrap(AdminAuth::new(stores.config.clone()))
+                    .configure(routes),
+            ),
+        )
+        .await;
+
+        // No token is configured, so no header can match it
+        let req = test::TestRequest::with_uri("/admin/torrents")
+            .header("Authorization", "Bearer ")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn admin_adds_lists_and_deletes_torrents() {
+        let stores = admin_state();
+        let mut app = test::init_service(
+            App::new().app_data(stores.clone()).service(
+                web::scope("admin")
+                    .wrap(AdminAuth::new(stores.config.clone()))
+                    .configure(routes),
+            ),
+        )
+        .await;
+        let uri = format!("/admin/torrents/{}", INFO_HASH);
+
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let proper_resp = format!(
+            "[{{\"info_hash\":\"{}\",\"complete\":0,\"downloaded\":0,\"incomplete\":0,\"balance\":0}}]",
+            INFO_HASH
+        );
+        let req = test::TestRequest::with_uri("/admin/torrents")
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, proper_resp.as_bytes());
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(stores.torrent_store.torrents.read().await.is_empty());
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn admin_inspects_swarm_and_kicks_peer() {
+        let stores = admin_state();
+        let info_hash = InfoHash::from_bytes(INFO_HASH.as_bytes()).unwrap();
+        let seeder = peer(b"-TR2940-000000000001", Instant::now());
+        stores.torrent_store.register(info_hash, 1).await;
+        stores
+            .peer_store
+            .announce(
+                info_hash,
+                seeder,
+                &Event::Completed,
+                0,
+                &stores.torrent_store,
+                &stores.stats,
+            )
+            .await;
+
+        let mut app = test::init_service(
+            App::new().app_data(stores.clone()).service(
+                web::scope("admin")
+                    .wrap(AdminAuth::new(stores.config.clone()))
+                    .configure(routes),
+            ),
+        )
+        .await;
+
+        let proper_resp = format!(
+            "{{\"info_hash\":\"{}\",\"seeders\":[{{\"peer_id\":\"{}\",\"ip\":\"10.0.0.1\",\
+             \"port\":6881,\"last_announced\":0,\"user_id\":null,\"uploaded\":0,\
+             \"downloaded\":0}}],\"leechers\":[]}}",
+            INFO_HASH,
+            PeerId(*b"-TR2940-000000000001")
+        );
+        let req = test::TestRequest::with_uri(&format!("/admin/swarms/{}", INFO_HASH))
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, proper_resp.as_bytes());
+
+        let uri = format!(
+            "/admin/swarms/{}/peers/{}",
+            INFO_HASH,
+            PeerId(*b"-TR2940-000000000001")
+        );
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        assert!(stores.peer_store.get_swarm(info_hash).await.is_none());
+        assert_eq!(stores.stats.total_seeders(), 0);
+        assert_eq!(
+            stores.torrent_store.get_announce_stats(info_hash).await,
+            (0, 0)
+        );
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn admin_reaps_stale_peers() {
+        let stores = admin_state();
+        let info_hash = InfoHash::from_bytes(INFO_HASH.as_bytes()).unwrap();
+        let stale = Instant::now()
+            .checked_sub(Duration::from_secs(7300))
+            .unwrap();
+        stores
+            .peer_store
+            .put_seeder(info_hash, peer(b"-TR2940-000000000001", Instant::now()))
+            .await;
+        stores
+            .peer_store
+            .put_leecher(info_hash, peer(b"-TR2940-000000000002", stale))
+            .await;
+
+        let mut app = test::init_service(
+            App::new().app_data(stores.clone()).service(
+                web::scope("admin")
+                    .wrap(AdminAuth::new(stores.config.clone()))
+                    .configure(routes),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/admin/janitor/reap")
+            .header("Authorization", "Bearer letmein")
+            .to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(
+            resp,
+            "{\"seeders\":0,\"leechers\":1,\"swarms\":0}".as_bytes()
+        );
+    }
+}
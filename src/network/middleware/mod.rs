@@ -1,28 +1,86 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use actix_service::{Service, Transform};
 use actix_web::dev::{ServiceRequest, ServiceResponse};
-use actix_web::{Error, HttpResponse};
-use futures::future::{ok, Either, Ready};
-use hashbrown::HashSet;
-use url::form_urlencoded;
+use actix_web::{web, Error, HttpResponse};
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
 
 use crate::bencode;
-use crate::bittorrent::AnnounceResponse;
+use crate::bittorrent::{AnnounceResponse, InfoHash, PeerId};
+use crate::config::{Config, SharedConfig, TorrentSource};
 use crate::errors::ClientError;
+use crate::metrics::Metrics;
+use crate::state::State;
+use crate::statistics::GlobalStatistics;
+use crate::util::{parse_query, string_to_event};
+
+// Requests turned away here never reach the handlers, so they
+// are counted as failed announces or scrapes on their behalf
+fn count_rejection(
+    req: &ServiceRequest,
+    metrics: &Metrics,
+    stats: &GlobalStatistics,
+    error: &ClientError,
+) {
+    metrics.failure(error);
+
+    if req.path().ends_with("/scrape") {
+        metrics.scrape(false);
+    } else if req.path().ends_with("/announce") {
+        let event = parse_query(req.query_string())
+            .into_iter()
+            .find(|(k, _)| k == "event")
+            .and_then(|(_, value)| {
+                string_to_event(String::from_utf8_lossy(&value).into_owned()).ok()
+            });
+
+        stats.fail_announce();
+        metrics.announce(event.as_ref(), false);
+    }
+}
+
+// Shared with the UDP listener, which has no middleware of its own
+pub fn client_approved(config: &Config, peer_id: &PeerId) -> bool {
+    let client_approval = &config.client_approval;
+    if !client_approval.enabled {
+        return true;
+    }
 
+    // Most clients do Azureus-style encoding which
+    // looks like '-AZ1234-' followed by a random string
+    let client_check = if client_approval.versioned {
+        &peer_id.0[1..7]
+    } else {
+        &peer_id.0[1..3]
+    };
+
+    let listed = client_approval
+        .client_list
+        .iter()
+        .any(|client| client.as_bytes() == client_check);
+
+    // Blacklisted clients are turned away, as are
+    // any clients missing from a whitelist
+    listed != client_approval.blacklist_style
+}
+
+// The client list is read from the current config on every request
+// so that changes to it take effect as soon as the config is reloaded
 pub struct ClientApproval {
-    blacklist_style: bool,
-    versioned: bool,
-    list: HashSet<String>,
+    config: SharedConfig,
+    metrics: Arc<Metrics>,
+    stats: Arc<GlobalStatistics>,
 }
 
 impl ClientApproval {
-    pub fn new(blacklist_style: bool, versioned: bool, client_list: Vec<String>) -> Self {
+    pub fn new(config: SharedConfig, metrics: Arc<Metrics>, stats: Arc<GlobalStatistics>) -> Self {
         ClientApproval {
-            blacklist_style,
-            versioned,
-            list: client_list.into_iter().collect(),
+            config,
+            metrics,
+            stats,
         }
     }
 }
@@ -42,17 +100,17 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(ClientApprovalMiddleware {
             service,
-            blacklist_style: self.blacklist_style,
-            versioned: self.versioned,
-            list: self.list.clone(),
+            config: self.config.clone(),
+            metrics: self.metrics.clone(),
+            stats: self.stats.clone(),
         })
     }
 }
 pub struct ClientApprovalMiddleware<S> {
     service: S,
-    blacklist_style: bool,
-    versioned: bool,
-    list: HashSet<String>,
+    config: SharedConfig,
+    metrics: Arc<Metrics>,
+    stats: Arc<GlobalStatistics>,
 }
 
 impl<S, B> Service for ClientApprovalMiddleware<S>
@@ -70,87 +128,170 @@ where
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let request_kv_pairs = form_urlencoded::parse(req.query_string().as_bytes()).into_owned();
-        let mut peer_string: String = "".to_string();
+        let config = self.config.current();
+        if !config.client_approval.enabled {
+            return Either::Left(self.service.call(req));
+        }
+
+        let request_kv_pairs = parse_query(req.query_string());
+        let mut peer_id = None;
 
         for (k, value) in request_kv_pairs {
             if let "peer_id" = k.as_str() {
-                peer_string = value
+                peer_id = PeerId::from_bytes(&value)
             }
         }
 
-        // If a client's peer string is empty, this is a Bad Thing
-        if peer_string.is_empty() {
-            let failure = AnnounceResponse::failure(ClientError::UnapprovedClient.text());
-            let bencoded = bencode::encode_announce_response(failure);
-            Either::Right(ok(req.into_response(
-                HttpResponse::Ok()
-                    .content_type("text/plain")
-                    .body(bencoded)
-                    .into_body(),
-            )))
-        } else {
-            // Most clients do Azureus-style encoding which
-            // looks like '-AZ1234-' followed by a random string
-            let client_check = if self.versioned {
-                &peer_string[1..7]
-            } else {
-                &peer_string[1..3]
-            };
-
-            if self.blacklist_style {
-                // Check that client isn't part of blacklist.
-                // If so, reject with same error as above.
-                // If not, let the request pass through.
-                if self.list.contains(&client_check.to_string()) {
-                    let failure = AnnounceResponse::failure(ClientError::UnapprovedClient.text());
-                    let bencoded = bencode::encode_announce_response(failure);
-                    Either::Right(ok(req.into_response(
-                        HttpResponse::Ok()
-                            .content_type("text/plain")
-                            .body(bencoded)
-                            .into_body(),
-                    )))
-                } else {
-                    Either::Left(self.service.call(req))
-                }
-            } else {
-                // Check that client is part of whitelist.
-                // If so, let the request pass through.
-                // If not, reject with same error as above.
-                if self.list.contains(&client_check.to_string()) {
-                    Either::Left(self.service.call(req))
-                } else {
-                    let failure = AnnounceResponse::failure(ClientError::UnapprovedClient.text());
-                    let bencoded = bencode::encode_announce_response(failure);
-                    Either::Right(ok(req.into_response(
-                        HttpResponse::Ok()
-                            .content_type("text/plain")
-                            .body(bencoded)
-                            .into_body(),
-                    )))
-                }
+        // If a client's peer ID is missing or malformed, this is a Bad Thing
+        let approved = match peer_id {
+            Some(id) => client_approved(&config, &id),
+            None => false,
+        };
+        if approved {
+            return Either::Left(self.service.call(req));
+        }
+
+        count_rejection(
+            &req,
+            &self.metrics,
+            &self.stats,
+            &ClientError::UnapprovedClient,
+        );
+        let failure = AnnounceResponse::failure(ClientError::UnapprovedClient.text());
+        let bencoded = bencode::encode_announce_response(failure);
+        Either::Right(ok(req.into_response(
+            HttpResponse::Ok()
+                .content_type("text/plain")
+                .body(bencoded)
+                .into_body(),
+        )))
+    }
+}
+
+// Guards the admin scope with a bearer token from the current config.
+// The API is hidden entirely while it is disabled or has no token.
+pub struct AdminAuth {
+    config: SharedConfig,
+}
+
+impl AdminAuth {
+    pub fn new(config: SharedConfig) -> Self {
+        AdminAuth { config }
+    }
+}
+
+impl<S, B> Transform<S> for AdminAuth
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AdminAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AdminAuthMiddleware {
+            service,
+            config: self.config.clone(),
+        })
+    }
+}
+pub struct AdminAuthMiddleware<S> {
+    service: S,
+    config: SharedConfig,
+}
+
+impl<S, B> Service for AdminAuthMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let config = self.config.current();
+        if !config.admin.enabled || config.admin.token.is_empty() {
+            return Either::Right(ok(
+                req.into_response(HttpResponse::NotFound().finish().into_body())
+            ));
+        }
+
+        let token = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match token {
+            Some(token) if tokens_match(token.as_bytes(), config.admin.token.as_bytes()) => {
+                Either::Left(self.service.call(req))
             }
+            _ => Either::Right(ok(
+                req.into_response(HttpResponse::Unauthorized().finish().into_body())
+            )),
         }
     }
 }
 
+// Looks at every byte regardless of where the first mismatch
+// is, so response times don't give away the token
+fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Shared with the UDP listener, which has no middleware of its own
+pub async fn torrent_approved(data: &State, info_hash: &InfoHash) -> bool {
+    let config = data.config.current();
+    let torrent_approval = &config.torrent_approval;
+    if !torrent_approval.enabled {
+        return true;
+    }
+
+    let listed = match torrent_approval.source {
+        TorrentSource::Config => torrent_approval.info_hashes.contains(info_hash),
+        TorrentSource::Backend => data
+            .torrent_store
+            .torrents
+            .read()
+            .await
+            .contains_key(info_hash),
+    };
+
+    listed != torrent_approval.blacklist_style
+}
+
+// Backend lists live behind the torrent store's lock, so unlike
+// ClientApproval the check has to happen inside the returned future
 pub struct TorrentApproval {
-    prohibited_list: HashSet<String>,
+    state: web::Data<State>,
 }
 
 impl TorrentApproval {
-    pub fn new(prohibited_list: Vec<String>) -> Self {
-        TorrentApproval {
-            prohibited_list: prohibited_list.into_iter().collect(),
-        }
+    pub fn new(state: web::Data<State>) -> Self {
+        TorrentApproval { state }
     }
 }
 
 impl<S, B> Transform<S> for TorrentApproval
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
+    B: 'static,
 {
     type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
@@ -161,53 +302,69 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ok(TorrentApprovalMiddleware {
-            service,
-            prohibited_list: self.prohibited_list.clone(),
+            service: Rc::new(RefCell::new(service)),
+            state: self.state.clone(),
         })
     }
 }
 pub struct TorrentApprovalMiddleware<S> {
-    service: S,
-    prohibited_list: HashSet<String>,
+    service: Rc<RefCell<S>>,
+    state: web::Data<State>,
 }
 
 impl<S, B> Service for TorrentApprovalMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
+    B: 'static,
 {
     type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
     type Error = Error;
-    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
         self.service.poll_ready(cx)
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let request_kv_pairs = form_urlencoded::parse(req.query_string().as_bytes()).into_owned();
-        let mut info_hash: String = "".to_string();
+        let mut service = self.service.clone();
+        let state = self.state.clone();
 
-        for (k, value) in request_kv_pairs {
-            if let "info_hash" = k.as_str() {
-                info_hash = value
+        Box::pin(async move {
+            if !state.config.current().torrent_approval.enabled {
+                return service.call(req).await;
             }
-        }
 
-        // If a client's peer string is empty, this is a Bad Thing
-        if self.prohibited_list.contains(&info_hash) {
-            let failure = AnnounceResponse::failure(ClientError::UnapprovedTorrent.text());
-            let bencoded = bencode::encode_announce_response(failure);
-            Either::Right(ok(req.into_response(
-                HttpResponse::Ok()
-                    .content_type("text/plain")
-                    .body(bencoded)
-                    .into_body(),
-            )))
-        } else {
-            Either::Left(self.service.call(req))
-        }
+            // Scrapes may carry several info hashes; all of them must pass.
+            // Malformed info hashes are left for the handlers to reject.
+            let info_hashes: Vec<InfoHash> = parse_query(req.query_string())
+                .into_iter()
+                .filter(|(k, _)| k == "info_hash")
+                .filter_map(|(_, value)| InfoHash::from_bytes(&value))
+                .collect();
+
+            for info_hash in &info_hashes {
+                if !torrent_approved(&state, info_hash).await {
+                    count_rejection(
+                        &req,
+                        &state.metrics,
+                        &state.stats,
+                        &ClientError::UnapprovedTorrent,
+                    );
+                    let failure = AnnounceResponse::failure(ClientError::UnapprovedTorrent.text());
+                    let bencoded = bencode::encode_announce_response(failure);
+                    return Ok(req.into_response(
+                        HttpResponse::Ok()
+                            .content_type("text/plain")
+                            .body(bencoded)
+                            .into_body(),
+                    ));
+                }
+            }
+
+            service.call(req).await
+        })
     }
 }
 
@@ -220,26 +377,31 @@ mod tests {
     use crate::config::Config;
     use crate::network::parse_announce;
     use crate::state::State;
-    use crate::storage::{TorrentRecords, TorrentStore};
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
 
     #[actix_rt::test]
     async fn client_blacklist_non_versioned() {
-        let config = Config::default();
-        let torrent_store = TorrentStore::new(TorrentRecords::new());
-        let stores = web::Data::new(State::new(config, torrent_store));
-        let blacklist_style = true;
-        let versioned = false;
-        let client_list = vec![
+        let mut config = Config::default();
+        config.client_approval.enabled = true;
+        config.client_approval.blacklist_style = true;
+        config.client_approval.versioned = false;
+        config.client_approval.client_list = vec![
             "DE".to_string(),
             "LT".to_string(),
             "qB".to_string(),
             "TR".to_string(),
             "UT".to_string(),
         ];
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
 
         let mut app = test::init_service(
             App::new()
-                .wrap(ClientApproval::new(blacklist_style, versioned, client_list))
+                .wrap(ClientApproval::new(
+                    stores.config.clone(),
+                    stores.metrics.clone(),
+                    stores.stats.clone(),
+                ))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -248,31 +410,43 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason17:Unapproved cliente".as_bytes();
-        let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
+        let proper_resp = "d14:failure reason17:Unapproved cliente".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1&event=started").to_request();
         let resp = test::read_response(&mut app, req).await;
 
         assert_eq!(resp, proper_resp);
+        assert_eq!(stores.stats.num_fails(), 1);
+        let rendered = stores
+            .metrics
+            .render(&stores.stats, &stores.peer_store)
+            .await;
+        assert!(rendered.contains("tyto_announces_total{event=\"started\",outcome=\"failure\"} 1"));
+        assert!(rendered.contains("tyto_failures_total{error=\"UnapprovedClient\"} 1"));
     }
 
     #[actix_rt::test]
     async fn client_blacklist_versioned() {
-        let config = Config::default();
-        let torrent_store = TorrentStore::new(TorrentRecords::new());
-        let stores = web::Data::new(State::new(config, torrent_store));
-        let blacklist_style = true;
-        let versioned = true;
-        let client_list = vec![
+        let mut config = Config::default();
+        config.client_approval.enabled = true;
+        config.client_approval.blacklist_style = true;
+        config.client_approval.versioned = true;
+        config.client_approval.client_list = vec![
             "DE9824".to_string(),
             "LT1111".to_string(),
             "qB2222".to_string(),
             "TR3333".to_string(),
             "UT4444".to_string(),
         ];
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
 
         let mut app = test::init_service(
             App::new()
-                .wrap(ClientApproval::new(blacklist_style, versioned, client_list))
+                .wrap(ClientApproval::new(
+                    stores.config.clone(),
+                    stores.metrics.clone(),
+                    stores.stats.clone(),
+                ))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -281,7 +455,7 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason17:Unapproved cliente".as_bytes();
+        let proper_resp = "d14:failure reason17:Unapproved cliente".as_bytes();
         let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
         let resp = test::read_response(&mut app, req).await;
 
@@ -290,22 +464,27 @@ mod tests {
 
     #[actix_rt::test]
     async fn client_whitelist_non_versioned() {
-        let config = Config::default();
-        let torrent_store = TorrentStore::new(TorrentRecords::new());
-        let stores = web::Data::new(State::new(config, torrent_store));
-        let blacklist_style = false;
-        let versioned = false;
-        let client_list = vec![
+        let mut config = Config::default();
+        config.client_approval.enabled = true;
+        config.client_approval.blacklist_style = false;
+        config.client_approval.versioned = false;
+        config.client_approval.client_list = vec![
             "DE".to_string(),
             "LT".to_string(),
             "qB".to_string(),
             "TR".to_string(),
             "UT".to_string(),
         ];
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
 
         let mut app = test::init_service(
             App::new()
-                .wrap(ClientApproval::new(blacklist_style, versioned, client_list))
+                .wrap(ClientApproval::new(
+                    stores.config.clone(),
+                    stores.metrics.clone(),
+                    stores.stats.clone(),
+                ))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -314,7 +493,7 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason17:Unapproved cliente".as_bytes();
+        let proper_resp = "d14:failure reason17:Unapproved cliente".as_bytes();
         let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-AZ9824-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
         let resp = test::read_response(&mut app, req).await;
 
@@ -323,22 +502,27 @@ mod tests {
 
     #[actix_rt::test]
     async fn client_whitelist_versioned() {
-        let config = Config::default();
-        let torrent_store = TorrentStore::new(TorrentRecords::new());
-        let stores = web::Data::new(State::new(config, torrent_store));
-        let blacklist_style = false;
-        let versioned = true;
-        let client_list = vec![
+        let mut config = Config::default();
+        config.client_approval.enabled = true;
+        config.client_approval.blacklist_style = false;
+        config.client_approval.versioned = true;
+        config.client_approval.client_list = vec![
             "DE1111".to_string(),
             "LT2222".to_string(),
             "qB3333".to_string(),
             "TR4444".to_string(),
             "UT5555".to_string(),
         ];
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
 
         let mut app = test::init_service(
             App::new()
-                .wrap(ClientApproval::new(blacklist_style, versioned, client_list))
+                .wrap(ClientApproval::new(
+                    stores.config.clone(),
+                    stores.metrics.clone(),
+                    stores.stats.clone(),
+                ))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -347,7 +531,7 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason17:Unapproved cliente".as_bytes();
+        let proper_resp = "d14:failure reason17:Unapproved cliente".as_bytes();
         let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
         let resp = test::read_response(&mut app, req).await;
 
@@ -356,18 +540,23 @@ mod tests {
 
     #[actix_rt::test]
     async fn torrent_blacklist() {
-        let config = Config::default();
+        let mut config = Config::default();
+        config.torrent_approval.enabled = true;
+        config.torrent_approval.blacklist_style = true;
+        config.torrent_approval.info_hashes = [
+            "2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f",
+            "3bbc36a0bcae854bd40c4deec639d4afadf65deb",
+            "8a541fa2db56003884b0acf9c059f6652d5f611c",
+        ]
+        .iter()
+        .filter_map(|h| InfoHash::from_bytes(h.as_bytes()))
+        .collect();
         let torrent_store = TorrentStore::new(TorrentRecords::new());
         let stores = web::Data::new(State::new(config, torrent_store));
-        let prohibited_list = vec![
-            "2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f".to_string(),
-            "3bbc36a0bcae854bd40c4deec639d4afadf65deb".to_string(),
-            "8a541fa2db56003884b0acf9c059f6652d5f611c".to_string(),
-        ];
 
         let mut app = test::init_service(
             App::new()
-                .wrap(TorrentApproval::new(prohibited_list))
+                .wrap(TorrentApproval::new(stores.clone()))
                 .service(
                     web::scope("announce")
                         .app_data(stores.clone())
@@ -376,10 +565,46 @@ mod tests {
         )
         .await;
 
-        let proper_resp = "d14:failure_reason18:Unapproved torrente".as_bytes();
+        let proper_resp = "d14:failure reason18:Unapproved torrente".as_bytes();
         let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
         let resp = test::read_response(&mut app, req).await;
 
         assert_eq!(resp, proper_resp);
     }
+
+    #[actix_rt::test]
+    async fn torrent_whitelist_from_backend() {
+        let mut config = Config::default();
+        config.torrent_approval.enabled = true;
+        config.torrent_approval.source = TorrentSource::Backend;
+        let info_hash = InfoHash::from_bytes(b"2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f").unwrap();
+        let mut torrents = TorrentRecords::new();
+        torrents.insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+        let stores = web::Data::new(State::new(config, TorrentStore::new(torrents)));
+
+        let mut app = test::init_service(
+            App::new()
+                .wrap(TorrentApproval::new(stores.clone()))
+                .service(
+                    web::scope("announce")
+                        .app_data(stores.clone())
+                        .route("", web::get().to(parse_announce)),
+                ),
+        )
+        .await;
+
+        let proper_resp = "d14:failure reason18:Unapproved torrente".as_bytes();
+        let req = test::TestRequest::with_uri("/announce?info_hash=3bbc36a0bcae854bd40c4deec639d4afadf65deb&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, proper_resp);
+
+        // Registered torrents go through to the announce handler
+        let req = test::TestRequest::with_uri("/announce?info_hash=2fa90c59c8072c5a4c54c1f1307dacaeb4c82f0f&peer_id=-DE0000-143964258012&port=6881&uploaded=9000&downloaded=1000&left=727955456&numwant=30&no_peer_id=1&compact=1&event=started&ip=10.0.0.1").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_ne!(resp, proper_resp);
+        assert_eq!(
+            stores.torrent_store.get_announce_stats(info_hash).await,
+            (0, 1)
+        );
+    }
 }
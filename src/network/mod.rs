@@ -1,181 +1,236 @@
+pub mod admin;
 pub mod middleware;
+pub mod udp;
+
+use std::sync::atomic::Ordering;
 
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
 
 use crate::bencode;
-use crate::bittorrent::{AnnounceRequest, AnnounceResponse, ScrapeRequest, ScrapeResponse};
+use crate::bittorrent::{
+    AnnounceRequest, AnnounceResponse, InfoHash, ScrapeRequest, ScrapeResponse,
+};
+use crate::errors::ClientError;
 use crate::state::State;
-use crate::statistics::ReturnedStatistics;
+use crate::statistics::{ReturnedStatistics, SwarmStatistics, TopSwarms};
+use crate::storage::{Snatcher, User};
 use crate::util::Event;
 
-pub async fn parse_announce(data: web::Data<State>, req: HttpRequest) -> impl Responder {
-    let announce_request = AnnounceRequest::new(req.query_string(), req.connection_info().remote());
+// Number of peers handed out when a client does not ask for a specific amount
+pub const DEFAULT_NUMWANT: u32 = 50;
 
-    match announce_request {
-        Ok(parsed_req) => {
-            // There are only three types of events that lead to
-            // actual change between swarms on the storage layer
-            match parsed_req.event {
-                // Started should be sent whenever a client
-                // starts or resumes the leeching process
-                Event::Started => {
-                    data.peer_store
-                        .put_leecher(parsed_req.info_hash.clone(), parsed_req.peer)
-                        .await;
-                    data.torrent_store
-                        .new_leech(parsed_req.info_hash.clone())
-                        .await;
-
-                    // Get randomized peer list
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
-
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
-
-                    // Associate all the requisite data together and
-                    // respond with the bencoded version of the data
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-
-                    let mut stats = data.stats.write().await;
-                    stats.add_leech();
-                    stats.succ_announce();
-
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
-                    HttpResponse::Ok().content_type("text/plain").body(bencoded)
-                }
+// Largest page of swarms handed out by the top swarms listing
+const MAX_PER_PAGE: usize = 100;
 
-                // Stopped should be sent when a client stops seed or leeching
-                Event::Stopped => {
-                    // If the peer is present in one set, then it
-                    // cannot be present in the other.
-                    let mut stats = data.stats.write().await;
-
-                    if data
-                        .peer_store
-                        .remove_seeder(parsed_req.info_hash.clone(), parsed_req.peer.clone())
-                        .await
-                    {
-                        stats.sub_seed();
-                    } else {
-                        data.peer_store
-                            .remove_leecher(parsed_req.info_hash.clone(), parsed_req.peer)
-                            .await;
-                        stats.sub_leech();
-                    }
+// Counts an announce that is being turned away and builds the failure
+// sent back for it. The event is only known once the request is parsed.
+pub async fn reject_announce(
+    data: &State,
+    event: Option<&Event>,
+    error: ClientError,
+) -> AnnounceResponse {
+    data.stats.fail_announce();
+    data.metrics.announce(event, false);
+    data.metrics.failure(&error);
+    AnnounceResponse::failure(error.text())
+}
 
-                    stats.succ_announce();
-
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
-
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
-
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
-                    HttpResponse::Ok().content_type("text/plain").body(bencoded)
-                }
+pub async fn parse_announce(data: web::Data<State>, req: HttpRequest) -> impl Responder {
+    announce(&data, &req, None).await
+}
 
-                // Completed should be sent when a peer receives 100%
-                // of the data associated with a particular torrent
-                Event::Completed => {
-                    data.peer_store
-                        .promote_leecher(parsed_req.info_hash.clone(), parsed_req.peer)
-                        .await;
-                    data.torrent_store
-                        .new_seed(parsed_req.info_hash.clone())
-                        .await;
-
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
-
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
-
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-                    let mut stats = data.stats.write().await;
-                    stats.promote_leech();
-                    stats.succ_announce();
-
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
-                    HttpResponse::Ok().content_type("text/plain").body(bencoded)
-                }
+// Private trackers hand each user an announce URL containing
+// their passkey, which ties every peer they run back to them
+pub async fn parse_private_announce(
+    data: web::Data<State>,
+    req: HttpRequest,
+    passkey: web::Path<String>,
+) -> impl Responder {
+    match data.user_store.get_user(&passkey).await {
+        Some(user) => announce(&data, &req, Some(user)).await,
+        None => {
+            let failure = reject_announce(&data, None, ClientError::InvalidPasskey).await;
+            let bencoded = bencode::encode_announce_response(failure);
+            HttpResponse::Ok().content_type("text/plain").body(bencoded)
+        }
+    }
+}
 
-                // None should only be sent if
-                // there is no change in snatch state
-                Event::None => {
-                    // This updates a peer if it is present in either swarm.
-                    // It is intended that a client correctly send its states.
-                    // If a client starts out with this event, it will never be added.
-                    data.peer_store
-                        .update_peer(parsed_req.info_hash.clone(), parsed_req.peer)
-                        .await;
-
-                    let (peers, peers6) = data
-                        .peer_store
-                        .get_peers(parsed_req.info_hash.clone(), parsed_req.numwant.unwrap())
-                        .await;
-
-                    let (complete, incomplete) = data
-                        .torrent_store
-                        .get_announce_stats(parsed_req.info_hash)
-                        .await;
-
-                    let response = AnnounceResponse::new(
-                        data.config.bt.announce_rate as u32,
-                        complete,
-                        incomplete,
-                        peers,
-                        peers6,
-                    );
-                    let bencoded = bencode::encode_announce_response(response.unwrap());
-                    data.stats.write().await.succ_announce();
-                    HttpResponse::Ok().content_type("text/plain").body(bencoded)
-                }
-            }
+async fn announce(data: &State, req: &HttpRequest, user: Option<User>) -> HttpResponse {
+    let _timer = data.metrics.request_timer("http", "announce");
+    let announce_request = AnnounceRequest::new(req.query_string(), req.connection_info().remote());
+
+    match announce_request {
+        Ok(mut parsed_req) => {
+            parsed_req.peer.set_user_id(user.map(|u| u.id));
+
+            let response = handle_announce(data, parsed_req).await;
+            let bencoded = bencode::encode_announce_response(response);
+            HttpResponse::Ok().content_type("text/plain").body(bencoded)
         }
 
         // If the request is not parse-able, short-circuit and respond with failure
         Err(failure) => {
             let bencoded = bencode::encode_announce_response(failure);
-            data.stats.write().await.fail_announce();
+            data.stats.fail_announce();
+            data.metrics.announce(None, false);
+            data.metrics.failure(&ClientError::MalformedAnnounce);
             HttpResponse::Ok().content_type("text/plain").body(bencoded)
         }
     }
 }
 
+// Applies a parsed announce to the peer and torrent stores and builds
+// the response. Both the HTTP and UDP listeners go through here so
+// that they share the same swarms and statistics.
+pub async fn handle_announce(data: &State, parsed_req: AnnounceRequest) -> AnnounceResponse {
+    let numwant = parsed_req.numwant.unwrap_or(DEFAULT_NUMWANT);
+    let config = data.config.current();
+
+    // Swarms are left alone once the final flush is underway
+    if data.shutting_down.load(Ordering::SeqCst) {
+        return reject_announce(data, Some(&parsed_req.event), ClientError::ShuttingDown).await;
+    }
+
+    // Only announces made through a passkey URL carry a user,
+    // so this also turns away UDP announces on private trackers
+    if config.bt.private && parsed_req.peer.user_id().is_none() {
+        return reject_announce(data, Some(&parsed_req.event), ClientError::InvalidPasskey).await;
+    }
+
+    // Operators may choose to only serve compact peer lists
+    if !parsed_req.compact && config.bt.compact_only {
+        return reject_announce(data, Some(&parsed_req.event), ClientError::NotCompact).await;
+    }
+
+    // Open trackers learn about torrents from their announces rather than
+    // the backend; the record is then persisted by the next flush
+    if config.bt.open
+        && parsed_req.event != Event::Stopped
+        && !data
+            .torrent_store
+            .register(parsed_req.info_hash, config.bt.max_torrents)
+            .await
+    {
+        return reject_announce(
+            data,
+            Some(&parsed_req.event),
+            ClientError::TorrentLimitReached,
+        )
+        .await;
+    }
+
+    // Only the first completion by each peer, or by each user on a
+    // private tracker, is counted as a snatch of a known torrent
+    if parsed_req.event == Event::Completed
+        && data
+            .torrent_store
+            .torrents
+            .read()
+            .await
+            .contains_key(&parsed_req.info_hash)
+        && data
+            .snatch_store
+            .record(parsed_req.info_hash, Snatcher::of(&parsed_req.peer))
+            .await
+    {
+        data.torrent_store.add_snatch(parsed_req.info_hash).await;
+    }
+
+    // The peer store works out where the event and the amount left leave
+    // the peer, and the torrent and global counts follow that move. Repeated
+    // or out of order events therefore refresh the peer without skewing the
+    // counts, and peers missing from the swarm are added back.
+    let user_id = parsed_req.peer.user_id();
+    let announced = data
+        .peer_store
+        .announce(
+            parsed_req.info_hash,
+            parsed_req.peer,
+            &parsed_req.event,
+            parsed_req.left,
+            &data.torrent_store,
+            &data.stats,
+        )
+        .await;
+
+    let (uploaded, downloaded) = (announced.uploaded, announced.downloaded);
+    if uploaded > 0 || downloaded > 0 {
+        data.torrent_store
+            .add_traffic(parsed_req.info_hash, uploaded, downloaded)
+            .await;
+        if let Some(user_id) = user_id {
+            data.user_store
+                .add_transfer(user_id, uploaded, downloaded)
+                .await;
+        }
+    }
+
+    let (complete, incomplete) = data
+        .torrent_store
+        .get_announce_stats(parsed_req.info_hash)
+        .await;
+
+    data.stats.succ_announce();
+    data.metrics.announce(Some(&parsed_req.event), true);
+
+    // Get randomized peer list, associate all the requisite
+    // data together and let the caller encode it for the wire
+    if parsed_req.compact {
+        let (peers, peers6) = data
+            .peer_store
+            .get_peers(parsed_req.info_hash, numwant)
+            .await;
+
+        AnnounceResponse::new(
+            config.bt.announce_rate as u32,
+            complete,
+            incomplete,
+            peers,
+            peers6,
+        )
+        .unwrap()
+    } else {
+        let peers = data
+            .peer_store
+            .get_dictionary_peers(parsed_req.info_hash, numwant, parsed_req.no_peer_id)
+            .await;
+
+        AnnounceResponse::non_compact(config.bt.announce_rate as u32, complete, incomplete, peers)
+    }
+}
+
 pub async fn parse_scrape(data: web::Data<State>, req: HttpRequest) -> impl Responder {
+    if data.config.current().bt.private {
+        let failure = ScrapeResponse::failure(ClientError::InvalidPasskey.text());
+        let bencoded = bencode::encode_scrape_response(failure);
+        data.metrics.scrape(false);
+        data.metrics.failure(&ClientError::InvalidPasskey);
+        return HttpResponse::Ok().content_type("text/plain").body(bencoded);
+    }
+
+    scrape(&data, &req).await
+}
+
+pub async fn parse_private_scrape(
+    data: web::Data<State>,
+    req: HttpRequest,
+    passkey: web::Path<String>,
+) -> impl Responder {
+    if data.user_store.get_user(&passkey).await.is_none() {
+        let failure = ScrapeResponse::failure(ClientError::InvalidPasskey.text());
+        let bencoded = bencode::encode_scrape_response(failure);
+        data.metrics.scrape(false);
+        data.metrics.failure(&ClientError::InvalidPasskey);
+        return HttpResponse::Ok().content_type("text/plain").body(bencoded);
+    }
+
+    scrape(&data, &req).await
+}
+
+async fn scrape(data: &State, req: &HttpRequest) -> HttpResponse {
+    let _timer = data.metrics.request_timer("http", "scrape");
     let scrape_request = ScrapeRequest::new(req.query_string());
     match scrape_request {
         Ok(parsed_req) => {
@@ -183,27 +238,109 @@ pub async fn parse_scrape(data: web::Data<State>, req: HttpRequest) -> impl Resp
             let mut scrape_response = ScrapeResponse::new().unwrap();
 
             for file in scrape_files {
-                scrape_response.add_file(file.info_hash.clone(), file);
+                scrape_response.add_file(file.info_hash, file);
             }
 
             let bencoded = bencode::encode_scrape_response(scrape_response);
-            data.stats.write().await.incr_scrapes();
+            data.stats.incr_scrapes();
+            data.metrics.scrape(true);
             HttpResponse::Ok().content_type("text/plain").body(bencoded)
         }
 
         Err(failure) => {
             let bencoded = bencode::encode_scrape_response(failure);
+            data.metrics.scrape(false);
+            data.metrics.failure(&ClientError::MalformedScrape);
             HttpResponse::Ok().content_type("text/plain").body(bencoded)
         }
     }
 }
 
 pub async fn get_stats(data: web::Data<State>) -> impl Responder {
-    let global_stats = data.stats.read().await;
-    let stats = ReturnedStatistics::new(&global_stats);
+    let stats = ReturnedStatistics::new(&data.stats);
     web::Json(stats)
 }
 
+pub async fn get_torrent_stats(
+    data: web::Data<State>,
+    info_hash: web::Path<String>,
+) -> HttpResponse {
+    let info_hash = match InfoHash::from_bytes(info_hash.as_bytes()) {
+        Some(info_hash) => info_hash,
+        None => return HttpResponse::BadRequest().body("Invalid info hash"),
+    };
+
+    let swarm = data.peer_store.get_swarm(info_hash).await;
+    let mut stats = SwarmStatistics::new(info_hash, swarm.as_ref());
+    match data.torrent_store.torrents.read().await.get(&info_hash) {
+        Some(torrent) => stats.add_torrent(torrent),
+        None if stats.seeders + stats.leechers == 0 => {
+            return HttpResponse::NotFound().finish();
+        }
+        None => {}
+    }
+
+    HttpResponse::Ok().json(stats)
+}
+
+#[derive(Deserialize)]
+pub struct TopSwarmsQuery {
+    #[serde(default = "default_page")]
+    page: usize,
+    #[serde(default = "default_per_page")]
+    per_page: usize,
+}
+
+fn default_page() -> usize {
+    1
+}
+
+fn default_per_page() -> usize {
+    20
+}
+
+pub async fn get_top_swarms(
+    data: web::Data<State>,
+    query: web::Query<TopSwarmsQuery>,
+) -> HttpResponse {
+    let page = query.page.max(1);
+    let per_page = query.per_page.clamp(1, MAX_PER_PAGE);
+
+    let ranking = data.swarm_ranking.get(&data.peer_store).await;
+
+    let mut swarms = Vec::new();
+    for info_hash in ranking
+        .iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+    {
+        let swarm = data.peer_store.get_swarm(*info_hash).await;
+        swarms.push((*info_hash, SwarmStatistics::new(*info_hash, swarm.as_ref())));
+    }
+    let total = ranking.len();
+
+    let torrents = data.torrent_store.torrents.read().await;
+    for (info_hash, stats) in swarms.iter_mut() {
+        if let Some(torrent) = torrents.get(info_hash) {
+            stats.add_torrent(torrent);
+        }
+    }
+
+    HttpResponse::Ok().json(TopSwarms {
+        page,
+        per_page,
+        total,
+        swarms: swarms.into_iter().map(|(_, stats)| stats).collect(),
+    })
+}
+
+pub async fn get_metrics(data: web::Data<State>) -> impl Responder {
+    let rendered = data.metrics.render(&data.stats, &data.peer_store).await;
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(rendered)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -211,9 +348,19 @@ mod tests {
     use actix_service::Service;
     use actix_web::{test, web, App, HttpResponse};
 
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::{Duration, Instant};
+
+    use proptest::prelude::*;
+
+    use crate::bittorrent::{InfoHash, Peer, PeerId, Peerv4, Peerv6};
     use crate::config::Config;
     use crate::state::State;
-    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+    use crate::storage::expiry::BUCKET_WIDTH;
+    use crate::storage::janitor;
+    use crate::storage::{
+        SnatchStore, Torrent, TorrentRecords, TorrentStore, UserRecords, UserStore, UserTransfer,
+    };
 
     #[actix_rt::test]
     async fn index_get_not_allowed() {
@@ -232,9 +379,7 @@ mod tests {
                         .app_data(stores.clone())
                         .route("", web::get().to(parse_scrape)),
                 )
-                .service(
-                    web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())),
-                ),
+                .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed))),
         )
         .await;
 
@@ -261,13 +406,11 @@ mod tests {
                         .app_data(stores.clone())
                         .route("", web::get().to(parse_scrape)),
                 )
-                .service(
-                    web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())),
-                ),
+                .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed))),
         )
         .await;
 
-        let proper_resp = "d14:failure_reason26:Malformed announce requeste".as_bytes();
+        let proper_resp = "d14:failure reason26:Malformed announce requeste".as_bytes();
         let req = test::TestRequest::with_uri("/announce?bad_stuff=123").to_request();
         let resp = test::read_response(&mut app, req).await;
 
@@ -291,13 +434,11 @@ mod tests {
                         .app_data(stores.clone())
                         .route("", web::get().to(parse_scrape)),
                 )
-                .service(
-                    web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())),
-                ),
+                .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed))),
         )
         .await;
 
-        let proper_resp = "d14:failure_reason24:Malformed scrape requeste".as_bytes();
+        let proper_resp = "d14:failure reason24:Malformed scrape requeste".as_bytes();
         let req = test::TestRequest::with_uri("/scrape?bad_stuff=123").to_request();
         let resp = test::read_response(&mut app, req).await;
 
@@ -310,16 +451,16 @@ mod tests {
         let torrent_store = TorrentStore::new(TorrentRecords::new());
         let stores = web::Data::new(State::new(config, torrent_store));
 
-        let info_hash1 = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash1 = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let torrent1 = Torrent::new(info_hash1, 10, 34, 7, 10000000);
 
-        let info_hash2 = "B2C3D4E5F6G7H8I9J0K1".to_string();
+        let info_hash2 = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
         let torrent2 = Torrent::new(info_hash2, 25, 57, 19, 20000000);
 
         {
             let mut store = stores.torrent_store.torrents.write().await;
-            store.insert(torrent1.info_hash.clone(), torrent1);
-            store.insert(torrent2.info_hash.clone(), torrent2);
+            store.insert(torrent1.info_hash, torrent1);
+            store.insert(torrent2.info_hash, torrent2);
         }
 
         let mut app = test::init_service(
@@ -334,9 +475,7 @@ mod tests {
                         .app_data(stores.clone())
                         .route("", web::get().to(parse_scrape)),
                 )
-                .service(
-                    web::scope("/").route("", web::get().to(|| HttpResponse::MethodNotAllowed())),
-                ),
+                .service(web::scope("/").route("", web::get().to(HttpResponse::MethodNotAllowed))),
         )
         .await;
 
@@ -349,4 +488,730 @@ mod tests {
 
         assert_eq!(resp, proper_resp);
     }
+
+    #[actix_rt::test]
+    async fn announce_get_non_compact() {
+        let config = Config::default();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        let uri = "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012\
+                   &port=6881&uploaded=0&downloaded=0&left=1000&event=started&compact=0\
+                   &ip=10.0.0.1";
+
+        let proper_resp = "d8:completei0e10:incompletei0e8:intervali1800e5:peersl\
+                           d2:ip8:10.0.0.17:peer id20:-DE9824-1439642580124:porti6881eeee"
+            .as_bytes();
+        let req = test::TestRequest::with_uri(uri).to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+    }
+
+    #[actix_rt::test]
+    async fn announce_get_non_compact_rejected() {
+        let mut config = Config::default();
+        config.bt.compact_only = true;
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        let uri = "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012\
+                   &port=6881&uploaded=0&downloaded=0&left=1000&event=started&compact=0\
+                   &ip=10.0.0.1";
+
+        let proper_resp = "d14:failure reason35:Announces must be in compact formate".as_bytes();
+        let req = test::TestRequest::with_uri(uri).to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+        assert!(stores
+            .peer_store
+            .get_swarm(InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"))
+            .await
+            .is_none());
+    }
+
+    #[actix_rt::test]
+    async fn announce_rejected_while_shutting_down() {
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(Config::default(), torrent_store));
+        stores.shutting_down.store(true, Ordering::SeqCst);
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        let uri = "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012\
+                   &port=6881&uploaded=0&downloaded=0&left=1000&event=started\
+                   &ip=10.0.0.1";
+
+        let proper_resp = "d14:failure reason24:Tracker is shutting downe".as_bytes();
+        let req = test::TestRequest::with_uri(uri).to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+        assert!(stores
+            .peer_store
+            .get_swarm(InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"))
+            .await
+            .is_none());
+    }
+
+    #[actix_rt::test]
+    async fn stats_report_global_counters() {
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(Config::default(), torrent_store));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .service(web::scope("announce").route("", web::get().to(parse_announce)))
+                .service(web::scope("stats").route("", web::get().to(get_stats))),
+        )
+        .await;
+
+        let uri = "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012\
+                   &port=6881&uploaded=0&downloaded=0&left=1000&event=started\
+                   &ip=10.0.0.1&compact=1";
+        let req = test::TestRequest::with_uri(uri).to_request();
+        test::read_response(&mut app, req).await;
+        let req = test::TestRequest::with_uri("/announce?info_hash=A1B2").to_request();
+        test::read_response(&mut app, req).await;
+
+        let req = test::TestRequest::with_uri("/stats").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let rendered = String::from_utf8(resp.to_vec()).unwrap();
+
+        assert!(rendered.starts_with("{\"uptime\":"));
+        assert!(rendered.ends_with(
+            ",\"total_seeders\":0,\"total_leechers\":1,\"announce_requests\":2,\
+             \"succ_announces\":1,\"scrapes\":0}"
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn metrics_report_announces() {
+        let mut config = Config::default();
+        config.bt.compact_only = true;
+        config.admin.enabled = true;
+        config.admin.token = "s3cret".to_string();
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .service(web::scope("announce").route("", web::get().to(parse_announce)))
+                .service(web::scope("metrics").route("", web::get().to(get_metrics))),
+        )
+        .await;
+
+        let uri = "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012\
+                   &port=6881&uploaded=0&downloaded=0&left=1000&event=started\
+                   &ip=10.0.0.1";
+        let req = test::TestRequest::with_uri(uri).to_request();
+        test::read_response(&mut app, req).await;
+        let req = test::TestRequest::with_uri(&format!("{}&compact=0", uri)).to_request();
+        test::read_response(&mut app, req).await;
+
+        // Scrapes need no credentials, even with the admin API enabled
+        let req = test::TestRequest::with_uri("/metrics").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let rendered = String::from_utf8(resp.to_vec()).unwrap();
+
+        for line in [
+            "tyto_announces_total{event=\"started\",outcome=\"success\"} 1",
+            "tyto_announces_total{event=\"started\",outcome=\"failure\"} 1",
+            "tyto_failures_total{error=\"NotCompact\"} 1",
+            "tyto_peers{family=\"ipv4\",kind=\"leecher\"} 1",
+            "tyto_swarms 1",
+            "tyto_request_duration_seconds_count{protocol=\"http\",request=\"announce\"} 2",
+        ] {
+            assert!(rendered.contains(line), "missing {}", line);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn open_announce_registers_torrent() {
+        let mut config = Config::default();
+        config.bt.open = true;
+        config.bt.max_torrents = 1;
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(config, torrent_store));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("announce")
+                    .app_data(stores.clone())
+                    .route("", web::get().to(parse_announce)),
+            ),
+        )
+        .await;
+
+        let uri = "/announce?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012\
+                   &port=6881&uploaded=0&downloaded=0&left=1000&event=started\
+                   &ip=10.0.0.1";
+        let req = test::TestRequest::with_uri(uri).to_request();
+        test::read_response(&mut app, req).await;
+
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        assert_eq!(
+            stores.torrent_store.get_announce_stats(info_hash).await,
+            (0, 1)
+        );
+
+        // The store is full, so the next new torrent is turned away
+        let uri = "/announce?info_hash=B2C3D4E5F6G7H8I9J0K1&peer_id=-DE9824-143964258012\
+                   &port=6881&uploaded=0&downloaded=0&left=1000&event=started\
+                   &ip=10.0.0.1";
+        let proper_resp = "d14:failure reason37:Tracker is not accepting new torrentse".as_bytes();
+        let req = test::TestRequest::with_uri(uri).to_request();
+        let resp = test::read_response(&mut app, req).await;
+
+        assert_eq!(resp, proper_resp);
+        assert_eq!(stores.torrent_store.torrents.read().await.len(), 1);
+    }
+
+    fn stats_peer(peer_id: &[u8; 20], v6: bool) -> Peer {
+        if v6 {
+            Peer::V6(Peerv6 {
+                peer_id: PeerId(*peer_id),
+                ip: Ipv6Addr::LOCALHOST,
+                port: 6881,
+                last_announced: Instant::now(),
+                user_id: None,
+                uploaded: 0,
+                downloaded: 0,
+            })
+        } else {
+            Peer::V4(Peerv4 {
+                peer_id: PeerId(*peer_id),
+                ip: Ipv4Addr::new(10, 0, 0, 1),
+                port: 6881,
+                last_announced: Instant::now(),
+                user_id: None,
+                uploaded: 0,
+                downloaded: 0,
+            })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn torrent_stats_report_swarm() {
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut torrents = TorrentRecords::new();
+        torrents.insert(info_hash, Torrent::new(info_hash, 1, 5, 2, 1000));
+        let stores = web::Data::new(State::new(Config::default(), TorrentStore::new(torrents)));
+        stores
+            .peer_store
+            .put_seeder(info_hash, stats_peer(b"-TR2940-000000000001", false))
+            .await;
+        stores
+            .peer_store
+            .put_leecher(info_hash, stats_peer(b"-TR2940-000000000002", true))
+            .await;
+        stores
+            .peer_store
+            .put_leecher(info_hash, stats_peer(b"M7-2-2--000000000003", false))
+            .await;
+
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("stats")
+                    .app_data(stores.clone())
+                    .route("/torrent/{info_hash}", web::get().to(get_torrent_stats)),
+            ),
+        )
+        .await;
+
+        let proper_resp = format!(
+            "{{\"info_hash\":\"{}\",\"seeders\":1,\"leechers\":2,\"ipv4\":2,\"ipv6\":1,\
+             \"downloaded\":5,\"balance\":1000,\"clients\":{{\"TR\":2,\"unknown\":1}},\
+             \"last_announce\":0}}",
+            info_hash
+        );
+        let req =
+            test::TestRequest::with_uri(&format!("/stats/torrent/{}", info_hash)).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, proper_resp.as_bytes());
+
+        let req =
+            test::TestRequest::with_uri("/stats/torrent/3bbc36a0bcae854bd40c4deec639d4afadf65deb")
+                .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn top_swarms_are_paginated() {
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let stores = web::Data::new(State::new(Config::default(), torrent_store));
+        let peer_ids = [
+            b"-TR2940-000000000001",
+            b"-TR2940-000000000002",
+            b"-TR2940-000000000003",
+        ];
+        for (i, info_hash) in [
+            b"A1B2C3D4E5F6G7H8I9J0",
+            b"B2C3D4E5F6G7H8I9J0K1",
+            b"C3D4E5F6G7H8I9J0K1L2",
+        ]
+        .iter()
+        .enumerate()
+        {
+            for peer_id in &peer_ids[..=i] {
+                stores
+                    .peer_store
+                    .put_leecher(InfoHash(**info_hash), stats_peer(peer_id, false))
+                    .await;
+            }
+        }
+
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("stats")
+                    .app_data(stores.clone())
+                    .route("/top", web::get().to(get_top_swarms)),
+            ),
+        )
+        .await;
+
+        // Second page of two holds the smallest swarm
+        let req = test::TestRequest::with_uri("/stats/top?page=2&per_page=2").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let rendered = String::from_utf8(resp.to_vec()).unwrap();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+
+        assert!(rendered.starts_with("{\"page\":2,\"per_page\":2,\"total\":3,"));
+        assert!(rendered.contains(&format!(
+            "\"info_hash\":\"{}\",\"seeders\":0,\"leechers\":1",
+            info_hash
+        )));
+        assert_eq!(rendered.matches("info_hash").count(), 1);
+    }
+
+    fn private_state() -> web::Data<State> {
+        let mut config = Config::default();
+        config.bt.private = true;
+        let torrent_store = TorrentStore::new(TorrentRecords::new());
+        let mut state = State::new(config, torrent_store);
+
+        let mut users = UserRecords::new();
+        for user in [
+            User::new(1, "a1b2c3d4e5f6".to_string(), false),
+            User::new(2, "f6e5d4c3b2a1".to_string(), true),
+        ] {
+            users.insert(user.passkey.clone(), user);
+        }
+        state.user_store = UserStore::new(users);
+
+        web::Data::new(state)
+    }
+
+    #[actix_rt::test]
+    async fn private_announce_links_peer_to_user() {
+        let stores = private_state();
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .route("/announce", web::get().to(parse_announce))
+                .route("/{passkey}/announce", web::get().to(parse_private_announce)),
+        )
+        .await;
+
+        let query = "?info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012\
+                     &port=6881&uploaded=0&downloaded=0&left=1000&event=started\
+                     &ip=10.0.0.1";
+        let failure = "d14:failure reason26:Invalid or revoked passkeye".as_bytes();
+
+        // Public, unknown and revoked announce URLs are all turned away
+        for path in &[
+            "/announce",
+            "/000000000000/announce",
+            "/f6e5d4c3b2a1/announce",
+        ] {
+            let req = test::TestRequest::with_uri(&format!("{}{}", path, query)).to_request();
+            let resp = test::read_response(&mut app, req).await;
+            assert_eq!(resp, failure);
+        }
+        assert!(stores
+            .peer_store
+            .get_swarm(InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"))
+            .await
+            .is_none());
+
+        let req =
+            test::TestRequest::with_uri(&format!("/a1b2c3d4e5f6/announce{}", query)).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert!(resp.starts_with(b"d8:complete"));
+
+        let swarm = stores
+            .peer_store
+            .get_swarm(InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"))
+            .await
+            .unwrap();
+        let peer = swarm.leechers.iter().next().unwrap();
+        assert_eq!(peer.user_id(), Some(1));
+    }
+
+    #[actix_rt::test]
+    async fn private_scrape_requires_passkey() {
+        let stores = private_state();
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .route("/scrape", web::get().to(parse_scrape))
+                .route("/{passkey}/scrape", web::get().to(parse_private_scrape)),
+        )
+        .await;
+
+        let query = "?info_hash=A1B2C3D4E5F6G7H8I9J0";
+        let failure = "d14:failure reason26:Invalid or revoked passkeye".as_bytes();
+
+        for path in &["/scrape", "/f6e5d4c3b2a1/scrape"] {
+            let req = test::TestRequest::with_uri(&format!("{}{}", path, query)).to_request();
+            let resp = test::read_response(&mut app, req).await;
+            assert_eq!(resp, failure);
+        }
+
+        let req =
+            test::TestRequest::with_uri(&format!("/a1b2c3d4e5f6/scrape{}", query)).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, "d5:filesdee".as_bytes());
+    }
+
+    #[actix_rt::test]
+    async fn private_announce_accounts_transfers() {
+        let stores = private_state();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        stores
+            .torrent_store
+            .torrents
+            .write()
+            .await
+            .insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .route("/{passkey}/announce", web::get().to(parse_private_announce)),
+        )
+        .await;
+
+        // Started, a regular announce, then a client restart that
+        // resets its counters before announcing again
+        for (uploaded, downloaded, event) in &[
+            (0, 0, "started"),
+            (1000, 400, ""),
+            (300, 100, "started"),
+            (500, 100, "stopped"),
+        ] {
+            let uri = format!(
+                "/a1b2c3d4e5f6/announce?info_hash=A1B2C3D4E5F6G7H8I9J0\
+                 &peer_id=-DE9824-143964258012&port=6881&uploaded={}&downloaded={}\
+                 &left=1000&event={}&ip=10.0.0.1",
+                uploaded, downloaded, event
+            );
+            let req = test::TestRequest::with_uri(&uri).to_request();
+            test::read_response(&mut app, req).await;
+        }
+
+        let transfers = stores.user_store.take_transfers().await;
+        assert_eq!(
+            transfers,
+            vec![UserTransfer {
+                user_id: 1,
+                uploaded: 1000 + 300 + 200,
+                downloaded: 400 + 100,
+            }]
+        );
+
+        let torrents = stores.torrent_store.torrents.read().await;
+        assert_eq!(torrents.get(&info_hash).unwrap().balance, 1500 + 500);
+    }
+
+    #[actix_rt::test]
+    async fn private_announce_accounts_multi_terabyte_transfers() {
+        let stores = private_state();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        stores
+            .torrent_store
+            .torrents
+            .write()
+            .await
+            .insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .route("/{passkey}/announce", web::get().to(parse_private_announce)),
+        )
+        .await;
+
+        for (uploaded, downloaded, event) in
+            &[(4u64 << 40, 3u64 << 40, "started"), (10 << 40, 8 << 40, "")]
+        {
+            let uri = format!(
+                "/a1b2c3d4e5f6/announce?info_hash=A1B2C3D4E5F6G7H8I9J0\
+                 &peer_id=-DE9824-143964258012&port=6881&uploaded={}&downloaded={}\
+                 &left={}&event={}&ip=10.0.0.1",
+                uploaded,
+                downloaded,
+                20u64 << 40,
+                event
+            );
+            let req = test::TestRequest::with_uri(&uri).to_request();
+            test::read_response(&mut app, req).await;
+        }
+
+        let transfers = stores.user_store.take_transfers().await;
+        assert_eq!(
+            transfers,
+            vec![UserTransfer {
+                user_id: 1,
+                uploaded: 6 << 40,
+                downloaded: 5 << 40,
+            }]
+        );
+
+        let torrents = stores.torrent_store.torrents.read().await;
+        assert_eq!(torrents.get(&info_hash).unwrap().balance, 11 << 40);
+    }
+
+    #[actix_rt::test]
+    async fn completed_announces_count_snatches_once() {
+        let config = Config::default();
+        let stores = web::Data::new(State::new(config, TorrentStore::default()));
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        stores
+            .torrent_store
+            .torrents
+            .write()
+            .await
+            .insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .route("/announce", web::get().to(parse_announce))
+                .route("/scrape", web::get().to(parse_scrape)),
+        )
+        .await;
+
+        // A second completion from the same peer and one for
+        // an unknown torrent are both left uncounted
+        for (info_hash, peer_id) in &[
+            ("A1B2C3D4E5F6G7H8I9J0", "-TR2940-000000000001"),
+            ("A1B2C3D4E5F6G7H8I9J0", "-TR2940-000000000001"),
+            ("A1B2C3D4E5F6G7H8I9J0", "-TR2940-000000000002"),
+            ("B2C3D4E5F6G7H8I9J0K1", "-TR2940-000000000001"),
+        ] {
+            let uri = format!(
+                "/announce?info_hash={}&peer_id={}&port=6881&uploaded=0\
+                 &downloaded=0&left=0&event=completed&ip=10.0.0.1",
+                info_hash, peer_id
+            );
+            let req = test::TestRequest::with_uri(&uri).to_request();
+            test::read_response(&mut app, req).await;
+        }
+
+        let req =
+            test::TestRequest::with_uri("/scrape?info_hash=A1B2C3D4E5F6G7H8I9J0").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(
+            resp,
+            "d5:filesd20:A1B2C3D4E5F6G7H8I9J0d8:completei2e10:downloadedi2e10:incompletei0eeee"
+                .as_bytes()
+        );
+
+        // Flushed snatches still count after a restart
+        assert_eq!(janitor::flush_snatches(&stores).await, Ok(2));
+        let restarted = SnatchStore::new(stores.backend.load_snatches().unwrap());
+        let snatcher = Snatcher::Peer(PeerId(*b"-TR2940-000000000001"));
+        assert!(!restarted.record(info_hash, snatcher).await);
+    }
+
+    #[actix_rt::test]
+    async fn private_snatches_are_per_user() {
+        let stores = private_state();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        stores
+            .torrent_store
+            .torrents
+            .write()
+            .await
+            .insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(stores.clone())
+                .route("/{passkey}/announce", web::get().to(parse_private_announce)),
+        )
+        .await;
+
+        // The same user completing through two clients
+        for peer_id in &["-TR2940-000000000001", "-DE9824-143964258012"] {
+            let uri = format!(
+                "/a1b2c3d4e5f6/announce?info_hash=A1B2C3D4E5F6G7H8I9J0\
+                 &peer_id={}&port=6881&uploaded=0&downloaded=0&left=0\
+                 &event=completed&ip=10.0.0.1",
+                peer_id
+            );
+            let req = test::TestRequest::with_uri(&uri).to_request();
+            test::read_response(&mut app, req).await;
+        }
+
+        let torrents = stores.torrent_store.torrents.read().await;
+        assert_eq!(torrents.get(&info_hash).unwrap().downloaded, 1);
+
+        let snatches = stores.snatch_store.take_pending().await;
+        assert_eq!(snatches.len(), 1);
+        assert_eq!(snatches[0].snatcher, Snatcher::User(1));
+    }
+
+    #[derive(Clone, Debug)]
+    enum SwarmOp {
+        Announce {
+            torrent: usize,
+            peer: u8,
+            event: u8,
+            seeding: bool,
+        },
+        Expire {
+            torrent: usize,
+            peer: u8,
+        },
+        Tick,
+        Reap,
+    }
+
+    fn swarm_op() -> impl Strategy<Value = SwarmOp> {
+        prop_oneof![
+            8 => (0..2usize, 0..4u8, 0..4u8, any::<bool>()).prop_map(
+                |(torrent, peer, event, seeding)| SwarmOp::Announce {
+                    torrent,
+                    peer,
+                    event,
+                    seeding,
+                }
+            ),
+            1 => (0..2usize, 0..4u8).prop_map(|(torrent, peer)| SwarmOp::Expire { torrent, peer }),
+            1 => Just(SwarmOp::Tick),
+            1 => Just(SwarmOp::Reap),
+        ]
+    }
+
+    const COUNTED_TORRENTS: [&[u8; 20]; 2] = [b"A1B2C3D4E5F6G7H8I9J0", b"B2C3D4E5F6G7H8I9J0K1"];
+
+    fn counted_peer_id(peer: u8) -> PeerId {
+        let mut peer_id = *b"-TR2940-000000000000";
+        peer_id[19] = b'0' + peer;
+        PeerId(peer_id)
+    }
+
+    // Files the peer again as if it last announced a couple of buckets
+    // beyond the timeout, so the next expiry pass is sure to drop it
+    async fn expire_peer(data: &State, info_hash: InfoHash, peer_id: PeerId) {
+        let when = Instant::now()
+            - Duration::from_secs(data.config.current().bt.peer_timeout)
+            - 2 * BUCKET_WIDTH;
+        let swarm = match data.peer_store.get_swarm(info_hash).await {
+            Some(swarm) => swarm,
+            None => return,
+        };
+        let backdate = |peer: &Peer| {
+            let mut peer = peer.clone();
+            match &mut peer {
+                Peer::V4(p) => p.last_announced = when,
+                Peer::V6(p) => p.last_announced = when,
+            }
+            peer
+        };
+
+        for peer in swarm.seeders.iter().filter(|p| p.peer_id() == peer_id) {
+            data.peer_store.put_seeder(info_hash, backdate(peer)).await;
+        }
+        for peer in swarm.leechers.iter().filter(|p| p.peer_id() == peer_id) {
+            data.peer_store.put_leecher(info_hash, backdate(peer)).await;
+        }
+    }
+
+    async fn assert_counts_match_swarms(data: &State) {
+        let (mut seeders, mut leechers) = (0, 0);
+        for info_hash in COUNTED_TORRENTS.iter().map(|ih| InfoHash(**ih)) {
+            let swarm = data.peer_store.get_swarm(info_hash).await;
+            let sizes = swarm.map_or((0, 0), |sw| (sw.seeders.len(), sw.leechers.len()));
+            let (complete, incomplete) = data.torrent_store.get_announce_stats(info_hash).await;
+
+            assert_eq!((complete as usize, incomplete as usize), sizes);
+            seeders += sizes.0 as u64;
+            leechers += sizes.1 as u64;
+        }
+        assert_eq!(data.stats.total_seeders(), seeders);
+        assert_eq!(data.stats.total_leechers(), leechers);
+    }
+
+    proptest! {
+        // Any mix of announces, expired peers and reaps leaves the torrent
+        // and global counts equal to the number of peers in the swarms
+        #[test]
+        fn announce_counts_match_swarms(ops in proptest::collection::vec(swarm_op(), 1..60)) {
+            let mut config = Config::default();
+            config.bt.peer_timeout = 60;
+            let mut torrents = TorrentRecords::new();
+            for info_hash in COUNTED_TORRENTS.iter().map(|ih| InfoHash(**ih)) {
+                torrents.insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+            }
+            let data = State::new(config, TorrentStore::new(torrents));
+
+            futures::executor::block_on(async {
+                for op in ops {
+                    match op {
+                        SwarmOp::Announce {
+                            torrent,
+                            peer,
+                            event,
+                            seeding,
+                        } => {
+                            let event = ["started", "stopped", "completed", ""][event as usize];
+                            let query = format!(
+                                "info_hash={}&peer_id={}&port=6881&uploaded=0&downloaded=0\
+                                 &left={}&event={}&compact=1",
+                                String::from_utf8_lossy(COUNTED_TORRENTS[torrent]),
+                                String::from_utf8_lossy(&counted_peer_id(peer).0),
+                                if seeding { 0 } else { 1000 },
+                                event
+                            );
+                            let req = AnnounceRequest::new(&query, Some("10.0.0.1:6881")).unwrap();
+                            handle_announce(&data, req).await;
+                        }
+                        SwarmOp::Expire { torrent, peer } => {
+                            let info_hash = InfoHash(*COUNTED_TORRENTS[torrent]);
+                            expire_peer(&data, info_hash, counted_peer_id(peer)).await;
+                        }
+                        SwarmOp::Tick => {
+                            janitor::expire_peers(&data).await;
+                        }
+                        SwarmOp::Reap => {
+                            janitor::reap_peers(&data).await;
+                        }
+                    }
+                    assert_counts_match_swarms(&data).await;
+                }
+            });
+        }
+    }
 }
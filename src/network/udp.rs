@@ -0,0 +1,695 @@
+// BEP 15: UDP Tracker Protocol
+// Packets are translated into the same request types that the HTTP
+// handlers use, so both listeners share swarms, torrents and statistics.
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::web;
+use bytes::BufMut;
+use tokio::net::UdpSocket;
+
+use crate::bittorrent::{
+    AnnounceRequest, AnnounceResponse, InfoHash, Peer, PeerId, Peerv4, Peerv6,
+};
+use crate::errors::ClientError;
+use crate::network::middleware::{client_approved, torrent_approved};
+use crate::network::{handle_announce, reject_announce};
+use crate::state::State;
+use crate::util::Event;
+
+// Magic constant that every connect request must carry
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// Connection IDs are bucketed by this many seconds. An ID is accepted
+// for the bucket in which it was issued and the one after it, which
+// keeps it alive for between one and two minutes as the spec suggests.
+const CONNECTION_ID_WINDOW: u64 = 60;
+
+// Largest request we are willing to read; scrapes top out well below this
+const MAX_PACKET_SIZE: usize = 1496;
+
+const HEADER_LEN: usize = 16;
+const ANNOUNCE_LEN: usize = 98;
+const INFO_HASH_LEN: usize = 20;
+const MAX_SCRAPE_HASHES: usize = 74;
+
+// Connection IDs are derived from a per-process secret, the client's
+// address and the current time window, so nothing has to be stored
+// in order to validate them later on.
+pub struct ConnectionIds {
+    secret: RandomState,
+}
+
+impl ConnectionIds {
+    pub fn new() -> ConnectionIds {
+        ConnectionIds {
+            secret: RandomState::new(),
+        }
+    }
+
+    fn derive(&self, ip: IpAddr, window: u64) -> u64 {
+        let mut hasher = self.secret.build_hasher();
+        ip.hash(&mut hasher);
+        window.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn issue(&self, ip: IpAddr, now: u64) -> u64 {
+        self.derive(ip, now / CONNECTION_ID_WINDOW)
+    }
+
+    pub fn validate(&self, connection_id: u64, ip: IpAddr, now: u64) -> bool {
+        let window = now / CONNECTION_ID_WINDOW;
+        connection_id == self.derive(ip, window)
+            || (window > 0 && connection_id == self.derive(ip, window - 1))
+    }
+}
+
+impl Default for ConnectionIds {
+    fn default() -> Self {
+        ConnectionIds::new()
+    }
+}
+
+// Runs forever on the given socket, answering one packet at a time
+pub async fn serve(mut socket: UdpSocket, data: web::Data<State>) {
+    let connection_ids = ConnectionIds::new();
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Could not receive UDP packet: {}", e);
+                continue;
+            }
+        };
+
+        if let Some(response) = handle_packet(&data, &connection_ids, &buf[..len], src).await {
+            if let Err(e) = socket.send_to(&response, &src).await {
+                warn!("Could not send UDP response to {}: {}", src, e);
+            }
+        }
+    }
+}
+
+// Returns the bytes to be sent back to the client, if any.
+// Packets too short to carry a transaction ID are silently dropped.
+pub async fn handle_packet(
+    data: &State,
+    connection_ids: &ConnectionIds,
+    packet: &[u8],
+    src: SocketAddr,
+) -> Option<Vec<u8>> {
+    if packet.len() < HEADER_LEN {
+        return None;
+    }
+
+    let connection_id = read_u64(packet, 0);
+    let action = read_u32(packet, 8);
+    let transaction_id = read_u32(packet, 12);
+    let now = unix_time();
+
+    let _timer = data.metrics.request_timer(
+        "udp",
+        match action {
+            ACTION_CONNECT => "connect",
+            ACTION_ANNOUNCE => "announce",
+            ACTION_SCRAPE => "scrape",
+            _ => "unknown",
+        },
+    );
+
+    match action {
+        ACTION_CONNECT => {
+            if connection_id != PROTOCOL_ID {
+                return Some(error_response(
+                    data,
+                    transaction_id,
+                    ClientError::InvalidConnectionId,
+                ));
+            }
+
+            let mut response = Vec::with_capacity(16);
+            response.put_u32(ACTION_CONNECT);
+            response.put_u32(transaction_id);
+            response.put_u64(connection_ids.issue(src.ip(), now));
+            Some(response)
+        }
+
+        ACTION_ANNOUNCE => {
+            if !connection_ids.validate(connection_id, src.ip(), now) {
+                return Some(error_response(
+                    data,
+                    transaction_id,
+                    ClientError::InvalidConnectionId,
+                ));
+            }
+
+            match parse_announce(packet, src) {
+                // Checked before the torrent, in the same order as HTTP
+                Ok(parsed_req)
+                    if !client_approved(&data.config.current(), &parsed_req.peer.peer_id()) =>
+                {
+                    let failure = reject_announce(
+                        data,
+                        Some(&parsed_req.event),
+                        ClientError::UnapprovedClient,
+                    )
+                    .await;
+                    Some(announce_response(transaction_id, &failure, src))
+                }
+                Ok(parsed_req) if !torrent_approved(data, &parsed_req.info_hash).await => {
+                    let failure = reject_announce(
+                        data,
+                        Some(&parsed_req.event),
+                        ClientError::UnapprovedTorrent,
+                    )
+                    .await;
+                    Some(announce_response(transaction_id, &failure, src))
+                }
+                Ok(parsed_req) => {
+                    let response = handle_announce(data, parsed_req).await;
+                    Some(announce_response(transaction_id, &response, src))
+                }
+                Err(e) => {
+                    let failure = reject_announce(data, None, e).await;
+                    Some(announce_response(transaction_id, &failure, src))
+                }
+            }
+        }
+
+        ACTION_SCRAPE => {
+            if !connection_ids.validate(connection_id, src.ip(), now) {
+                return Some(error_response(
+                    data,
+                    transaction_id,
+                    ClientError::InvalidConnectionId,
+                ));
+            }
+
+            // UDP has nowhere to carry a passkey, so scrapes on
+            // a private tracker are turned away like HTTP ones
+            if data.config.current().bt.private {
+                data.metrics.scrape(false);
+                return Some(error_response(
+                    data,
+                    transaction_id,
+                    ClientError::InvalidPasskey,
+                ));
+            }
+
+            match parse_scrape(packet) {
+                // As with HTTP, one unapproved torrent fails the whole scrape
+                Ok(info_hashes) if !all_approved(data, &info_hashes).await => {
+                    data.metrics.scrape(false);
+                    Some(error_response(
+                        data,
+                        transaction_id,
+                        ClientError::UnapprovedTorrent,
+                    ))
+                }
+                Ok(info_hashes) => {
+                    let response = scrape_response(data, transaction_id, info_hashes).await;
+                    data.stats.incr_scrapes();
+                    data.metrics.scrape(true);
+                    Some(response)
+                }
+                Err(e) => {
+                    data.metrics.scrape(false);
+                    Some(error_response(data, transaction_id, e))
+                }
+            }
+        }
+
+        _ => Some(error_response(
+            data,
+            transaction_id,
+            ClientError::InvalidAction,
+        )),
+    }
+}
+
+fn parse_announce(packet: &[u8], src: SocketAddr) -> Result<AnnounceRequest, ClientError> {
+    if packet.len() < ANNOUNCE_LEN {
+        return Err(ClientError::MalformedAnnounce);
+    }
+
+    let info_hash = InfoHash(packet[16..36].try_into().unwrap());
+    let peer_id = PeerId(packet[36..56].try_into().unwrap());
+
+    let downloaded = read_u64(packet, 56);
+    let left = read_u64(packet, 64);
+    let uploaded = read_u64(packet, 72);
+
+    let event = match read_u32(packet, 80) {
+        0 => Event::None,
+        1 => Event::Completed,
+        2 => Event::Started,
+        3 => Event::Stopped,
+        _ => return Err(ClientError::MalformedAnnounce),
+    };
+
+    // An IPv4 client may ask to be listed under a different address;
+    // IPv6 clients are always listed under their source address.
+    let ip = match (src.ip(), read_u32(packet, 84)) {
+        (IpAddr::V4(_), requested) if requested != 0 => IpAddr::V4(Ipv4Addr::from(requested)),
+        (addr, _) => addr,
+    };
+
+    let key = read_u32(packet, 88);
+    let numwant = match read_u32(packet, 92) as i32 {
+        n if n < 0 => None,
+        n => Some(n as u32),
+    };
+    let port = u16::from_be_bytes([packet[96], packet[97]]);
+
+    let peer = match ip {
+        IpAddr::V4(i) => Peer::V4(Peerv4 {
+            peer_id,
+            ip: i,
+            port,
+            last_announced: Instant::now(),
+            user_id: None,
+            uploaded,
+            downloaded,
+        }),
+        IpAddr::V6(i) => Peer::V6(Peerv6 {
+            peer_id,
+            ip: i,
+            port,
+            last_announced: Instant::now(),
+            user_id: None,
+            uploaded,
+            downloaded,
+        }),
+    };
+
+    Ok(AnnounceRequest {
+        info_hash,
+        peer,
+        port,
+        uploaded,
+        downloaded,
+        left,
+        compact: true,
+        no_peer_id: true,
+        event,
+        ip: Some(ip),
+        numwant,
+        key: Some(key.to_string()),
+        trackerid: None,
+    })
+}
+
+fn parse_scrape(packet: &[u8]) -> Result<Vec<InfoHash>, ClientError> {
+    let hashes = &packet[HEADER_LEN..];
+
+    if hashes.is_empty()
+        || !hashes.len().is_multiple_of(INFO_HASH_LEN)
+        || hashes.len() / INFO_HASH_LEN > MAX_SCRAPE_HASHES
+    {
+        return Err(ClientError::MalformedScrape);
+    }
+
+    Ok(hashes
+        .chunks(INFO_HASH_LEN)
+        .map(|h| InfoHash(h.try_into().unwrap()))
+        .collect())
+}
+
+// Peers are only sent in the address family of the socket that the
+// request arrived on, as the packet format has no room for both.
+fn announce_response(transaction_id: u32, response: &AnnounceResponse, src: SocketAddr) -> Vec<u8> {
+    if let Some(reason) = &response.failure_reason {
+        return error_message(transaction_id, reason);
+    }
+
+    let peers = match src {
+        SocketAddr::V4(_) => response.peersv4_as_compact(),
+        SocketAddr::V6(_) => response.peersv6_as_compact(),
+    };
+
+    let mut packet = Vec::with_capacity(20 + peers.len());
+    packet.put_u32(ACTION_ANNOUNCE);
+    packet.put_u32(transaction_id);
+    packet.put_u32(response.interval);
+    packet.put_u32(response.incomplete);
+    packet.put_u32(response.complete);
+    packet.put_slice(&peers);
+
+    packet
+}
+
+// Unlike the HTTP scrape, every requested info hash must be answered
+// in order, so unknown torrents are reported with zeroed counts.
+async fn all_approved(data: &State, info_hashes: &[InfoHash]) -> bool {
+    for info_hash in info_hashes {
+        if !torrent_approved(data, info_hash).await {
+            return false;
+        }
+    }
+    true
+}
+
+async fn scrape_response(data: &State, transaction_id: u32, info_hashes: Vec<InfoHash>) -> Vec<u8> {
+    let scrapes = data.torrent_store.get_scrapes(info_hashes.clone()).await;
+
+    let mut packet = Vec::with_capacity(8 + info_hashes.len() * 12);
+    packet.put_u32(ACTION_SCRAPE);
+    packet.put_u32(transaction_id);
+
+    for info_hash in info_hashes {
+        match scrapes.iter().find(|s| s.info_hash == info_hash) {
+            Some(s) => {
+                packet.put_u32(s.complete);
+                packet.put_u32(s.downloaded);
+                packet.put_u32(s.incomplete);
+            }
+            None => packet.put_slice(&[0; 12]),
+        }
+    }
+
+    packet
+}
+
+// Failed announces are counted by reject_announce and
+// sent through announce_response instead of here
+fn error_response(data: &State, transaction_id: u32, error: ClientError) -> Vec<u8> {
+    data.metrics.failure(&error);
+    error_message(transaction_id, &error.text())
+}
+
+fn error_message(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(8 + message.len());
+    packet.put_u32(ACTION_ERROR);
+    packet.put_u32(transaction_id);
+    packet.put_slice(message.as_bytes());
+
+    packet
+}
+
+fn read_u32(packet: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(packet[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(packet: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(packet[offset..offset + 8].try_into().unwrap())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bittorrent::AnnounceRequest;
+    use crate::config::Config;
+    use crate::network::handle_announce;
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+
+    fn client_addr() -> SocketAddr {
+        "127.0.0.1:6881".parse().unwrap()
+    }
+
+    async fn connect(data: &State, connection_ids: &ConnectionIds) -> u64 {
+        let mut packet = vec![];
+        packet.put_u64(PROTOCOL_ID);
+        packet.put_u32(ACTION_CONNECT);
+        packet.put_u32(1234);
+
+        let resp = handle_packet(data, connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+
+        assert_eq!(resp.len(), 16);
+        assert_eq!(read_u32(&resp, 0), ACTION_CONNECT);
+        assert_eq!(read_u32(&resp, 4), 1234);
+        read_u64(&resp, 8)
+    }
+
+    fn announce_packet(connection_id: u64, info_hash: &[u8], event: u32, port: u16) -> Vec<u8> {
+        let mut packet = vec![];
+        packet.put_u64(connection_id);
+        packet.put_u32(ACTION_ANNOUNCE);
+        packet.put_u32(5678);
+        packet.put_slice(info_hash);
+        packet.put_slice(b"-UT2210-143964258012");
+        packet.put_u64(0); // downloaded
+        packet.put_u64(1000); // left
+        packet.put_u64(0); // uploaded
+        packet.put_u32(event);
+        packet.put_u32(0); // ip
+        packet.put_u32(42); // key
+        packet.put_i32(-1); // numwant
+        packet.put_u16(port);
+        packet
+    }
+
+    fn scrape_packet(connection_id: u64, info_hashes: &[&[u8]]) -> Vec<u8> {
+        let mut packet = vec![];
+        packet.put_u64(connection_id);
+        packet.put_u32(ACTION_SCRAPE);
+        packet.put_u32(91);
+        for info_hash in info_hashes {
+            packet.put_slice(info_hash);
+        }
+        packet
+    }
+
+    #[test]
+    fn connection_id_expires() {
+        let connection_ids = ConnectionIds::new();
+        let ip = client_addr().ip();
+        let id = connection_ids.issue(ip, 600);
+
+        assert!(connection_ids.validate(id, ip, 600));
+        assert!(connection_ids.validate(id, ip, 600 + CONNECTION_ID_WINDOW));
+        assert!(!connection_ids.validate(id, ip, 600 + 2 * CONNECTION_ID_WINDOW));
+        assert!(!connection_ids.validate(id, "127.0.0.2".parse().unwrap(), 600));
+    }
+
+    #[actix_rt::test]
+    async fn udp_connect_bad_protocol_id() {
+        let data = State::new(Config::default(), TorrentStore::new(TorrentRecords::new()));
+        let connection_ids = ConnectionIds::new();
+
+        let mut packet = vec![];
+        packet.put_u64(0xdead_beef);
+        packet.put_u32(ACTION_CONNECT);
+        packet.put_u32(1);
+
+        let resp = handle_packet(&data, &connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+
+        assert_eq!(read_u32(&resp, 0), ACTION_ERROR);
+        assert_eq!(
+            &resp[8..],
+            ClientError::InvalidConnectionId.text().as_bytes()
+        );
+    }
+
+    #[test]
+    fn udp_announce_multi_terabyte_counters() {
+        let mut packet = announce_packet(1, b"A1B2C3D4E5F6G7H8I9J0", 0, 6881);
+        packet[56..64].copy_from_slice(&(5u64 << 40).to_be_bytes());
+        packet[64..72].copy_from_slice(&(7u64 << 40).to_be_bytes());
+        packet[72..80].copy_from_slice(&(6u64 << 40).to_be_bytes());
+
+        let announce = parse_announce(&packet, client_addr()).unwrap();
+        assert_eq!(
+            (announce.uploaded, announce.downloaded, announce.left),
+            (6 << 40, 5 << 40, 7 << 40)
+        );
+        assert_eq!(announce.peer.transferred(), (6 << 40, 5 << 40));
+    }
+
+    #[actix_rt::test]
+    async fn udp_announce_invalid_connection_id() {
+        let data = State::new(Config::default(), TorrentStore::new(TorrentRecords::new()));
+        let connection_ids = ConnectionIds::new();
+
+        let packet = announce_packet(99, b"A1B2C3D4E5F6G7H8I9J0", 2, 6881);
+        let resp = handle_packet(&data, &connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+
+        assert_eq!(read_u32(&resp, 0), ACTION_ERROR);
+        assert_eq!(read_u32(&resp, 4), 5678);
+    }
+
+    #[actix_rt::test]
+    async fn udp_announce_shares_swarm_with_http() {
+        let data = State::new(Config::default(), TorrentStore::new(TorrentRecords::new()));
+        let connection_ids = ConnectionIds::new();
+
+        let http_req = AnnounceRequest::new(
+            "info_hash=A1B2C3D4E5F6G7H8I9J0&peer_id=-DE9824-143964258012&port=7000\
+             &uploaded=0&downloaded=0&left=1000&event=started&numwant=30&compact=1",
+            Some("10.0.0.1:7000"),
+        )
+        .unwrap();
+        handle_announce(&data, http_req).await;
+
+        let connection_id = connect(&data, &connection_ids).await;
+        let packet = announce_packet(connection_id, b"A1B2C3D4E5F6G7H8I9J0", 2, 6881);
+        let resp = handle_packet(&data, &connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+
+        assert_eq!(read_u32(&resp, 0), ACTION_ANNOUNCE);
+        assert_eq!(read_u32(&resp, 4), 5678);
+        assert_eq!(
+            read_u32(&resp, 8),
+            data.config.current().bt.announce_rate as u32
+        );
+
+        // Both the HTTP peer and the UDP peer itself are handed out
+        let peers: Vec<&[u8]> = resp[20..].chunks(6).collect();
+        assert_eq!(peers.len(), 2);
+        assert!(peers.contains(&&[10, 0, 0, 1, 0x1b, 0x58][..]));
+        assert!(peers.contains(&&[127, 0, 0, 1, 0x1a, 0xe1][..]));
+        assert_eq!(data.stats.succ_announces(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn udp_scrape_known_and_unknown() {
+        let mut torrents = TorrentRecords::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        torrents.insert(info_hash, Torrent::new(info_hash, 10, 34, 7, 10000000));
+        let data = State::new(Config::default(), TorrentStore::new(torrents));
+        let connection_ids = ConnectionIds::new();
+        let connection_id = connect(&data, &connection_ids).await;
+
+        let mut packet = vec![];
+        packet.put_u64(connection_id);
+        packet.put_u32(ACTION_SCRAPE);
+        packet.put_u32(91);
+        packet.put_slice(b"A1B2C3D4E5F6G7H8I9J0");
+        packet.put_slice(b"B2C3D4E5F6G7H8I9J0K1");
+
+        let resp = handle_packet(&data, &connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+
+        assert_eq!(read_u32(&resp, 0), ACTION_SCRAPE);
+        assert_eq!(read_u32(&resp, 4), 91);
+        assert_eq!(
+            &resp[8..],
+            &[0, 0, 0, 10, 0, 0, 0, 34, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0][..]
+        );
+        assert_eq!(data.stats.scrapes(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn udp_scrape_private_tracker() {
+        let mut torrents = TorrentRecords::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        torrents.insert(info_hash, Torrent::new(info_hash, 10, 34, 7, 10000000));
+        let mut config = Config::default();
+        config.bt.private = true;
+        let data = State::new(config, TorrentStore::new(torrents));
+        let connection_ids = ConnectionIds::new();
+        let connection_id = connect(&data, &connection_ids).await;
+
+        let packet = scrape_packet(connection_id, &[b"A1B2C3D4E5F6G7H8I9J0"]);
+        let resp = handle_packet(&data, &connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+
+        assert_eq!(read_u32(&resp, 0), ACTION_ERROR);
+        assert_eq!(read_u32(&resp, 4), 91);
+        assert_eq!(&resp[8..], ClientError::InvalidPasskey.text().as_bytes());
+        assert_eq!(data.stats.scrapes(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn udp_scrape_unapproved_torrent() {
+        let mut config = Config::default();
+        config.torrent_approval.enabled = true;
+        config.torrent_approval.info_hashes = vec![InfoHash(*b"A1B2C3D4E5F6G7H8I9J0")]
+            .into_iter()
+            .collect();
+        let data = State::new(config, TorrentStore::new(TorrentRecords::new()));
+        let connection_ids = ConnectionIds::new();
+        let connection_id = connect(&data, &connection_ids).await;
+
+        let packet = scrape_packet(connection_id, &[b"A1B2C3D4E5F6G7H8I9J0"]);
+        let resp = handle_packet(&data, &connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+        assert_eq!(read_u32(&resp, 0), ACTION_SCRAPE);
+
+        let packet = scrape_packet(
+            connection_id,
+            &[b"A1B2C3D4E5F6G7H8I9J0", b"B2C3D4E5F6G7H8I9J0K1"],
+        );
+        let resp = handle_packet(&data, &connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+        assert_eq!(read_u32(&resp, 0), ACTION_ERROR);
+        assert_eq!(&resp[8..], ClientError::UnapprovedTorrent.text().as_bytes());
+        assert_eq!(data.stats.scrapes(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn udp_announce_unapproved_client() {
+        let mut config = Config::default();
+        config.client_approval.enabled = true;
+        config.client_approval.client_list = vec!["DE".to_string(), "qB".to_string()];
+        let data = State::new(config, TorrentStore::new(TorrentRecords::new()));
+        let connection_ids = ConnectionIds::new();
+        let connection_id = connect(&data, &connection_ids).await;
+
+        // The packet's peer ID belongs to a client missing from the whitelist
+        let packet = announce_packet(connection_id, b"A1B2C3D4E5F6G7H8I9J0", 2, 6881);
+        let resp = handle_packet(&data, &connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+
+        assert_eq!(read_u32(&resp, 0), ACTION_ERROR);
+        assert_eq!(read_u32(&resp, 4), 5678);
+        assert_eq!(&resp[8..], ClientError::UnapprovedClient.text().as_bytes());
+        assert_eq!(data.stats.num_fails(), 1);
+        assert!(data
+            .peer_store
+            .get_swarm(InfoHash(*b"A1B2C3D4E5F6G7H8I9J0"))
+            .await
+            .is_none());
+        let rendered = data.metrics.render(&data.stats, &data.peer_store).await;
+        assert!(rendered.contains("tyto_failures_total{error=\"UnapprovedClient\"} 1"));
+    }
+
+    #[actix_rt::test]
+    async fn udp_unknown_action() {
+        let data = State::new(Config::default(), TorrentStore::new(TorrentRecords::new()));
+        let connection_ids = ConnectionIds::new();
+
+        let mut packet = vec![];
+        packet.put_u64(PROTOCOL_ID);
+        packet.put_u32(17);
+        packet.put_u32(3);
+
+        let resp = handle_packet(&data, &connection_ids, &packet, client_addr())
+            .await
+            .unwrap();
+
+        assert_eq!(read_u32(&resp, 0), ACTION_ERROR);
+        assert_eq!(&resp[8..], ClientError::InvalidAction.text().as_bytes());
+    }
+}
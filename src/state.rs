@@ -1,25 +1,47 @@
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
-use tokio::sync::RwLock;
 
-use crate::config::Config;
-use crate::statistics::GlobalStatistics;
-use crate::storage::{PeerStore, TorrentStore};
+use crate::config::{Config, SharedConfig};
+use crate::metrics::Metrics;
+use crate::statistics::{GlobalStatistics, SwarmRanking};
+use crate::storage::memory::MemoryBackend;
+use crate::storage::{PeerStore, SnatchStore, TorrentBackend, TorrentStore, UserStore};
 
 #[derive(Clone)]
 pub struct State {
-    pub config: Config,
+    pub config: SharedConfig,
     pub peer_store: PeerStore,
-    pub stats: Arc<RwLock<GlobalStatistics>>,
+    pub stats: Arc<GlobalStatistics>,
+    pub metrics: Arc<Metrics>,
+    pub swarm_ranking: Arc<SwarmRanking>,
     pub torrent_store: TorrentStore,
+    pub user_store: UserStore,
+    pub snatch_store: SnatchStore,
+    pub backend: Arc<dyn TorrentBackend>,
+    pub shutting_down: Arc<AtomicBool>,
 }
 
 impl State {
     pub fn new(config: Config, torrent_store: TorrentStore) -> State {
+        State::with_backend(config, torrent_store, Arc::new(MemoryBackend::new()))
+    }
+
+    pub fn with_backend(
+        config: Config,
+        torrent_store: TorrentStore,
+        backend: Arc<dyn TorrentBackend>,
+    ) -> State {
         State {
-            config,
+            config: SharedConfig::new(config),
             peer_store: PeerStore::new(),
-            stats: Arc::new(RwLock::new(GlobalStatistics::new())),
+            stats: Arc::new(GlobalStatistics::new()),
+            metrics: Arc::new(Metrics::new()),
+            swarm_ranking: Arc::new(SwarmRanking::default()),
             torrent_store,
+            user_store: UserStore::default(),
+            snatch_store: SnatchStore::default(),
+            backend,
+            shutting_down: Arc::new(AtomicBool::new(false)),
         }
     }
 }
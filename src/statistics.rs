@@ -1,26 +1,47 @@
-use std::time::Instant;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use serde::Serialize;
+use tokio::sync::Mutex;
 
-#[derive(Clone)]
+use crate::bittorrent::{InfoHash, Peer, PeerId};
+use crate::storage::{PeerStore, Swarm, Torrent};
+
+// How long a ranking of the busiest swarms is handed out before it is rebuilt
+const RANKING_TTL: Duration = Duration::from_secs(10);
+
+// Peers by kind and address family, indexed as
+// [seeders, leechers][ipv4, ipv6]
+pub type PeerCounts = [[i64; 2]; 2];
+
+// Every counter is its own atomic so that announces on different workers
+// never wait on each other; the figures are only read together for /stats.
+// Peer totals are the sum of every shard's share, so they are kept signed
+// and only clamped when read; a shard putting its share right then always
+// lands the totals on the true figure.
 pub struct GlobalStatistics {
     pub start_time: Instant,
-    pub total_seeders: u32,
-    pub total_leechers: u32,
-    pub announce_requests: u32,
-    pub succ_announces: u32,
-    pub scrapes: u32,
+    peers: [[AtomicI64; 2]; 2],
+    announce_requests: AtomicU64,
+    succ_announces: AtomicU64,
+    scrapes: AtomicU64,
+}
+
+// The counters don't guard any other memory, so relaxed ordering is enough
+fn incr(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
 }
 
 impl GlobalStatistics {
     pub fn new() -> GlobalStatistics {
         GlobalStatistics {
             start_time: Instant::now(),
-            total_seeders: 0,
-            total_leechers: 0,
-            announce_requests: 0,
-            succ_announces: 0,
-            scrapes: 0,
+            peers: Default::default(),
+            announce_requests: AtomicU64::new(0),
+            succ_announces: AtomicU64::new(0),
+            scrapes: AtomicU64::new(0),
         }
     }
 
@@ -28,47 +49,68 @@ impl GlobalStatistics {
         self.start_time.elapsed().as_secs()
     }
 
-    pub fn succ_announce(&mut self) {
-        self.announce_requests += 1;
-        self.succ_announces += 1;
+    pub fn total_seeders(&self) -> u64 {
+        self.peer_counts()[0].iter().sum::<i64>() as u64
+    }
+
+    pub fn total_leechers(&self) -> u64 {
+        self.peer_counts()[1].iter().sum::<i64>() as u64
+    }
+
+    pub fn peer_counts(&self) -> PeerCounts {
+        let mut counts = [[0; 2]; 2];
+        for (kind, families) in self.peers.iter().enumerate() {
+            for (family, count) in families.iter().enumerate() {
+                counts[kind][family] = count.load(Ordering::Relaxed).max(0);
+            }
+        }
+        counts
     }
 
-    pub fn fail_announce(&mut self) {
-        self.announce_requests += 1;
+    pub fn announce_requests(&self) -> u64 {
+        self.announce_requests.load(Ordering::Relaxed)
     }
 
-    pub fn num_fails(&self) -> u32 {
-        self.announce_requests - self.succ_announces
+    pub fn succ_announces(&self) -> u64 {
+        self.succ_announces.load(Ordering::Relaxed)
     }
 
-    pub fn incr_scrapes(&mut self) {
-        self.scrapes += 1;
+    pub fn scrapes(&self) -> u64 {
+        self.scrapes.load(Ordering::Relaxed)
     }
 
-    pub fn add_seed(&mut self) {
-        self.total_seeders += 1;
+    pub fn succ_announce(&self) {
+        incr(&self.announce_requests, 1);
+        incr(&self.succ_announces, 1);
     }
 
-    pub fn add_leech(&mut self) {
-        self.total_leechers += 1;
+    pub fn fail_announce(&self) {
+        incr(&self.announce_requests, 1);
     }
 
-    pub fn sub_seed(&mut self) {
-        self.total_leechers = self.total_seeders.saturating_sub(1);
+    pub fn num_fails(&self) -> u64 {
+        self.announce_requests()
+            .saturating_sub(self.succ_announces())
     }
 
-    pub fn sub_leech(&mut self) {
-        self.total_leechers = self.total_leechers.saturating_sub(1);
+    pub fn incr_scrapes(&self) {
+        incr(&self.scrapes, 1);
     }
 
-    pub fn promote_leech(&mut self) {
-        self.total_leechers = self.total_leechers.saturating_sub(1);
-        self.total_seeders += 1;
+    // Seeder and leecher totals only move with peers moving in their
+    // swarms, which the peer store passes on shard by shard
+    pub fn adjust_peers(&self, delta: &PeerCounts) {
+        for (kind, families) in self.peers.iter().enumerate() {
+            for (family, count) in families.iter().enumerate() {
+                count.fetch_add(delta[kind][family], Ordering::Relaxed);
+            }
+        }
     }
+}
 
-    pub fn cleared_peers(&mut self, seeders_cleared: u32, leechers_cleared: u32) {
-        self.total_seeders = self.total_seeders.saturating_sub(seeders_cleared);
-        self.total_leechers = self.total_leechers.saturating_sub(leechers_cleared);
+impl Default for GlobalStatistics {
+    fn default() -> Self {
+        GlobalStatistics::new()
     }
 }
 
@@ -78,22 +120,241 @@ impl GlobalStatistics {
 #[derive(Clone, Serialize)]
 pub struct ReturnedStatistics {
     pub uptime: u64,
-    pub total_seeders: u32,
-    pub total_leechers: u32,
-    pub announce_requests: u32,
-    pub succ_announces: u32,
-    pub scrapes: u32,
+    pub total_seeders: u64,
+    pub total_leechers: u64,
+    pub announce_requests: u64,
+    pub succ_announces: u64,
+    pub scrapes: u64,
 }
 
 impl ReturnedStatistics {
     pub fn new(stats: &GlobalStatistics) -> ReturnedStatistics {
         ReturnedStatistics {
             uptime: stats.uptime(),
-            total_seeders: stats.total_seeders,
-            total_leechers: stats.total_leechers,
-            announce_requests: stats.announce_requests,
-            succ_announces: stats.succ_announces,
-            scrapes: stats.scrapes,
+            total_seeders: stats.total_seeders(),
+            total_leechers: stats.total_leechers(),
+            announce_requests: stats.announce_requests(),
+            succ_announces: stats.succ_announces(),
+            scrapes: stats.scrapes(),
         }
     }
 }
+
+// Live figures for a single swarm. Peer counts come from the swarm
+// itself; snatches and traffic are only known if the torrent is registered.
+#[derive(Clone, Serialize)]
+pub struct SwarmStatistics {
+    pub info_hash: String,
+    pub seeders: usize,
+    pub leechers: usize,
+    pub ipv4: usize,
+    pub ipv6: usize,
+    pub downloaded: u32,
+    pub balance: u64,
+    pub clients: BTreeMap<String, usize>,
+    pub last_announce: Option<u64>, // Seconds ago
+}
+
+// Azureus-style peer IDs look like '-AZ1234-', so the two letters after the
+// dash name the client; anything else is counted as unknown
+fn client_prefix(peer_id: &PeerId) -> String {
+    let id = &peer_id.0;
+    if id[0] == b'-' && id[7] == b'-' && id[1..3].iter().all(u8::is_ascii_alphanumeric) {
+        String::from_utf8_lossy(&id[1..3]).into_owned()
+    } else {
+        "unknown".to_string()
+    }
+}
+
+impl SwarmStatistics {
+    pub fn new(info_hash: InfoHash, swarm: Option<&Swarm>) -> SwarmStatistics {
+        let mut stats = SwarmStatistics {
+            info_hash: info_hash.to_string(),
+            seeders: 0,
+            leechers: 0,
+            ipv4: 0,
+            ipv6: 0,
+            downloaded: 0,
+            balance: 0,
+            clients: BTreeMap::new(),
+            last_announce: None,
+        };
+
+        let swarm = match swarm {
+            Some(swarm) => swarm,
+            None => return stats,
+        };
+        stats.seeders = swarm.seeders.len();
+        stats.leechers = swarm.leechers.len();
+
+        for peer in swarm.seeders.iter().chain(swarm.leechers.iter()) {
+            let last_announced = match peer {
+                Peer::V4(p) => {
+                    stats.ipv4 += 1;
+                    p.last_announced
+                }
+                Peer::V6(p) => {
+                    stats.ipv6 += 1;
+                    p.last_announced
+                }
+            };
+
+            *stats
+                .clients
+                .entry(client_prefix(&peer.peer_id()))
+                .or_insert(0) += 1;
+
+            let secs = last_announced.elapsed().as_secs();
+            stats.last_announce = Some(stats.last_announce.map_or(secs, |s| s.min(secs)));
+        }
+
+        stats
+    }
+
+    pub fn add_torrent(&mut self, torrent: &Torrent) {
+        self.downloaded = torrent.downloaded;
+        self.balance = torrent.balance;
+    }
+}
+
+// A page of the busiest swarms, ordered by their number of peers
+#[derive(Clone, Serialize)]
+pub struct TopSwarms {
+    pub page: usize,
+    pub per_page: usize,
+    pub total: usize,
+    pub swarms: Vec<SwarmStatistics>,
+}
+
+// Ranking the swarms walks every shard, so the result is kept for a while
+// instead of being rebuilt for every request to the top swarms listing.
+// Requests arriving while it is rebuilt wait for that one walk to finish.
+#[derive(Default)]
+pub struct SwarmRanking {
+    cached: Mutex<Option<(Instant, Arc<Vec<InfoHash>>)>>,
+}
+
+impl SwarmRanking {
+    pub async fn get(&self, peer_store: &PeerStore) -> Arc<Vec<InfoHash>> {
+        let mut cached = self.cached.lock().await;
+        if let Some((built, ranking)) = cached.as_ref() {
+            if built.elapsed() < RANKING_TTL {
+                return ranking.clone();
+            }
+        }
+
+        let mut sizes: Vec<(usize, InfoHash)> = Vec::new();
+        for shard in peer_store.shards() {
+            sizes.extend(
+                shard
+                    .read()
+                    .await
+                    .records
+                    .iter()
+                    .map(|(info_hash, sw)| (sw.seeders.len() + sw.leechers.len(), *info_hash))
+                    .filter(|(size, _)| *size > 0),
+            );
+        }
+
+        // Largest first, with ties broken by info hash so that pages stay put
+        sizes.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        let ranking = Arc::new(sizes.into_iter().map(|(_, info_hash)| info_hash).collect());
+        *cached = Some((Instant::now(), Arc::clone(&ranking)));
+        ranking
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    use crate::bittorrent::Peerv4;
+
+    #[test]
+    fn global_statistics_count_across_threads() {
+        let stats = Arc::new(GlobalStatistics::new());
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let stats = stats.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        stats.succ_announce();
+                        stats.adjust_peers(&[[0, 0], [1, 0]]);
+                    }
+                    stats.fail_announce();
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert_eq!(stats.announce_requests(), 4004);
+        assert_eq!(stats.succ_announces(), 4000);
+        assert_eq!(stats.num_fails(), 4);
+        assert_eq!(stats.total_leechers(), 4000);
+    }
+
+    #[test]
+    fn global_statistics_never_read_below_zero() {
+        let stats = GlobalStatistics::new();
+        stats.adjust_peers(&[[1, 0], [0, 0]]);
+        stats.adjust_peers(&[[0, 0], [1, 1]]);
+
+        // Removing a seeder must not touch the leecher count
+        stats.adjust_peers(&[[-1, 0], [0, 0]]);
+        stats.adjust_peers(&[[-1, 0], [0, 0]]);
+        assert_eq!((stats.total_seeders(), stats.total_leechers()), (0, 2));
+
+        // The extra removal stays owed until a shard puts its share right
+        stats.adjust_peers(&[[1, 0], [0, -1]]);
+        assert_eq!((stats.total_seeders(), stats.total_leechers()), (0, 1));
+        stats.adjust_peers(&[[1, 0], [0, 0]]);
+        assert_eq!((stats.total_seeders(), stats.total_leechers()), (1, 1));
+        assert_eq!(stats.peer_counts(), [[1, 0], [1, 0]]);
+
+        // Nothing wraps once past the old 32 bit limit
+        incr(&stats.scrapes, u64::from(u32::MAX));
+        stats.incr_scrapes();
+        assert_eq!(stats.scrapes(), u64::from(u32::MAX) + 1);
+    }
+
+    #[actix_rt::test]
+    async fn swarm_ranking_is_reused_until_stale() {
+        let peer_store = PeerStore::new();
+        let ranking = SwarmRanking::default();
+        let leecher = |n: u8| {
+            let mut peer_id = *b"-TR2940-000000000000";
+            peer_id[19] = n;
+            Peer::V4(Peerv4 {
+                peer_id: PeerId(peer_id),
+                ip: Ipv4Addr::new(10, 0, 0, n),
+                port: 6881,
+                last_announced: Instant::now(),
+                user_id: None,
+                uploaded: 0,
+                downloaded: 0,
+            })
+        };
+        let small = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let large = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+
+        peer_store.put_leecher(small, leecher(1)).await;
+        peer_store.put_leecher(large, leecher(2)).await;
+        peer_store.put_leecher(large, leecher(3)).await;
+        assert_eq!(*ranking.get(&peer_store).await, vec![large, small]);
+
+        // Swarms that grow after the ranking was built wait for the next one
+        peer_store.put_leecher(small, leecher(4)).await;
+        peer_store.put_leecher(small, leecher(5)).await;
+        assert_eq!(*ranking.get(&peer_store).await, vec![large, small]);
+
+        ranking.cached.lock().await.as_mut().unwrap().0 -= RANKING_TTL;
+        assert_eq!(*ranking.get(&peer_store).await, vec![small, large]);
+    }
+}
@@ -0,0 +1,130 @@
+// Behaviour that every TorrentBackend has to share. Each backend's tests
+// run these against their own instance; anything that has to be put in
+// place behind the tracker's back is left to the backend, which is the
+// only one that knows how.
+
+use crate::bittorrent::{InfoHash, PeerId};
+use crate::storage::{Snatch, Snatcher, Torrent, TorrentBackend, User, UserRecords, UserTransfer};
+
+pub const INFO_HASH1: InfoHash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+pub const INFO_HASH2: InfoHash = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+
+// The users that backends are expected to hold before calling users()
+pub fn user_records() -> UserRecords {
+    vec![
+        User::new(1, "a1b2c3d4e5f6".to_string(), false),
+        User::new(2, "f6e5d4c3b2a1".to_string(), true),
+    ]
+    .into_iter()
+    .map(|user| (user.passkey.clone(), user))
+    .collect()
+}
+
+// A later flush replaces the earlier counts, and balances past 32 bits
+// come back whole. Reading goes through `read`, which may be a second
+// handle on the same database to show the torrents really were stored.
+pub fn flush_and_load(backend: &dyn TorrentBackend, read: &dyn TorrentBackend) {
+    assert!(backend.load_torrents().unwrap().is_empty());
+
+    backend
+        .flush_torrents(vec![Torrent::new(INFO_HASH1, 1, 2, 3, 4)])
+        .unwrap();
+    backend
+        .flush_torrents(vec![Torrent::new(INFO_HASH1, 5, 6, 7, 5 << 40)])
+        .unwrap();
+
+    let torrents = read.load_torrents().unwrap();
+    let torrent = torrents.get(&INFO_HASH1).unwrap();
+
+    assert_eq!(torrents.len(), 1);
+    assert_eq!(
+        (
+            torrent.complete,
+            torrent.downloaded,
+            torrent.incomplete,
+            torrent.balance
+        ),
+        (5, 6, 7, 5 << 40)
+    );
+
+    backend.delete_torrents(vec![INFO_HASH1]).unwrap();
+}
+
+// `add_torrent` stands in for another process adding a torrent
+pub fn fetch_new_and_delete(backend: &dyn TorrentBackend, add_torrent: &dyn Fn(InfoHash)) {
+    add_torrent(INFO_HASH1);
+    backend
+        .flush_torrents(vec![Torrent::new(INFO_HASH2, 1, 2, 3, 4)])
+        .unwrap();
+    assert_eq!(backend.fetch_new_torrents().unwrap().len(), 2);
+
+    backend.delete_torrents(vec![INFO_HASH1]).unwrap();
+
+    let torrents = backend.fetch_new_torrents().unwrap();
+    assert!(!torrents.contains_key(&INFO_HASH1));
+    assert!(torrents.contains_key(&INFO_HASH2));
+}
+
+// Transfers add onto the stored totals rather than replacing them
+pub fn users(backend: &dyn TorrentBackend) {
+    let users = backend.load_users().unwrap();
+    assert_eq!(users.len(), 2);
+    assert_eq!(users.get("a1b2c3d4e5f6").unwrap().id, 1);
+    assert!(users.get("f6e5d4c3b2a1").unwrap().revoked);
+
+    let transfer = UserTransfer {
+        user_id: 1,
+        uploaded: 1000,
+        downloaded: 10,
+    };
+    backend.flush_user_transfers(vec![transfer]).unwrap();
+    backend.flush_user_transfers(vec![transfer]).unwrap();
+
+    let user = backend
+        .load_users()
+        .unwrap()
+        .remove("a1b2c3d4e5f6")
+        .unwrap();
+    assert_eq!((user.uploaded, user.downloaded), (2000, 20));
+}
+
+// Only the first completion of each snatcher is kept, and snatches
+// go along with their torrent. As with torrents, reading may go
+// through a second handle on the same database.
+pub fn snatches(backend: &dyn TorrentBackend, read: &dyn TorrentBackend) {
+    let user = Snatch {
+        info_hash: INFO_HASH1,
+        snatcher: Snatcher::User(1),
+        completed_at: 100,
+    };
+    let peer = Snatch {
+        info_hash: INFO_HASH2,
+        snatcher: Snatcher::Peer(PeerId(*b"-TR2940-000000000001")),
+        completed_at: 100,
+    };
+    // User 0 is a real user, not a peer snatch with its user ID left out
+    let user_zero = Snatch {
+        info_hash: INFO_HASH2,
+        snatcher: Snatcher::User(0),
+        completed_at: 100,
+    };
+
+    backend.flush_snatches(vec![user, peer, user_zero]).unwrap();
+    backend
+        .flush_snatches(vec![Snatch {
+            completed_at: 200,
+            ..user
+        }])
+        .unwrap();
+
+    let snatches = read.load_snatches().unwrap();
+    assert_eq!(snatches.len(), 3);
+    for snatch in [user, peer, user_zero].iter() {
+        assert!(snatches.contains(snatch), "missing {:?}", snatch);
+    }
+
+    backend.delete_torrents(vec![INFO_HASH1]).unwrap();
+    let snatches = read.load_snatches().unwrap();
+    assert_eq!(snatches.len(), 2);
+    assert!(snatches.contains(&peer) && snatches.contains(&user_zero));
+}
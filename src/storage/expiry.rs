@@ -0,0 +1,187 @@
+// Peers indexed by when they last announced, in buckets a few seconds
+// wide. Expiring peers only visits the buckets that have fallen wholly
+// past the cutoff, so a pass costs as much as the peers it removes rather
+// than as much as the whole store. Peers go at most one bucket late.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashSet;
+
+use crate::bittorrent::{InfoHash, Peer, PeerId, Peerv4, Peerv6};
+
+pub const BUCKET_WIDTH: Duration = Duration::from_secs(5);
+
+// Just enough to find a peer in its swarm again, so that the
+// index doesn't hold a second copy of every peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExpiryKey {
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+    pub ip: IpAddr,
+    pub port: u16,
+}
+
+impl ExpiryKey {
+    pub fn new(info_hash: InfoHash, peer: &Peer) -> ExpiryKey {
+        let (peer_id, ip, port) = match peer {
+            Peer::V4(p) => (p.peer_id, IpAddr::V4(p.ip), p.port),
+            Peer::V6(p) => (p.peer_id, IpAddr::V6(p.ip), p.port),
+        };
+
+        ExpiryKey {
+            info_hash,
+            peer_id,
+            ip,
+            port,
+        }
+    }
+
+    // Peers are told apart by their ID and address alone, so this
+    // finds the peer in its swarm whatever else the swarm holds for it
+    pub fn peer(&self) -> Peer {
+        match self.ip {
+            IpAddr::V4(ip) => Peer::V4(Peerv4 {
+                peer_id: self.peer_id,
+                ip,
+                port: self.port,
+                last_announced: Instant::now(),
+                user_id: None,
+                uploaded: 0,
+                downloaded: 0,
+            }),
+            IpAddr::V6(ip) => Peer::V6(Peerv6 {
+                peer_id: self.peer_id,
+                ip,
+                port: self.port,
+                last_announced: Instant::now(),
+                user_id: None,
+                uploaded: 0,
+                downloaded: 0,
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ExpiryIndex {
+    epoch: Instant,
+    buckets: BTreeMap<i64, HashSet<ExpiryKey>>,
+}
+
+impl ExpiryIndex {
+    pub fn new(epoch: Instant) -> ExpiryIndex {
+        ExpiryIndex {
+            epoch,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    // Restored peers may have announced before the index was created,
+    // so buckets are counted either side of the epoch
+    fn bucket(&self, when: Instant) -> i64 {
+        let offset = if when >= self.epoch {
+            (when - self.epoch).as_nanos() as i128
+        } else {
+            -((self.epoch - when).as_nanos() as i128)
+        };
+        offset.div_euclid(BUCKET_WIDTH.as_nanos() as i128) as i64
+    }
+
+    // Peers are filed under their own announce time
+    pub fn insert(&mut self, info_hash: InfoHash, peer: &Peer) {
+        let bucket = self.bucket(peer.last_announced());
+        self.buckets
+            .entry(bucket)
+            .or_default()
+            .insert(ExpiryKey::new(info_hash, peer));
+    }
+
+    // Takes the peer out of the bucket it was filed under, which
+    // needs the announce time it was inserted with
+    pub fn remove(&mut self, info_hash: InfoHash, peer: &Peer) {
+        let bucket = self.bucket(peer.last_announced());
+        if let Some(entries) = self.buckets.get_mut(&bucket) {
+            entries.remove(&ExpiryKey::new(info_hash, peer));
+            if entries.is_empty() {
+                self.buckets.remove(&bucket);
+            }
+        }
+    }
+
+    // Removes and returns every peer in buckets that end before the cutoff
+    pub fn take_expired(&mut self, cutoff: Instant) -> Vec<ExpiryKey> {
+        let remaining = self.buckets.split_off(&self.bucket(cutoff));
+        mem::replace(&mut self.buckets, remaining)
+            .into_values()
+            .flatten()
+            .collect()
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.buckets.values().map(HashSet::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn peer(peer_id: &[u8; 20], last_announced: Instant) -> Peer {
+        Peer::V4(Peerv4 {
+            peer_id: PeerId(*peer_id),
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 6881,
+            last_announced,
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
+        })
+    }
+
+    #[test]
+    fn expiry_index_takes_whole_buckets() {
+        let epoch = Instant::now();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut index = ExpiryIndex::new(epoch);
+
+        // One before the epoch, as a restored peer would be
+        let old = peer(b"-TR2940-000000000001", epoch - Duration::from_secs(60));
+        let recent = peer(b"-TR2940-000000000002", epoch + Duration::from_secs(60));
+        index.insert(info_hash, &old);
+        index.insert(info_hash, &recent);
+        assert_eq!(index.len(), 2);
+
+        // A cutoff partway into the recent peer's bucket leaves it alone
+        let expired = index.take_expired(epoch + Duration::from_secs(61));
+        assert_eq!(expired, vec![ExpiryKey::new(info_hash, &old)]);
+        assert_eq!(expired[0].peer(), old);
+        assert_eq!(index.len(), 1);
+
+        assert_eq!(index.take_expired(epoch + Duration::from_secs(65)).len(), 1);
+        assert_eq!(index.len(), 0);
+    }
+
+    #[test]
+    fn expiry_index_refresh_moves_peer() {
+        let epoch = Instant::now();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut index = ExpiryIndex::new(epoch);
+
+        let first = peer(b"-TR2940-000000000001", epoch);
+        let refreshed = peer(b"-TR2940-000000000001", epoch + Duration::from_secs(30));
+        index.insert(info_hash, &first);
+        index.remove(info_hash, &first);
+        index.insert(info_hash, &refreshed);
+
+        assert!(index
+            .take_expired(epoch + Duration::from_secs(20))
+            .is_empty());
+        assert_eq!(index.len(), 1);
+    }
+}
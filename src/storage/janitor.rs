@@ -1,91 +1,72 @@
-use crate::bittorrent::Peer;
+use crate::config::Config;
 use crate::errors::InternalError;
 use crate::state::State;
 use crate::storage;
 
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use actix_web::web;
-use mysql::*;
+use serde::Serialize;
+use tokio::sync::Mutex;
 
+// Flushes and snapshots hold the writing lock for as long as they
+// run, so that stopping the Janitor can wait for them to finish
 #[derive(Clone)]
 pub struct Janitor {
-    reap_interval: Duration,
-    peer_timeout: Duration,
-    flush_interval: Duration,
     state: web::Data<State>,
-    pool: Pool,
+    writing: Arc<Mutex<()>>,
 }
 
 impl Janitor {
-    pub fn new(state: web::Data<State>, pool: Pool) -> Janitor {
+    pub fn new(state: web::Data<State>) -> Janitor {
         Janitor {
-            reap_interval: Duration::new(state.config.bt.reap_interval, 0),
-            peer_timeout: Duration::new(state.config.bt.peer_timeout, 0),
-            flush_interval: Duration::new(state.config.bt.flush_interval, 0),
             state,
-            pool,
+            writing: Arc::new(Mutex::new(())),
         }
     }
 
+    // Intervals are looked up from the current config before every
+    // wait, so a reload takes effect once the pending run is done
+    fn run_every(
+        &self,
+        ctx: &mut Context<Self>,
+        interval: fn(&Config) -> u64,
+        task: fn(&mut Self, &mut Context<Self>),
+    ) {
+        let delay = Duration::new(interval(&self.state.config.current()), 0);
+        ctx.run_later(delay, move |janitor, ctx| {
+            task(janitor, ctx);
+            janitor.run_every(ctx, interval, task);
+        });
+    }
+
+    fn expire(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            let reaped = expire_peers(&self2.state).await;
+
+            if reaped != Reaped::default() {
+                info!(
+                    "Expired {} seeders and {} leechers, removed {} empty swarms.",
+                    reaped.seeders, reaped.leechers, reaped.swarms
+                );
+            }
+        }));
+    }
+
     // Had to clone self to avoid wacky lifetime error
     fn clear_peers(&mut self, ctx: &mut Context<Self>) {
         let self2 = self.clone();
         ctx.spawn(actix::fut::wrap_future(async move {
             info!("Clearing away stale peers...");
 
-            let mut seeds_cleared = 0;
-            let mut leeches_cleared = 0;
-
-            let info_hashes: Vec<String> = self2
-                .state
-                .peer_store
-                .records
-                .read()
-                .await
-                .iter()
-                .map(|(info_hash, _)| info_hash.clone())
-                .collect();
-
-            for info_hash in info_hashes {
-                if let Some(swarm) = self2
-                    .state
-                    .peer_store
-                    .records
-                    .write()
-                    .await
-                    .get_mut(&info_hash)
-                {
-                    let seeds_1 = swarm.seeders.len();
-                    let leeches_1 = swarm.leechers.len();
-
-                    swarm.seeders.retain(|peer| match peer {
-                        Peer::V4(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                        Peer::V6(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                    });
-                    swarm.leechers.retain(|peer| match peer {
-                        Peer::V4(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                        Peer::V6(p) => p.last_announced.elapsed() < self2.peer_timeout,
-                    });
-
-                    seeds_cleared += seeds_1 - swarm.seeders.len();
-                    leeches_cleared += leeches_1 - swarm.leechers.len();
-                }
-            }
-
-            // Make sure that stats are up-to-date
-            // TODO: Getting E0495 all over this thing
-            self2
-                .state
-                .stats
-                .write()
-                .await
-                .cleared_peers(seeds_cleared as u32, leeches_cleared as u32);
+            let reaped = reap_peers(&self2.state).await;
 
             info!(
-                "Cleared {} seeders and {} leechers.",
-                seeds_cleared, leeches_cleared
+                "Cleared {} seeders and {} leechers, removed {} empty swarms.",
+                reaped.seeders, reaped.leechers, reaped.swarms
             );
         }));
     }
@@ -94,23 +75,61 @@ impl Janitor {
     fn flush(&mut self, ctx: &mut Context<Self>) {
         let self2 = self.clone();
         ctx.spawn(actix::fut::wrap_future(async move {
+            let _writing = self2.writing.lock().await;
             info!("Flushing torrents to database...");
 
-            let torrents: Vec<storage::Torrent> = self2
-                .state
-                .torrent_store
-                .torrents
-                .read()
-                .await
-                .iter()
-                .map(|(_, torrent)| torrent.clone())
-                .collect();
+            match flush_torrents(&self2.state).await {
+                Ok(num_torrents) => info!("Flushed {} torrents.", num_torrents),
+                Err(e) => error!("{}", e.text()),
+            }
 
-            let num_torrents = torrents.len();
+            match flush_user_transfers(&self2.state).await {
+                Ok(0) => {}
+                Ok(num_users) => info!("Flushed transfers for {} users.", num_users),
+                Err(e) => error!("{}", e.text()),
+            }
 
-            let _result = storage::mysql::flush_torrents(self2.pool, torrents);
+            match flush_snatches(&self2.state).await {
+                Ok(0) => {}
+                Ok(num_snatches) => info!("Flushed {} snatches.", num_snatches),
+                Err(e) => error!("{}", e.text()),
+            }
+        }));
+    }
 
-            info!("Flushed {} torrents.", num_torrents);
+    fn snapshot_peers(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            let _writing = self2.writing.lock().await;
+            let config = self2.state.config.current();
+            if let Some(path) = &config.storage.peer_snapshot {
+                info!("Snapshotting peers to disk...");
+
+                match storage::snapshot::save(&self2.state.peer_store, path).await {
+                    Ok(num_peers) => info!("Snapshotted {} peers.", num_peers),
+                    Err(e) => error!("{}", e.text()),
+                }
+            }
+        }));
+    }
+
+    fn fetch_users(&mut self, ctx: &mut Context<Self>) {
+        let self2 = self.clone();
+        ctx.spawn(actix::fut::wrap_future(async move {
+            if !self2.state.config.current().bt.private {
+                return;
+            }
+
+            info!("Fetching users from database...");
+
+            match self2.state.backend.load_users() {
+                Ok(users) => {
+                    let num_users = users.len();
+                    self2.state.user_store.replace(users).await;
+                    info!("Loaded {} users from database.", num_users);
+                }
+                Err(e) => error!("{}", e.text()),
+            }
         }));
     }
 
@@ -119,43 +138,358 @@ impl Janitor {
         ctx.spawn(actix::fut::wrap_future(async move {
             info!("Fetching new torrents from database...");
 
-            match storage::mysql::get_torrents(self2.pool) {
+            match self2.state.backend.fetch_new_torrents() {
                 Ok(db_torrents) => {
                     let mut diff = 0;
                     let mut torrent_store = self2.state.torrent_store.torrents.write().await;
                     for (info_hash, torrent) in db_torrents.iter() {
-                        if !torrent_store.contains_key(&info_hash[..].to_string()) {
-                            torrent_store.insert(info_hash.clone(), torrent.clone());
+                        if !torrent_store.contains_key(info_hash) {
+                            torrent_store.insert(*info_hash, torrent.clone());
                             diff += 1;
                         }
                     }
                     info!("Added new {} torrents from database.", diff);
                 }
-                _ => error!("{}", InternalError::StorageTorrentFetchNew.text()),
+                Err(e) => error!("{}", e.text()),
             }
         }));
     }
 }
 
+// What a single reap pass cleared away
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Reaped {
+    pub seeders: usize,
+    pub leechers: usize,
+    pub swarms: usize,
+}
+
+// Drops every peer that hasn't announced within the peer timeout along
+// with any swarm left empty. Peers are found through the expiry index, so
+// this only does as much work as there are peers to drop.
+pub async fn expire_peers(state: &State) -> Reaped {
+    let started = Instant::now();
+    let peer_timeout = Duration::new(state.config.current().bt.peer_timeout, 0);
+
+    // Nothing can have timed out before the clock is that old
+    let mut reaped = Reaped::default();
+    let cutoff = match Instant::now().checked_sub(peer_timeout) {
+        Some(cutoff) => cutoff,
+        None => return reaped,
+    };
+
+    let expired = state
+        .peer_store
+        .expire(cutoff, &state.torrent_store, &state.stats)
+        .await;
+    for e in expired.iter() {
+        reaped.seeders += e.seeders;
+        reaped.leechers += e.leechers;
+        if e.remaining == (0, 0) {
+            reaped.swarms += 1;
+        }
+    }
+
+    state
+        .metrics
+        .reaped(reaped.seeders, reaped.leechers, reaped.swarms);
+    state.metrics.janitor_run("expire", started.elapsed());
+
+    reaped
+}
+
+// Expires whatever is due, then sets each torrent's seeder and leecher
+// counts, and the global ones, to what is actually left in the swarms.
+// Swarm sizes are read off without visiting their peers. Also used by
+// the admin API.
+pub async fn reap_peers(state: &State) -> Reaped {
+    let started = Instant::now();
+    let reaped = expire_peers(state).await;
+
+    state
+        .peer_store
+        .reconcile(&state.torrent_store, &state.stats)
+        .await;
+    state.metrics.janitor_run("reap", started.elapsed());
+
+    reaped
+}
+
+// Writes every torrent in the store through the storage backend and
+// returns how many were written. Also used for the final flush on shutdown.
+pub async fn flush_torrents(state: &State) -> Result<usize, InternalError> {
+    let torrents: Vec<storage::Torrent> = state
+        .torrent_store
+        .torrents
+        .read()
+        .await
+        .iter()
+        .map(|(_, torrent)| torrent.clone())
+        .collect();
+
+    let started = Instant::now();
+    let num_torrents = torrents.len();
+    state.backend.flush_torrents(torrents)?;
+    state.metrics.janitor_run("flush", started.elapsed());
+
+    Ok(num_torrents)
+}
+
+// Adds the traffic users have reported since the last flush onto their
+// stored totals. Transfers are put back if the backend fails so that
+// they are retried with the next flush instead of being lost.
+pub async fn flush_user_transfers(state: &State) -> Result<usize, InternalError> {
+    let transfers = state.user_store.take_transfers().await;
+    if transfers.is_empty() {
+        return Ok(0);
+    }
+
+    let num_users = transfers.len();
+    if let Err(e) = state.backend.flush_user_transfers(transfers.clone()) {
+        state.user_store.restore_transfers(transfers).await;
+        return Err(e);
+    }
+
+    Ok(num_users)
+}
+
+// Writes snatches recorded since the last flush through the storage
+// backend. As with transfers, they are put back if the backend fails.
+pub async fn flush_snatches(state: &State) -> Result<usize, InternalError> {
+    let snatches = state.snatch_store.take_pending().await;
+    if snatches.is_empty() {
+        return Ok(0);
+    }
+
+    let num_snatches = snatches.len();
+    if let Err(e) = state.backend.flush_snatches(snatches.clone()) {
+        state.snatch_store.restore_pending(snatches).await;
+        return Err(e);
+    }
+
+    Ok(num_snatches)
+}
+
 impl Actor for Janitor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
         info!("Janitor is now on duty...");
 
-        // This will go through all of the swarms and remove
-        // any peers that have not announced in a defined time
-        ctx.run_interval(self.reap_interval, Self::clear_peers);
+        // Peers that have not announced in a defined time are
+        // dropped a bucket at a time as soon as their bucket is due
+        self.run_every(
+            ctx,
+            |_| storage::expiry::BUCKET_WIDTH.as_secs(),
+            Self::expire,
+        );
+
+        // This will also bring every torrent's peer counts
+        // back in line with its swarm, should they have drifted
+        self.run_every(ctx, |config| config.bt.reap_interval, Self::clear_peers);
 
         // This will flush all torrent data to the database
         // to ensure that stats are up-to-date
-        ctx.run_interval(self.flush_interval, Self::flush);
+        self.run_every(ctx, |config| config.bt.flush_interval, Self::flush);
+
+        // Peers are written out alongside torrents so that
+        // a restart doesn't leave every swarm empty
+        self.run_every(ctx, |config| config.bt.flush_interval, Self::snapshot_peers);
 
         // This will pull any new torrents from the database
         // and add them to the torrent store
-        ctx.run_interval(
-            Duration::new(self.state.config.bt.announce_rate, 0),
+        self.run_every(
+            ctx,
+            |config| config.bt.announce_rate,
             Self::fetch_new_torrents,
         );
+
+        // Private trackers also pick up new and revoked users
+        self.run_every(ctx, |config| config.bt.announce_rate, Self::fetch_users);
+    }
+}
+
+// Stops every scheduled task. Resolves once any flush or snapshot
+// that was already under way has finished, so that the final flush
+// on shutdown never runs alongside one of the Janitor's own.
+pub struct Stop;
+
+impl Message for Stop {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<Stop> for Janitor {
+    type Result = ResponseFuture<Result<(), ()>>;
+
+    fn handle(&mut self, _: Stop, ctx: &mut Context<Self>) -> Self::Result {
+        ctx.stop();
+
+        let writing = self.writing.clone();
+        Box::pin(async move {
+            let _ = writing.lock().await;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::bittorrent::{InfoHash, Peer, PeerId, Peerv4};
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+    use crate::util::Event;
+
+    fn peer(peer_id: &[u8; 20], last_announced: Instant) -> Peer {
+        Peer::V4(Peerv4 {
+            peer_id: PeerId(*peer_id),
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 6881,
+            last_announced,
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
+        })
+    }
+
+    #[actix_rt::test]
+    async fn reap_prunes_swarms_and_reconciles_counts() {
+        let info_hash1 = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let info_hash2 = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let info_hash3 = InfoHash(*b"C3D4E5F6G7H8I9J0K1L2");
+
+        // Counts that have drifted away from the swarms
+        let mut torrents = TorrentRecords::new();
+        torrents.insert(info_hash1, Torrent::new(info_hash1, 10, 0, 10, 0));
+        torrents.insert(info_hash2, Torrent::new(info_hash2, 3, 0, 3, 0));
+        torrents.insert(info_hash3, Torrent::new(info_hash3, 5, 0, 5, 0));
+        let state = State::new(Config::default(), TorrentStore::new(torrents));
+
+        // Peers may outlive the timeout by up to a bucket
+        let stale = Instant::now()
+            .checked_sub(Duration::from_secs(7200) + 2 * storage::expiry::BUCKET_WIDTH)
+            .unwrap();
+        let peer_store = &state.peer_store;
+        peer_store
+            .put_seeder(info_hash1, peer(b"-TR2940-000000000001", Instant::now()))
+            .await;
+        peer_store
+            .put_leecher(info_hash1, peer(b"-TR2940-000000000002", stale))
+            .await;
+        peer_store
+            .put_leecher(info_hash2, peer(b"-TR2940-000000000003", stale))
+            .await;
+
+        let reaped = reap_peers(&state).await;
+        assert_eq!(
+            reaped,
+            Reaped {
+                seeders: 0,
+                leechers: 2,
+                swarms: 1
+            }
+        );
+
+        assert!(peer_store.get_swarm(info_hash1).await.is_some());
+        assert!(peer_store.get_swarm(info_hash2).await.is_none());
+
+        let torrent_store = &state.torrent_store;
+        assert_eq!(torrent_store.get_announce_stats(info_hash1).await, (1, 0));
+        assert_eq!(torrent_store.get_announce_stats(info_hash2).await, (0, 0));
+        assert_eq!(torrent_store.get_announce_stats(info_hash3).await, (0, 0));
+        assert_eq!(
+            (state.stats.total_seeders(), state.stats.total_leechers()),
+            (1, 0)
+        );
+
+        let rendered = state.metrics.render(&state.stats, peer_store).await;
+        assert!(rendered.contains("tyto_reaped_peers_total{kind=\"leecher\"} 2"));
+        assert!(rendered.contains("tyto_pruned_swarms_total 1"));
+        assert!(rendered.contains("tyto_peers{family=\"ipv4\",kind=\"seeder\"} 1"));
+        assert!(rendered.contains("tyto_peers{family=\"ipv4\",kind=\"leecher\"} 0"));
+        assert!(rendered.contains("tyto_swarms 1"));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn counts_follow_swarms_under_their_lock() {
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut torrents = TorrentRecords::new();
+        torrents.insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+        let state = Arc::new(State::new(Config::default(), TorrentStore::new(torrents)));
+
+        let announcers: Vec<_> = (0..4)
+            .map(|worker| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for n in 0..500 {
+                        let mut peer_id = [0; 20];
+                        peer_id.copy_from_slice(format!("-TR2940-{}{:011}", worker, n).as_bytes());
+                        state
+                            .peer_store
+                            .announce(
+                                info_hash,
+                                peer(&peer_id, Instant::now()),
+                                &Event::Started,
+                                1000,
+                                &state.torrent_store,
+                                &state.stats,
+                            )
+                            .await;
+                    }
+                })
+            })
+            .collect();
+
+        // Reconciling relies on the counts never being behind the swarms
+        // while their shard is held
+        let checker = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                for _ in 0..200 {
+                    for shard in state.peer_store.shards() {
+                        let shard = shard.read().await;
+                        let leechers = match shard.records.get(&info_hash) {
+                            Some(swarm) => swarm.leechers.len(),
+                            None => continue,
+                        };
+                        assert_eq!(
+                            state.torrent_store.get_announce_stats(info_hash).await,
+                            (0, leechers as u32)
+                        );
+                        assert_eq!(state.stats.total_leechers(), leechers as u64);
+                    }
+                }
+            })
+        };
+        for announcer in announcers {
+            announcer.await.unwrap();
+        }
+        checker.await.unwrap();
+
+        reap_peers(&state).await;
+        assert_eq!(
+            state.torrent_store.get_announce_stats(info_hash).await,
+            (0, 2000)
+        );
+        assert_eq!(state.stats.total_leechers(), 2000);
+    }
+
+    #[actix_rt::test]
+    async fn stop_waits_for_writes_under_way() {
+        let state = State::new(Config::default(), TorrentStore::default());
+        let janitor = Janitor::new(web::Data::new(state));
+
+        // Stands in for a flush that is part way through
+        let writing = janitor.writing.clone();
+        let flush = writing.lock().await;
+
+        let mut stopped = janitor.start().send(Stop);
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        assert!(futures::poll!(&mut stopped).is_pending());
+
+        drop(flush);
+        assert_eq!(stopped.await.unwrap(), Ok(()));
     }
 }
@@ -0,0 +1,88 @@
+// Where a peer stands in its swarm. Announces move peers between these
+// states, and the seeder and leecher counts kept by the torrent store and
+// the global statistics only ever follow the moves that really happened.
+
+use crate::util::Event;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerState {
+    Absent,
+    Leeching,
+    Seeding,
+}
+
+impl PeerState {
+    // Where an announce leaves the peer, wherever it was before. Started and
+    // regular announces go by how much the client has left to download, so a
+    // peer that never sent started, or that expired and came back, is added
+    // again in the right place. A completed from an unknown peer means the
+    // tracker lost track of it, e.g. through a restart, so it joins as a seeder.
+    pub fn after(event: &Event, left: u64) -> PeerState {
+        match event {
+            Event::Stopped => PeerState::Absent,
+            Event::Completed => PeerState::Seeding,
+            Event::Started | Event::None if left == 0 => PeerState::Seeding,
+            Event::Started | Event::None => PeerState::Leeching,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transition {
+    pub from: PeerState,
+    pub to: PeerState,
+}
+
+impl Transition {
+    pub fn new(from: PeerState, to: PeerState) -> Transition {
+        Transition { from, to }
+    }
+
+    // Change in the number of seeders and leechers
+    pub fn deltas(&self) -> (i64, i64) {
+        let count = |state: PeerState| match state {
+            PeerState::Absent => (0, 0),
+            PeerState::Leeching => (0, 1),
+            PeerState::Seeding => (1, 0),
+        };
+        let (from, to) = (count(self.from), count(self.to));
+        (to.0 - from.0, to.1 - from.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use PeerState::*;
+
+    #[test]
+    fn peer_state_after() {
+        for (event, left, next) in [
+            (Event::Started, 1000, Leeching),
+            (Event::Started, 0, Seeding),
+            (Event::None, 1000, Leeching),
+            (Event::None, 0, Seeding),
+            (Event::Completed, 0, Seeding),
+            (Event::Completed, 1000, Seeding),
+            (Event::Stopped, 0, Absent),
+            (Event::Stopped, 1000, Absent),
+        ] {
+            assert_eq!(
+                PeerState::after(&event, left),
+                next,
+                "{:?} left={}",
+                event,
+                left
+            );
+        }
+    }
+
+    #[test]
+    fn transition_deltas() {
+        assert_eq!(Transition::new(Absent, Leeching).deltas(), (0, 1));
+        assert_eq!(Transition::new(Leeching, Seeding).deltas(), (1, -1));
+        assert_eq!(Transition::new(Seeding, Absent).deltas(), (-1, 0));
+        assert_eq!(Transition::new(Leeching, Leeching).deltas(), (0, 0));
+    }
+}
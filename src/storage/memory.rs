@@ -0,0 +1,157 @@
+use std::sync::Mutex;
+
+use crate::bittorrent::InfoHash;
+use crate::errors::InternalError;
+use hashbrown::HashMap;
+
+use crate::storage::{
+    Snatch, Snatcher, Torrent, TorrentBackend, TorrentRecords, UserRecords, UserTransfer,
+};
+
+// Keeps flushed torrents around for the lifetime of the process only.
+// This allows the tracker to run without any database at all.
+pub struct MemoryBackend {
+    torrents: Mutex<TorrentRecords>,
+    users: Mutex<UserRecords>,
+    snatches: Mutex<HashMap<(InfoHash, Snatcher), Snatch>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::with_users(UserRecords::new())
+    }
+
+    pub fn with_users(users: UserRecords) -> MemoryBackend {
+        MemoryBackend {
+            torrents: Mutex::new(TorrentRecords::new()),
+            users: Mutex::new(users),
+            snatches: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        MemoryBackend::new()
+    }
+}
+
+impl TorrentBackend for MemoryBackend {
+    fn load_torrents(&self) -> Result<TorrentRecords, InternalError> {
+        match self.torrents.lock() {
+            Ok(torrents) => Ok(torrents.clone()),
+            Err(_) => Err(InternalError::StorageTorrentLoad),
+        }
+    }
+
+    fn flush_torrents(&self, torrents: Vec<Torrent>) -> Result<(), InternalError> {
+        match self.torrents.lock() {
+            Ok(mut stored) => {
+                for torrent in torrents {
+                    stored.insert(torrent.info_hash, torrent);
+                }
+                Ok(())
+            }
+            Err(_) => Err(InternalError::StorageTorrentFlush),
+        }
+    }
+
+    fn fetch_new_torrents(&self) -> Result<TorrentRecords, InternalError> {
+        match self.torrents.lock() {
+            Ok(torrents) => Ok(torrents.clone()),
+            Err(_) => Err(InternalError::StorageTorrentFetchNew),
+        }
+    }
+
+    // Snatches of deleted torrents go along with them
+    fn delete_torrents(&self, info_hashes: Vec<InfoHash>) -> Result<(), InternalError> {
+        match (self.torrents.lock(), self.snatches.lock()) {
+            (Ok(mut stored), Ok(mut snatches)) => {
+                for info_hash in info_hashes.iter() {
+                    stored.remove(info_hash);
+                }
+                snatches.retain(|(info_hash, _), _| !info_hashes.contains(info_hash));
+                Ok(())
+            }
+            _ => Err(InternalError::StorageTorrentDelete),
+        }
+    }
+
+    fn load_users(&self) -> Result<UserRecords, InternalError> {
+        match self.users.lock() {
+            Ok(users) => Ok(users.clone()),
+            Err(_) => Err(InternalError::StorageUserLoad),
+        }
+    }
+
+    fn flush_user_transfers(&self, transfers: Vec<UserTransfer>) -> Result<(), InternalError> {
+        match self.users.lock() {
+            Ok(mut users) => {
+                for transfer in transfers {
+                    if let Some(user) = users.values_mut().find(|u| u.id == transfer.user_id) {
+                        user.uploaded += transfer.uploaded;
+                        user.downloaded += transfer.downloaded;
+                    }
+                }
+                Ok(())
+            }
+            Err(_) => Err(InternalError::StorageUserFlush),
+        }
+    }
+
+    fn load_snatches(&self) -> Result<Vec<Snatch>, InternalError> {
+        match self.snatches.lock() {
+            Ok(snatches) => Ok(snatches.values().copied().collect()),
+            Err(_) => Err(InternalError::StorageSnatchLoad),
+        }
+    }
+
+    fn flush_snatches(&self, snatches: Vec<Snatch>) -> Result<(), InternalError> {
+        match self.snatches.lock() {
+            Ok(mut stored) => {
+                for snatch in snatches {
+                    stored
+                        .entry((snatch.info_hash, snatch.snatcher))
+                        .or_insert(snatch);
+                }
+                Ok(())
+            }
+            Err(_) => Err(InternalError::StorageSnatchFlush),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::conformance;
+
+    #[test]
+    fn memory_backend_flush_and_load() {
+        let backend = MemoryBackend::new();
+        conformance::flush_and_load(&backend, &backend);
+    }
+
+    #[test]
+    fn memory_backend_fetch_new_and_delete() {
+        let backend = MemoryBackend::new();
+        conformance::fetch_new_and_delete(&backend, &|info_hash| {
+            backend
+                .flush_torrents(vec![Torrent::new(info_hash, 0, 0, 0, 0)])
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn memory_backend_users() {
+        let backend = MemoryBackend::with_users(conformance::user_records());
+        conformance::users(&backend);
+    }
+
+    #[test]
+    fn memory_backend_snatches() {
+        let backend = MemoryBackend::new();
+        conformance::snatches(&backend, &backend);
+    }
+}
@@ -1,7 +1,17 @@
+#[cfg(test)]
+mod conformance;
+pub mod expiry;
 pub mod janitor;
+pub mod lifecycle;
+pub mod memory;
 pub mod mysql;
+pub mod postgres;
+pub mod snapshot;
+pub mod sqlite;
 
+use std::mem;
 use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use hashbrown::{HashMap, HashSet};
 use rand::seq::SliceRandom;
@@ -9,14 +19,23 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::bittorrent::ScrapeFile;
-use crate::bittorrent::{CompactPeer, CompactPeerv4, CompactPeerv6, Peer};
+use crate::bittorrent::{
+    CompactPeer, CompactPeerv4, CompactPeerv6, DictionaryPeer, InfoHash, Peer, PeerId,
+};
+use crate::config;
+use crate::errors::InternalError;
+use crate::statistics::{GlobalStatistics, PeerCounts};
+use crate::util::{from_hex, Event};
+
+use self::expiry::ExpiryIndex;
+use self::lifecycle::{PeerState, Transition};
 
 #[derive(Debug, Clone)]
-struct PeerList(Vec<CompactPeer>);
+struct PeerList<T>(Vec<T>);
 
 // Wasn't a huge fan of this, but couldn't do it using FromIterator
-impl PeerList {
-    fn new() -> PeerList {
+impl<T> PeerList<T> {
+    fn new() -> PeerList<T> {
         PeerList(Vec::new())
     }
 
@@ -34,20 +53,20 @@ impl PeerList {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Torrent {
-    pub info_hash: String,
+    pub info_hash: InfoHash,
     pub complete: u32,   // Number of seeders
-    pub downloaded: u32, // Amount of Event::Complete as been received
+    pub downloaded: u32, // Number of distinct snatches recorded
     pub incomplete: u32, // Number of leechers
-    pub balance: u32,    // Total traffic for this torrent
+    pub balance: u64,    // Total traffic reported for this torrent
 }
 
 impl Torrent {
     pub fn new(
-        info_hash: String,
+        info_hash: InfoHash,
         complete: u32,
         downloaded: u32,
         incomplete: u32,
-        balance: u32,
+        balance: u64,
     ) -> Torrent {
         Torrent {
             info_hash,
@@ -59,7 +78,156 @@ impl Torrent {
     }
 }
 
-pub type TorrentRecords = HashMap<String, Torrent>;
+pub type TorrentRecords = HashMap<InfoHash, Torrent>;
+
+pub type UserId = u32;
+
+// Users only matter when running as a private tracker,
+// where every announce has to carry a valid passkey
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub id: UserId,
+    pub passkey: String,
+    pub revoked: bool,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl User {
+    pub fn new(id: UserId, passkey: String, revoked: bool) -> User {
+        User {
+            id,
+            passkey,
+            revoked,
+            uploaded: 0,
+            downloaded: 0,
+        }
+    }
+}
+
+// Traffic a user has reported since the last flush. These are
+// added onto the stored totals rather than replacing them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct UserTransfer {
+    pub user_id: UserId,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+// Keyed by passkey, which is what announces are looked up by
+pub type UserRecords = HashMap<String, User>;
+
+// Whoever completed a torrent. Private trackers know the user behind
+// each peer, so a user snatching through several clients counts once.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Snatcher {
+    User(UserId),
+    Peer(PeerId),
+}
+
+impl Snatcher {
+    pub fn of(peer: &Peer) -> Snatcher {
+        match peer.user_id() {
+            Some(user_id) => Snatcher::User(user_id),
+            None => Snatcher::Peer(peer.peer_id()),
+        }
+    }
+
+    // Databases store a kind column of "user" or "peer" next to a user ID
+    // column and a hex peer ID column. Only the column the kind names is
+    // read, as any user ID, zero included, may be a real user.
+    fn to_columns(self) -> (&'static str, UserId, String) {
+        match self {
+            Snatcher::User(user_id) => ("user", user_id, String::new()),
+            Snatcher::Peer(peer_id) => ("peer", 0, peer_id.to_string()),
+        }
+    }
+
+    fn from_columns(kind: &str, user_id: UserId, peer_id: &str) -> Option<Snatcher> {
+        match kind {
+            "user" => Some(Snatcher::User(user_id)),
+            "peer" => {
+                let peer_id = from_hex(peer_id.as_bytes())?;
+                Some(Snatcher::Peer(PeerId::from_bytes(&peer_id)?))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Snatch {
+    pub info_hash: InfoHash,
+    pub snatcher: Snatcher,
+    pub completed_at: u64, // Seconds since the Unix epoch
+}
+
+// Anything that can persist torrents between runs of the tracker.
+// Calls are made from the Janitor and are allowed to block.
+pub trait TorrentBackend: Send + Sync {
+    // Called once at start-up to fill the torrent store
+    fn load_torrents(&self) -> Result<TorrentRecords, InternalError>;
+
+    // Writes the current state of every given torrent
+    fn flush_torrents(&self, torrents: Vec<Torrent>) -> Result<(), InternalError>;
+
+    // Returns torrents that may have been added since the last call;
+    // the caller only inserts those it doesn't already know about
+    fn fetch_new_torrents(&self) -> Result<TorrentRecords, InternalError>;
+
+    fn delete_torrents(&self, info_hashes: Vec<InfoHash>) -> Result<(), InternalError>;
+
+    // Returns every user, revoked or not; only used by private trackers
+    fn load_users(&self) -> Result<UserRecords, InternalError>;
+
+    // Adds each transfer onto the matching user's stored totals
+    fn flush_user_transfers(&self, transfers: Vec<UserTransfer>) -> Result<(), InternalError>;
+
+    // Called once at start-up so that completions from earlier runs
+    // aren't counted again
+    fn load_snatches(&self) -> Result<Vec<Snatch>, InternalError>;
+
+    // Records new snatches; ones already stored are left as they are
+    fn flush_snatches(&self, snatches: Vec<Snatch>) -> Result<(), InternalError>;
+}
+
+// Picks the storage backend named in the configuration
+pub fn create_backend(storage: &config::Storage) -> Result<Arc<dyn TorrentBackend>, InternalError> {
+    match storage.backend.as_str() {
+        "memory" => Ok(Arc::new(memory::MemoryBackend::new())),
+        "mysql" => match mysql::MySqlBackend::new(&storage.path, storage.password.clone()) {
+            Ok(backend) => Ok(Arc::new(backend)),
+            Err(e) => {
+                error!("MySQL: {}", e);
+                Err(InternalError::StorageBackendInit)
+            }
+        },
+        "postgres" => match postgres::PostgresBackend::new(&storage.path, storage.password.clone())
+        {
+            Ok(backend) => Ok(Arc::new(backend)),
+            Err(e) => {
+                error!("PostgreSQL: {}", e);
+                Err(InternalError::StorageBackendInit)
+            }
+        },
+        "sqlite" => match sqlite::SqliteBackend::new(&storage.path) {
+            Ok(backend) => Ok(Arc::new(backend)),
+            Err(e) => {
+                error!("SQLite: {}", e);
+                Err(InternalError::StorageBackendInit)
+            }
+        },
+        _ => Err(InternalError::StorageBackendUnknown),
+    }
+}
+
+fn apply_delta(count: u32, delta: i64) -> u32 {
+    if delta < 0 {
+        count.saturating_sub(delta.unsigned_abs() as u32)
+    } else {
+        count.saturating_add(delta as u32)
+    }
+}
 
 // TorrentStore needs to be wrapped in a RwLock or other exclusion
 // primitive in order to prevent data races. This is further wrapped
@@ -76,20 +244,14 @@ impl TorrentStore {
         }
     }
 
-    pub fn default() -> TorrentStore {
-        TorrentStore {
-            torrents: Arc::new(RwLock::new(TorrentRecords::new())),
-        }
-    }
-
-    pub async fn get_scrapes(&self, info_hashes: Vec<String>) -> Vec<ScrapeFile> {
+    pub async fn get_scrapes(&self, info_hashes: Vec<InfoHash>) -> Vec<ScrapeFile> {
         let torrents = self.torrents.read().await;
         let mut scrapes = Vec::new();
 
         for info_hash in info_hashes {
             if let Some(t) = torrents.get(&info_hash) {
                 scrapes.push(ScrapeFile {
-                    info_hash: info_hash.clone(),
+                    info_hash,
                     complete: t.complete,
                     downloaded: t.downloaded,
                     incomplete: t.incomplete,
@@ -102,7 +264,7 @@ impl TorrentStore {
     }
 
     // Announces only require complete and incomplete
-    pub async fn get_announce_stats(&self, info_hash: String) -> (u32, u32) {
+    pub async fn get_announce_stats(&self, info_hash: InfoHash) -> (u32, u32) {
         let torrents = self.torrents.read().await;
         let mut complete: u32 = 0;
         let mut incomplete: u32 = 0;
@@ -115,22 +277,94 @@ impl TorrentStore {
         (complete, incomplete)
     }
 
-    pub async fn new_seed(&self, info_hash: String) {
+    // Adds an empty record for an unknown torrent unless the store already
+    // holds `limit` torrents. Returns whether the torrent is known afterwards.
+    pub async fn register(&self, info_hash: InfoHash, limit: usize) -> bool {
+        if self.torrents.read().await.contains_key(&info_hash) {
+            return true;
+        }
+
+        let mut torrents = self.torrents.write().await;
+        if torrents.contains_key(&info_hash) {
+            return true;
+        }
+        if torrents.len() >= limit {
+            return false;
+        }
+
+        torrents.insert(info_hash, Torrent::new(info_hash, 0, 0, 0, 0));
+        true
+    }
+
+    pub async fn remove(&self, info_hash: InfoHash) -> Option<Torrent> {
+        self.torrents.write().await.remove(&info_hash)
+    }
+
+    // Keeps complete and incomplete in line with a peer's move in the swarm
+    pub async fn apply(&self, info_hash: InfoHash, transition: Transition) {
+        let (seeders, leechers) = transition.deltas();
+        if seeders == 0 && leechers == 0 {
+            return;
+        }
+
         let mut torrents = self.torrents.write().await;
         if let Some(t) = torrents.get_mut(&info_hash) {
-            t.complete += 1;
-            t.incomplete = t.incomplete.saturating_sub(1);
+            t.complete = apply_delta(t.complete, seeders);
+            t.incomplete = apply_delta(t.incomplete, leechers);
         }
     }
 
-    pub async fn new_leech(&self, info_hash: String) {
+    // Sets the given torrents' counts to those of their swarms,
+    // given as (seeders, leechers)
+    pub async fn reconcile(&self, counts: &HashMap<InfoHash, (usize, usize)>) {
+        let mut torrents = self.torrents.write().await;
+        for (info_hash, (seeders, leechers)) in counts {
+            if let Some(t) = torrents.get_mut(info_hash) {
+                t.complete = *seeders as u32;
+                t.incomplete = *leechers as u32;
+            }
+        }
+    }
+
+    // Takes peers that were dropped outside of an announce,
+    // given as (info hash, seeders, leechers), off the counts
+    pub async fn cleared_peers(&self, cleared: &[(InfoHash, usize, usize)]) {
+        if cleared.is_empty() {
+            return;
+        }
+
+        let mut torrents = self.torrents.write().await;
+        for (info_hash, seeders, leechers) in cleared {
+            if let Some(t) = torrents.get_mut(info_hash) {
+                t.complete = t.complete.saturating_sub(*seeders as u32);
+                t.incomplete = t.incomplete.saturating_sub(*leechers as u32);
+            }
+        }
+    }
+
+    pub async fn add_traffic(&self, info_hash: InfoHash, uploaded: u64, downloaded: u64) {
         let mut torrents = self.torrents.write().await;
         if let Some(t) = torrents.get_mut(&info_hash) {
-            t.incomplete += 1;
+            t.balance = t
+                .balance
+                .saturating_add(uploaded)
+                .saturating_add(downloaded);
         }
     }
 
-    /*pub fn undo_snatch(&self, info_hash: String) {
+    // Returns whether the torrent is known and the snatch was counted
+    pub async fn add_snatch(&self, info_hash: InfoHash) -> bool {
+        let mut torrents = self.torrents.write().await;
+        match torrents.get_mut(&info_hash) {
+            Some(t) => {
+                t.downloaded = t.downloaded.saturating_add(1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /*pub fn undo_snatch(&self, info_hash: InfoHash) {
         let mut torrents = self.torrents.write();
         if let Some(t) = torrents.get_mut(&info_hash) {
             t.incomplete = t.incomplete.saturating_sub(1);
@@ -138,6 +372,145 @@ impl TorrentStore {
     }*/
 }
 
+impl Default for TorrentStore {
+    fn default() -> Self {
+        TorrentStore::new(TorrentRecords::new())
+    }
+}
+
+// UserStore needs to be wrapped in a RwLock or other exclusion
+// primitive in order to prevent data races. This is further wrapped
+// in an atomic reference counter in order to make it thread-safe.
+#[derive(Debug, Clone)]
+pub struct UserStore {
+    pub users: Arc<RwLock<UserRecords>>,
+    pub transfers: Arc<RwLock<HashMap<UserId, UserTransfer>>>,
+}
+
+impl UserStore {
+    pub fn new(user_records: UserRecords) -> UserStore {
+        UserStore {
+            users: Arc::new(RwLock::new(user_records)),
+            transfers: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub async fn add_transfer(&self, user_id: UserId, uploaded: u64, downloaded: u64) {
+        let mut transfers = self.transfers.write().await;
+        let transfer = transfers.entry(user_id).or_insert(UserTransfer {
+            user_id,
+            uploaded: 0,
+            downloaded: 0,
+        });
+        transfer.uploaded += uploaded;
+        transfer.downloaded += downloaded;
+    }
+
+    // Hands over everything pending for a flush. If the flush fails,
+    // the transfers should be given back with restore_transfers.
+    pub async fn take_transfers(&self) -> Vec<UserTransfer> {
+        let mut transfers = self.transfers.write().await;
+        transfers.drain().map(|(_, transfer)| transfer).collect()
+    }
+
+    pub async fn restore_transfers(&self, transfers: Vec<UserTransfer>) {
+        for transfer in transfers {
+            self.add_transfer(transfer.user_id, transfer.uploaded, transfer.downloaded)
+                .await;
+        }
+    }
+
+    // Revoked passkeys are treated the same as unknown ones
+    pub async fn get_user(&self, passkey: &str) -> Option<User> {
+        let users = self.users.read().await;
+        users.get(passkey).filter(|u| !u.revoked).cloned()
+    }
+
+    // Swaps in a fresh set of users from the backend so
+    // that new users and revocations are picked up
+    pub async fn replace(&self, user_records: UserRecords) {
+        *self.users.write().await = user_records;
+    }
+}
+
+impl Default for UserStore {
+    fn default() -> Self {
+        UserStore::new(UserRecords::new())
+    }
+}
+
+// SnatchStore remembers who has completed each torrent so that repeated
+// completions are ignored, and holds new snatches until they are flushed.
+// Every snatch is kept for as long as its torrent is, so that completions
+// never wait on the backend.
+// Both parts are wrapped in RwLocks and atomic reference counters in
+// order to make them thread-safe.
+#[derive(Debug, Clone)]
+pub struct SnatchStore {
+    pub snatchers: Arc<RwLock<HashMap<InfoHash, HashSet<Snatcher>>>>,
+    pub pending: Arc<RwLock<Vec<Snatch>>>,
+}
+
+impl SnatchStore {
+    pub fn new(snatches: Vec<Snatch>) -> SnatchStore {
+        let mut snatchers: HashMap<InfoHash, HashSet<Snatcher>> = HashMap::new();
+        for snatch in snatches {
+            snatchers
+                .entry(snatch.info_hash)
+                .or_default()
+                .insert(snatch.snatcher);
+        }
+
+        SnatchStore {
+            snatchers: Arc::new(RwLock::new(snatchers)),
+            pending: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    // Returns whether this is the snatcher's first completion of the torrent
+    pub async fn record(&self, info_hash: InfoHash, snatcher: Snatcher) -> bool {
+        let mut snatchers = self.snatchers.write().await;
+        if !snatchers.entry(info_hash).or_default().insert(snatcher) {
+            return false;
+        }
+
+        let completed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.pending.write().await.push(Snatch {
+            info_hash,
+            snatcher,
+            completed_at,
+        });
+        true
+    }
+
+    // Hands over everything pending for a flush. If the flush fails,
+    // the snatches should be given back with restore_pending.
+    pub async fn take_pending(&self) -> Vec<Snatch> {
+        mem::take(&mut *self.pending.write().await)
+    }
+
+    pub async fn restore_pending(&self, snatches: Vec<Snatch>) {
+        self.pending.write().await.extend(snatches);
+    }
+
+    // Forgets a deleted torrent, including snatches not yet flushed
+    pub async fn remove(&self, info_hash: InfoHash) {
+        self.snatchers.write().await.remove(&info_hash);
+        self.pending
+            .write()
+            .await
+            .retain(|snatch| snatch.info_hash != info_hash);
+    }
+}
+
+impl Default for SnatchStore {
+    fn default() -> Self {
+        SnatchStore::new(Vec::new())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Swarm {
     pub seeders: HashSet<Peer>,
@@ -155,137 +528,502 @@ impl Swarm {
         }
     }
 
+    // Replacing rather than inserting keeps the announce time
+    // and reported totals of a peer that was already present
     fn add_seeder(&mut self, peer: Peer) {
-        self.seeders.insert(peer);
+        self.seeders.replace(peer);
     }
 
     fn add_leecher(&mut self, peer: Peer) {
-        self.leechers.insert(peer);
+        self.leechers.replace(peer);
     }
 
-    // The update methods ensure that peers that
-    // continue to announce have accurate announce times
-    // in order to prevent errant peer reaping
-    fn update_seeder(&mut self, peer: Peer) {
-        if self.seeders.contains(&peer) {
-            self.seeders.replace(peer);
+    fn state_of(&self, peer: &Peer) -> PeerState {
+        if self.seeders.contains(peer) {
+            PeerState::Seeding
+        } else if self.leechers.contains(peer) {
+            PeerState::Leeching
+        } else {
+            PeerState::Absent
         }
     }
 
-    fn update_leecher(&mut self, peer: Peer) {
-        if self.leechers.contains(&peer) {
-            self.leechers.replace(peer);
+    // Puts the peer in the set for its new state and takes it out of the
+    // other. Peers that stay put are still replaced, which keeps their
+    // announce times accurate in order to prevent errant peer reaping.
+    fn move_peer(&mut self, peer: Peer, to: PeerState) {
+        match to {
+            PeerState::Absent => {
+                self.seeders.remove(&peer);
+                self.leechers.remove(&peer);
+            }
+            PeerState::Leeching => {
+                self.seeders.remove(&peer);
+                self.leechers.replace(peer);
+            }
+            PeerState::Seeding => {
+                self.leechers.remove(&peer);
+                self.seeders.replace(peer);
+            }
         }
     }
 
-    fn remove_seeder(&mut self, peer: Peer) -> bool {
-        self.seeders.remove(&peer)
+    fn get_peer(&self, peer: &Peer) -> Option<&Peer> {
+        self.seeders.get(peer).or_else(|| self.leechers.get(peer))
+    }
+}
+
+pub type PeerRecords = HashMap<InfoHash, Swarm>;
+
+// Swarms are spread over this many independently locked shards
+const NUM_SHARDS: usize = 64;
+
+// Reported totals only grow within a client session, so a total lower
+// than the previous one means the client restarted and began counting
+// from zero again; everything it has reported since then is new traffic.
+fn counter_delta(previous: u64, current: u64) -> u64 {
+    if current >= previous {
+        current - previous
+    } else {
+        current
     }
+}
+
+// Info hashes are SHA-1 digests, so their leading bytes are already
+// evenly spread and can pick the shard without hashing them again
+fn shard_index(info_hash: &InfoHash, num_shards: usize) -> usize {
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&info_hash.0[..8]);
+    (u64::from_le_bytes(prefix) % num_shards as u64) as usize
+}
 
-    fn remove_leecher(&mut self, peer: Peer) -> bool {
-        self.leechers.remove(&peer)
+// Peers expired from one swarm, along with the seeders and leechers it has left
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Expired {
+    pub info_hash: InfoHash,
+    pub seeders: usize,
+    pub leechers: usize,
+    pub remaining: (usize, usize),
+}
+
+// What an announce changed: the peer's move in its swarm, and how much it
+// reported transferring since its previous announce
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Announced {
+    pub transition: Transition,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+// Which column of PeerCounts a peer is counted in
+fn family(peer: &Peer) -> usize {
+    match peer {
+        Peer::V4(_) => 0,
+        Peer::V6(_) => 1,
     }
+}
 
-    fn promote_leecher(&mut self, peer: Peer) {
-        match self.leechers.take(&peer) {
-            Some(leecher) => {
-                self.seeders.insert(leecher);
-            }
-            None => {
-                self.seeders.insert(peer);
-            }
-        };
+// Adds counts, or takes them away with a sign of -1, entry by entry
+fn add_counts(total: &mut PeerCounts, counts: &PeerCounts, sign: i64) {
+    for (total, counts) in total.iter_mut().zip(counts.iter()) {
+        for (total, count) in total.iter_mut().zip(counts.iter()) {
+            *total += sign * count;
+        }
     }
 }
 
-type PeerRecords = HashMap<String, Swarm>;
+// Counts the seeders and leechers of a swarm by address family
+fn swarm_counts(swarm: &Swarm) -> PeerCounts {
+    let mut counts = [[0; 2]; 2];
+    for (kind, peers) in [&swarm.seeders, &swarm.leechers].iter().enumerate() {
+        for peer in peers.iter() {
+            counts[kind][family(peer)] += 1;
+        }
+    }
+    counts
+}
+
+// The swarms behind one lock, along with the expiry index of their peers.
+// Each shard also keeps the seeders and leechers it has added to the
+// global totals, so that its share can be put right on its own.
+#[derive(Debug)]
+pub struct Shard {
+    pub records: PeerRecords,
+    expiry: ExpiryIndex,
+    counted: PeerCounts,
+}
+
+impl Shard {
+    fn new(epoch: Instant) -> Shard {
+        Shard {
+            records: PeerRecords::new(),
+            expiry: ExpiryIndex::new(epoch),
+            counted: [[0; 2]; 2],
+        }
+    }
+
+    // Moves the global totals, and this shard's share of them, by a change
+    // in the number of seeders and leechers of each address family
+    fn count(&mut self, stats: &GlobalStatistics, delta: PeerCounts) {
+        add_counts(&mut self.counted, &delta, 1);
+        stats.adjust_peers(&delta);
+    }
+
+    // Takes peers that were dropped outside of an announce off the torrent
+    // counts, given as (info hash, seeders, leechers), and off the global
+    // totals, given as every removed peer by kind and family
+    async fn clear_counts(
+        &mut self,
+        torrent_store: &TorrentStore,
+        stats: &GlobalStatistics,
+        cleared: &[(InfoHash, usize, usize)],
+        removed: PeerCounts,
+    ) {
+        torrent_store.cleared_peers(cleared).await;
+        let mut delta = [[0; 2]; 2];
+        add_counts(&mut delta, &removed, -1);
+        self.count(stats, delta);
+    }
+
+    // Every change to a swarm's peers goes through here so that the
+    // expiry index always matches the swarms. Swarms left empty are dropped.
+    fn move_peer(&mut self, info_hash: InfoHash, peer: Peer, to: PeerState) {
+        if let Some(previous) = self
+            .records
+            .get(&info_hash)
+            .and_then(|sw| sw.get_peer(&peer))
+        {
+            self.expiry.remove(info_hash, previous);
+        }
+        if to != PeerState::Absent {
+            self.expiry.insert(info_hash, &peer);
+        }
+
+        let swarm = self.records.entry(info_hash).or_insert_with(Swarm::new);
+        swarm.move_peer(peer, to);
+        if swarm.seeders.is_empty() && swarm.leechers.is_empty() {
+            self.records.remove(&info_hash);
+        }
+    }
+
+    fn remove_swarm(&mut self, info_hash: InfoHash) -> Option<Swarm> {
+        let swarm = self.records.remove(&info_hash)?;
+        for peer in swarm.seeders.iter().chain(swarm.leechers.iter()) {
+            self.expiry.remove(info_hash, peer);
+        }
+        Some(swarm)
+    }
+
+    // Also returns every expired peer by kind and address family
+    fn expire(&mut self, cutoff: Instant) -> (Vec<Expired>, PeerCounts) {
+        let mut expired: HashMap<InfoHash, (usize, usize)> = HashMap::new();
+        let mut removed = [[0; 2]; 2];
+        for key in self.expiry.take_expired(cutoff) {
+            if let Some(swarm) = self.records.get_mut(&key.info_hash) {
+                let peer = key.peer();
+                let counts = expired.entry(key.info_hash).or_insert((0, 0));
+                if swarm.seeders.remove(&peer) {
+                    counts.0 += 1;
+                    removed[0][family(&peer)] += 1;
+                } else if swarm.leechers.remove(&peer) {
+                    counts.1 += 1;
+                    removed[1][family(&peer)] += 1;
+                }
+            }
+        }
+
+        let expired = expired
+            .into_iter()
+            .map(|(info_hash, (seeders, leechers))| {
+                let remaining = self
+                    .records
+                    .get(&info_hash)
+                    .map_or((0, 0), |sw| (sw.seeders.len(), sw.leechers.len()));
+                if remaining == (0, 0) {
+                    self.records.remove(&info_hash);
+                }
+
+                Expired {
+                    info_hash,
+                    seeders,
+                    leechers,
+                    remaining,
+                }
+            })
+            .collect();
+        (expired, removed)
+    }
+}
 
 // PeerStore needs to be wrapped in a RwLock or other exclusion
-// primitive in order to prevent data races. This is further wrapped
-// in an atomic reference counter in order to make it thread-safe.
+// primitive in order to prevent data races. Swarms are split into
+// shards by info hash, each behind its own lock, so that announces
+// for different torrents rarely wait on each other. The torrent and
+// global peer counts are only changed while the lock of the swarm in
+// question is held, and the torrents lock is always taken after it.
+// This is further wrapped in an atomic reference counter in order to
+// make it thread-safe.
 #[derive(Debug, Clone)]
 pub struct PeerStore {
-    pub records: Arc<RwLock<PeerRecords>>,
+    shards: Arc<Vec<RwLock<Shard>>>,
 }
 
 impl PeerStore {
     pub fn new() -> PeerStore {
+        PeerStore::with_shards(NUM_SHARDS)
+    }
+
+    fn with_shards(num_shards: usize) -> PeerStore {
+        let epoch = Instant::now();
         PeerStore {
-            records: Arc::new(RwLock::new(PeerRecords::new())),
+            shards: Arc::new(
+                (0..num_shards)
+                    .map(|_| RwLock::new(Shard::new(epoch)))
+                    .collect(),
+            ),
         }
     }
 
-    pub async fn put_seeder(&self, info_hash: String, peer: Peer) {
-        let mut store = self.records.write().await;
-        match store.get_mut(&info_hash) {
-            Some(sw) => {
-                sw.add_seeder(peer);
-            }
-            None => {
-                let mut sw = Swarm::new();
-                sw.add_seeder(peer);
-                store.insert(info_hash, sw);
+    fn with_records(records: PeerRecords) -> PeerStore {
+        let epoch = Instant::now();
+        let mut shards: Vec<Shard> = (0..NUM_SHARDS).map(|_| Shard::new(epoch)).collect();
+        for (info_hash, swarm) in records {
+            let shard = &mut shards[shard_index(&info_hash, NUM_SHARDS)];
+            for peer in swarm.seeders.iter().chain(swarm.leechers.iter()) {
+                shard.expiry.insert(info_hash, peer);
             }
+            shard.records.insert(info_hash, swarm);
         }
-    }
 
-    pub async fn remove_seeder(&self, info_hash: String, peer: Peer) -> bool {
-        let mut result = false;
-        let mut store = self.records.write().await;
-        if let Some(sw) = store.get_mut(&info_hash) {
-            result = sw.remove_seeder(peer);
+        PeerStore {
+            shards: Arc::new(shards.into_iter().map(RwLock::new).collect()),
         }
-        result
     }
 
-    pub async fn put_leecher(&self, info_hash: String, peer: Peer) {
-        let mut store = self.records.write().await;
-        match store.get_mut(&info_hash) {
-            Some(sw) => {
-                sw.add_leecher(peer);
-            }
-            None => {
-                let mut sw = Swarm::new();
-                sw.add_leecher(peer);
-                store.insert(info_hash, sw);
+    fn shard(&self, info_hash: &InfoHash) -> &RwLock<Shard> {
+        &self.shards[shard_index(info_hash, self.shards.len())]
+    }
+
+    // For work that has to see every swarm, such as taking a snapshot.
+    // Callers should hold at most one shard lock at a time.
+    pub fn shards(&self) -> &[RwLock<Shard>] {
+        &self.shards
+    }
+
+    // Tests fill swarms directly; the tracker itself always goes through announce
+    #[cfg(test)]
+    pub async fn put_seeder(&self, info_hash: InfoHash, peer: Peer) {
+        self.shard(&info_hash)
+            .write()
+            .await
+            .move_peer(info_hash, peer, PeerState::Seeding);
+    }
+
+    #[cfg(test)]
+    pub async fn put_leecher(&self, info_hash: InfoHash, peer: Peer) {
+        self.shard(&info_hash)
+            .write()
+            .await
+            .move_peer(info_hash, peer, PeerState::Leeching);
+    }
+
+    pub async fn get_swarm(&self, info_hash: InfoHash) -> Option<Swarm> {
+        self.shard(&info_hash)
+            .read()
+            .await
+            .records
+            .get(&info_hash)
+            .cloned()
+    }
+
+    pub async fn remove_swarm(
+        &self,
+        info_hash: InfoHash,
+        torrent_store: &TorrentStore,
+        stats: &GlobalStatistics,
+    ) -> Option<Swarm> {
+        let mut shard = self.shard(&info_hash).write().await;
+        let swarm = shard.remove_swarm(info_hash)?;
+        shard
+            .clear_counts(
+                torrent_store,
+                stats,
+                &[(info_hash, swarm.seeders.len(), swarm.leechers.len())],
+                swarm_counts(&swarm),
+            )
+            .await;
+        Some(swarm)
+    }
+
+    // Removes every peer using the given peer ID, whichever address it
+    // announced from. Returns the number of seeders and leechers removed.
+    pub async fn remove_peer_id(
+        &self,
+        info_hash: InfoHash,
+        peer_id: PeerId,
+        torrent_store: &TorrentStore,
+        stats: &GlobalStatistics,
+    ) -> (usize, usize) {
+        let mut shard = self.shard(&info_hash).write().await;
+        let removed: Vec<(Peer, PeerState)> = match shard.records.get(&info_hash) {
+            Some(sw) => sw
+                .seeders
+                .iter()
+                .map(|p| (p, PeerState::Seeding))
+                .chain(sw.leechers.iter().map(|p| (p, PeerState::Leeching)))
+                .filter(|(p, _)| p.peer_id() == peer_id)
+                .map(|(p, state)| (p.clone(), state))
+                .collect(),
+            None => return (0, 0),
+        };
+
+        let mut counts = (0, 0);
+        let mut by_family = [[0; 2]; 2];
+        for (peer, state) in removed {
+            match state {
+                PeerState::Seeding => {
+                    counts.0 += 1;
+                    by_family[0][family(&peer)] += 1;
+                }
+                _ => {
+                    counts.1 += 1;
+                    by_family[1][family(&peer)] += 1;
+                }
             }
+            shard.move_peer(info_hash, peer, PeerState::Absent);
         }
+        shard
+            .clear_counts(
+                torrent_store,
+                stats,
+                &[(info_hash, counts.0, counts.1)],
+                by_family,
+            )
+            .await;
+        counts
     }
 
-    pub async fn remove_leecher(&self, info_hash: String, peer: Peer) -> bool {
-        let mut result = false;
-        let mut store = self.records.write().await;
-        if let Some(sw) = store.get_mut(&info_hash) {
-            result = sw.remove_leecher(peer);
+    // Moves the peer to wherever the announce leaves it, adding it if it
+    // wasn't in the swarm, and returns the move along with the traffic
+    // since its last announce. Looking the peer up, working out its
+    // traffic, moving it and updating the counts happen under the same
+    // lock, so neither a racing announce nor a reap can count the same
+    // change twice. Peers the swarm hasn't seen yet only set the traffic
+    // baseline, as their totals may already have been counted before
+    // being reaped. A stopping peer's final totals still count.
+    pub async fn announce(
+        &self,
+        info_hash: InfoHash,
+        peer: Peer,
+        event: &Event,
+        left: u64,
+        torrent_store: &TorrentStore,
+        stats: &GlobalStatistics,
+    ) -> Announced {
+        let mut shard = self.shard(&info_hash).write().await;
+        let swarm = shard.records.get(&info_hash);
+        let from = swarm.map_or(PeerState::Absent, |sw| sw.state_of(&peer));
+        let to = PeerState::after(event, left);
+
+        let current = peer.transferred();
+        let (uploaded, downloaded) = match swarm.and_then(|sw| sw.get_peer(&peer)) {
+            Some(previous) => {
+                let previous = previous.transferred();
+                (
+                    counter_delta(previous.0, current.0),
+                    counter_delta(previous.1, current.1),
+                )
+            }
+            None => (0, 0),
+        };
+
+        let family = family(&peer);
+        if from != PeerState::Absent || to != PeerState::Absent {
+            shard.move_peer(info_hash, peer, to);
+        }
+
+        let transition = Transition::new(from, to);
+        let (seeders, leechers) = transition.deltas();
+        torrent_store.apply(info_hash, transition).await;
+        let mut delta = [[0; 2]; 2];
+        delta[0][family] = seeders;
+        delta[1][family] = leechers;
+        shard.count(stats, delta);
+
+        Announced {
+            transition,
+            uploaded,
+            downloaded,
         }
-        result
     }
 
-    pub async fn promote_leecher(&self, info_hash: String, peer: Peer) {
-        let mut store = self.records.write().await;
-        if let Some(sw) = store.get_mut(&info_hash) {
-            sw.promote_leecher(peer);
+    // Drops every peer that last announced before the cutoff, one shard
+    // at a time, and takes them off the counts before the shard is let go.
+    // Only the expiring peers and their swarms are visited.
+    pub async fn expire(
+        &self,
+        cutoff: Instant,
+        torrent_store: &TorrentStore,
+        stats: &GlobalStatistics,
+    ) -> Vec<Expired> {
+        let mut expired = Vec::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().await;
+            let (dropped, removed) = shard.expire(cutoff);
+            let cleared: Vec<(InfoHash, usize, usize)> = dropped
+                .iter()
+                .map(|e| (e.info_hash, e.seeders, e.leechers))
+                .collect();
+            shard
+                .clear_counts(torrent_store, stats, &cleared, removed)
+                .await;
+            expired.extend(dropped);
         }
+        expired
     }
 
-    pub async fn update_peer(&self, info_hash: String, peer: Peer) {
-        let mut store = self.records.write().await;
-        if let Some(sw) = store.get_mut(&info_hash) {
-            sw.update_seeder(peer.clone());
-            sw.update_leecher(peer);
+    // Sets the torrent and global counts to the sizes of the swarms, one
+    // shard at a time. A torrent's counts only depend on its own shard,
+    // which is held until they and the shard's share of the global totals
+    // are written, so no change can land in between. Announces on other
+    // shards carry on meanwhile.
+    pub async fn reconcile(&self, torrent_store: &TorrentStore, stats: &GlobalStatistics) {
+        // Torrents without a swarm have no peers at all
+        let mut known: Vec<Vec<InfoHash>> = vec![Vec::new(); self.shards.len()];
+        for info_hash in torrent_store.torrents.read().await.keys() {
+            known[shard_index(info_hash, self.shards.len())].push(*info_hash);
+        }
+
+        for (shard, known) in self.shards.iter().zip(known) {
+            let mut shard = shard.write().await;
+            let mut counts: HashMap<InfoHash, (usize, usize)> = known
+                .into_iter()
+                .map(|info_hash| (info_hash, (0, 0)))
+                .collect();
+            // The global totals are split by address family, so every
+            // peer of the shard is looked at
+            let mut delta = [[0; 2]; 2];
+            for (info_hash, swarm) in shard.records.iter() {
+                counts.insert(*info_hash, (swarm.seeders.len(), swarm.leechers.len()));
+                add_counts(&mut delta, &swarm_counts(swarm), 1);
+            }
+
+            torrent_store.reconcile(&counts).await;
+            add_counts(&mut delta, &shard.counted, -1);
+            shard.count(stats, delta);
         }
     }
 
     // Returns a randomized vector of peers to be returned to client
     pub async fn get_peers(
         &self,
-        info_hash: String,
+        info_hash: InfoHash,
         numwant: u32,
     ) -> (Vec<CompactPeerv4>, Vec<CompactPeerv6>) {
         let mut peer_list = PeerList::new();
 
-        let store = self.records.read().await;
-        if let Some(sw) = store.get(&info_hash) {
+        let shard = self.shard(&info_hash).read().await;
+        if let Some(sw) = shard.records.get(&info_hash) {
             let seeds: Vec<CompactPeer> = sw
                 .seeders
                 .iter()
@@ -337,269 +1075,837 @@ impl PeerStore {
 
         (peers, peers6)
     }
+
+    // Returns a randomized vector of peers for clients that have
+    // asked for the non-compact dictionary model of peers
+    pub async fn get_dictionary_peers(
+        &self,
+        info_hash: InfoHash,
+        numwant: u32,
+        no_peer_id: bool,
+    ) -> Vec<DictionaryPeer> {
+        let mut peer_list = PeerList::new();
+
+        let shard = self.shard(&info_hash).read().await;
+        if let Some(sw) = shard.records.get(&info_hash) {
+            peer_list.0.extend(
+                sw.seeders
+                    .iter()
+                    .chain(sw.leechers.iter())
+                    .map(|p| DictionaryPeer::new(p, no_peer_id)),
+            );
+        }
+
+        peer_list.make_random(numwant);
+        peer_list.0
+    }
+}
+
+impl Default for PeerStore {
+    fn default() -> Self {
+        PeerStore::new()
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use std::net::Ipv4Addr;
-    use std::time::Instant;
+    use std::time::{Duration, Instant};
 
-    use crate::bittorrent::{Peer, Peerv4};
+    use crate::bittorrent::{Peer, PeerId, Peerv4};
 
     use super::*;
 
+    #[test]
+    fn backend_from_config() {
+        let mut storage = config::Storage::default();
+        assert!(create_backend(&storage).is_ok());
+
+        storage.backend = "floppy".to_string();
+        assert_eq!(
+            create_backend(&storage).err(),
+            Some(InternalError::StorageBackendUnknown)
+        );
+    }
+
     #[tokio::test]
     async fn memory_peer_storage_put_seeder_new_swarm() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
-        peer_store.put_seeder(info_hash.clone(), peer.clone()).await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .seeders
-                .contains(&peer),
-            true
-        );
+        peer_store.put_seeder(info_hash, peer.clone()).await;
+        assert!(peer_store
+            .get_swarm(info_hash)
+            .await
+            .unwrap()
+            .seeders
+            .contains(&peer));
     }
 
     #[tokio::test]
     async fn memory_peer_storage_put_seeder_prior_swarm() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer1 = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
-        peer_store.put_seeder(info_hash.clone(), peer1).await;
+        peer_store.put_seeder(info_hash, peer1).await;
 
         let peer2 = Peer::V4(Peerv4 {
-            peer_id: "TSRQPONMLKJIHGFEDCBA".to_string(),
+            peer_id: PeerId(*b"TSRQPONMLKJIHGFEDCBA"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6881,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
-        peer_store
-            .put_seeder(info_hash.clone(), peer2.clone())
-            .await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .seeders
-                .contains(&peer2),
-            true
-        );
+        peer_store.put_seeder(info_hash, peer2.clone()).await;
+        assert!(peer_store
+            .get_swarm(info_hash)
+            .await
+            .unwrap()
+            .seeders
+            .contains(&peer2));
     }
 
     #[tokio::test]
     async fn memory_peer_storage_put_leecher_new_swarm() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
-        peer_store
-            .put_leecher(info_hash.clone(), peer.clone())
-            .await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .leechers
-                .contains(&peer),
-            true
-        );
+        peer_store.put_leecher(info_hash, peer.clone()).await;
+        assert!(peer_store
+            .get_swarm(info_hash)
+            .await
+            .unwrap()
+            .leechers
+            .contains(&peer));
     }
 
     #[tokio::test]
     async fn memory_peer_storage_put_leecher_prior_swarm() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer1 = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
-        peer_store.put_seeder(info_hash.clone(), peer1).await;
+        peer_store.put_seeder(info_hash, peer1).await;
 
         let peer2 = Peer::V4(Peerv4 {
-            peer_id: "TSRQPONMLKJIHGFEDCBA".to_string(),
+            peer_id: PeerId(*b"TSRQPONMLKJIHGFEDCBA"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6881,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
-        peer_store
-            .put_leecher(info_hash.clone(), peer2.clone())
-            .await;
-        assert_eq!(
-            peer_store
-                .records
-                .read()
-                .await
-                .get(&info_hash)
-                .unwrap()
-                .leechers
-                .contains(&peer2),
-            true
-        );
+        peer_store.put_leecher(info_hash, peer2.clone()).await;
+        assert!(peer_store
+            .get_swarm(info_hash)
+            .await
+            .unwrap()
+            .leechers
+            .contains(&peer2));
     }
 
     #[tokio::test]
     async fn memory_peer_storage_remove_seeder() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let (torrent_store, stats) = (TorrentStore::default(), GlobalStatistics::new());
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
-        peer_store.put_seeder(info_hash.clone(), peer.clone()).await;
+        peer_store.put_seeder(info_hash, peer.clone()).await;
 
-        let _ = peer_store
-            .remove_seeder(info_hash.clone(), peer.clone())
-            .await;
         assert_eq!(
             peer_store
-                .records
-                .read()
+                .announce(
+                    info_hash,
+                    peer.clone(),
+                    &Event::Stopped,
+                    0,
+                    &torrent_store,
+                    &stats
+                )
                 .await
-                .get(&info_hash)
-                .unwrap()
-                .seeders
-                .contains(&peer),
-            false
+                .transition,
+            Transition::new(PeerState::Seeding, PeerState::Absent)
         );
+        // The swarm goes with its last peer
+        assert!(peer_store.get_swarm(info_hash).await.is_none());
     }
 
     #[tokio::test]
     async fn memory_peer_storage_remove_leecher() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let (torrent_store, stats) = (TorrentStore::default(), GlobalStatistics::new());
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
-        peer_store
-            .put_leecher(info_hash.clone(), peer.clone())
-            .await;
+        peer_store.put_leecher(info_hash, peer.clone()).await;
 
-        let _ = peer_store
-            .remove_leecher(info_hash.clone(), peer.clone())
-            .await;
         assert_eq!(
             peer_store
-                .records
-                .read()
+                .announce(
+                    info_hash,
+                    peer.clone(),
+                    &Event::Stopped,
+                    0,
+                    &torrent_store,
+                    &stats
+                )
                 .await
-                .get(&info_hash)
-                .unwrap()
-                .leechers
-                .contains(&peer),
-            false
+                .transition,
+            Transition::new(PeerState::Leeching, PeerState::Absent)
         );
+        // The swarm goes with its last peer
+        assert!(peer_store.get_swarm(info_hash).await.is_none());
     }
 
     #[tokio::test]
     async fn memory_peer_storage_promote_leecher() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let (torrent_store, stats) = (TorrentStore::default(), GlobalStatistics::new());
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
+        });
+
+        peer_store.put_leecher(info_hash, peer.clone()).await;
+        assert_eq!(
+            peer_store
+                .announce(
+                    info_hash,
+                    peer.clone(),
+                    &Event::Completed,
+                    0,
+                    &torrent_store,
+                    &stats
+                )
+                .await
+                .transition,
+            Transition::new(PeerState::Leeching, PeerState::Seeding)
+        );
+
+        let swarm = peer_store.get_swarm(info_hash).await.unwrap();
+        assert!(swarm.seeders.contains(&peer));
+        assert!(swarm.leechers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_get_dictionary_peers() {
+        let peer_store = PeerStore::new();
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+            last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
+        });
+
+        peer_store.put_seeder(info_hash, peer.clone()).await;
+
+        let peers = peer_store.get_dictionary_peers(info_hash, 50, false).await;
+        assert_eq!(peers, vec![DictionaryPeer::new(&peer, false)]);
+        assert_eq!(peers[0].peer_id, Some(PeerId(*b"ABCDEFGHIJKLMNOPQRST")));
+
+        let peers = peer_store.get_dictionary_peers(info_hash, 50, true).await;
+        assert_eq!(peers[0].peer_id, None);
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_update_peer() {
+        let peer_store = PeerStore::new();
+        let (torrent_store, stats) = (TorrentStore::default(), GlobalStatistics::new());
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+            last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
+        });
+
+        peer_store.put_leecher(info_hash, peer.clone()).await;
+
+        let peer2 = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+            ip: Ipv4Addr::LOCALHOST,
+            port: 6893,
+            last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
         peer_store
-            .put_leecher(info_hash.clone(), peer.clone())
-            .await;
-        peer_store
-            .promote_leecher(info_hash.clone(), peer.clone())
+            .announce(
+                info_hash,
+                peer2.clone(),
+                &Event::None,
+                1000,
+                &torrent_store,
+                &stats,
+            )
             .await;
 
+        assert!(peer_store
+            .get_swarm(info_hash)
+            .await
+            .unwrap()
+            .leechers
+            .contains(&peer2));
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_regular_announce_upserts() {
+        let peer_store = PeerStore::new();
+        let (torrent_store, stats) = (TorrentStore::default(), GlobalStatistics::new());
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = |peer_id: &[u8; 20]| {
+            Peer::V4(Peerv4 {
+                peer_id: PeerId(*peer_id),
+                ip: Ipv4Addr::LOCALHOST,
+                port: 6893,
+                last_announced: Instant::now(),
+                user_id: None,
+                uploaded: 0,
+                downloaded: 0,
+            })
+        };
+
+        // A client the tracker never saw start is added anyway
+        let leecher = peer(b"-TR2940-000000000001");
         assert_eq!(
             peer_store
-                .records
-                .read()
+                .announce(
+                    info_hash,
+                    leecher.clone(),
+                    &Event::None,
+                    1000,
+                    &torrent_store,
+                    &stats
+                )
                 .await
-                .get(&info_hash)
-                .unwrap()
-                .seeders
-                .contains(&peer),
-            true
+                .transition,
+            Transition::new(PeerState::Absent, PeerState::Leeching)
+        );
+
+        // Nothing left to download makes it a seeder, completed or not
+        assert_eq!(
+            peer_store
+                .announce(
+                    info_hash,
+                    leecher.clone(),
+                    &Event::None,
+                    0,
+                    &torrent_store,
+                    &stats
+                )
+                .await
+                .transition,
+            Transition::new(PeerState::Leeching, PeerState::Seeding)
+        );
+
+        // Starting with the whole torrent joins the seeders straight away
+        let seeder = peer(b"-TR2940-000000000002");
+        assert_eq!(
+            peer_store
+                .announce(
+                    info_hash,
+                    seeder.clone(),
+                    &Event::Started,
+                    0,
+                    &torrent_store,
+                    &stats
+                )
+                .await
+                .transition,
+            Transition::new(PeerState::Absent, PeerState::Seeding)
         );
+
+        let swarm = peer_store.get_swarm(info_hash).await.unwrap();
+        assert!(swarm.seeders.contains(&leecher) && swarm.seeders.contains(&seeder));
+        assert!(swarm.leechers.is_empty());
     }
 
     #[tokio::test]
-    async fn memory_peer_storage_update_peer() {
+    async fn memory_peer_storage_announce_repeated_events() {
         let peer_store = PeerStore::new();
-        let info_hash = "A1B2C3D4E5F6G7H8I9J0".to_string();
+        let (torrent_store, stats) = (TorrentStore::default(), GlobalStatistics::new());
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
         let peer = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+            peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
 
+        // Stopping a peer that was never there changes nothing
+        assert_eq!(
+            peer_store
+                .announce(
+                    info_hash,
+                    peer.clone(),
+                    &Event::Stopped,
+                    0,
+                    &torrent_store,
+                    &stats
+                )
+                .await
+                .transition,
+            Transition::new(PeerState::Absent, PeerState::Absent)
+        );
+        assert!(peer_store.get_swarm(info_hash).await.is_none());
+
         peer_store
-            .put_leecher(info_hash.clone(), peer.clone())
+            .announce(
+                info_hash,
+                peer.clone(),
+                &Event::Started,
+                1000,
+                &torrent_store,
+                &stats,
+            )
             .await;
+        assert_eq!(
+            peer_store
+                .announce(
+                    info_hash,
+                    peer.clone(),
+                    &Event::Started,
+                    1000,
+                    &torrent_store,
+                    &stats
+                )
+                .await
+                .transition,
+            Transition::new(PeerState::Leeching, PeerState::Leeching)
+        );
+        assert_eq!(
+            peer_store
+                .get_swarm(info_hash)
+                .await
+                .unwrap()
+                .leechers
+                .len(),
+            1
+        );
+    }
 
-        let peer2 = Peer::V4(Peerv4 {
-            peer_id: "ABCDEFGHIJKLMNOPQRST".to_string(),
+    #[tokio::test]
+    async fn peer_store_reconcile_holds_one_shard_at_a_time() {
+        let peer_store = PeerStore::new();
+        let (torrent_store, stats) = (TorrentStore::default(), GlobalStatistics::new());
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let last = peer_store.shards().len() - 1;
+        assert_ne!(shard_index(&info_hash, peer_store.shards().len()), last);
+        torrent_store.register(info_hash, 1).await;
+
+        // Reconciling stops at the last shard while something else holds it
+        let held = peer_store.shards()[last].write().await;
+        let mut reconcile = Box::pin(peer_store.reconcile(&torrent_store, &stats));
+        assert!(futures::poll!(&mut reconcile).is_pending());
+
+        // Shards it has already been through are free for announces
+        let peer = Peer::V4(Peerv4 {
+            peer_id: PeerId(*b"-TR2940-000000000001"),
             ip: Ipv4Addr::LOCALHOST,
             port: 6893,
             last_announced: Instant::now(),
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
         });
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            peer_store.announce(
+                info_hash,
+                peer,
+                &Event::Started,
+                1000,
+                &torrent_store,
+                &stats,
+            ),
+        )
+        .await
+        .unwrap();
+
+        drop(held);
+        reconcile.await;
+        assert_eq!(torrent_store.get_announce_stats(info_hash).await, (0, 1));
+        assert_eq!((stats.total_seeders(), stats.total_leechers()), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn peer_store_expire_skips_refreshed_peers() {
+        let peer_store = PeerStore::new();
+        let (torrent_store, stats) = (TorrentStore::default(), GlobalStatistics::new());
+        let info_hash1 = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let info_hash2 = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let peer = |peer_id: &[u8; 20], last_announced: Instant| {
+            Peer::V4(Peerv4 {
+                peer_id: PeerId(*peer_id),
+                ip: Ipv4Addr::LOCALHOST,
+                port: 6893,
+                last_announced,
+                user_id: None,
+                uploaded: 0,
+                downloaded: 0,
+            })
+        };
 
+        let stale = Instant::now() - Duration::from_secs(100);
+        peer_store
+            .put_seeder(info_hash1, peer(b"-TR2940-000000000001", stale))
+            .await;
         peer_store
-            .update_peer(info_hash.clone(), peer2.clone())
+            .put_leecher(info_hash2, peer(b"-TR2940-000000000002", stale))
+            .await;
+        peer_store
+            .announce(
+                info_hash2,
+                peer(b"-TR2940-000000000002", Instant::now()),
+                &Event::None,
+                1000,
+                &torrent_store,
+                &stats,
+            )
             .await;
 
+        let expired = peer_store
+            .expire(
+                Instant::now() - Duration::from_secs(50),
+                &torrent_store,
+                &stats,
+            )
+            .await;
+        assert_eq!(
+            expired,
+            vec![Expired {
+                info_hash: info_hash1,
+                seeders: 1,
+                leechers: 0,
+                remaining: (0, 0),
+            }]
+        );
+        assert!(peer_store.get_swarm(info_hash1).await.is_none());
         assert_eq!(
             peer_store
-                .records
-                .read()
+                .get_swarm(info_hash2)
                 .await
-                .get(&info_hash)
                 .unwrap()
                 .leechers
-                .contains(&peer2),
-            true
+                .len(),
+            1
         );
     }
+
+    #[actix_rt::test]
+    async fn user_store_get_user_skips_revoked() {
+        let mut users = UserRecords::new();
+        for user in [
+            User::new(1, "a1b2c3d4e5f6".to_string(), false),
+            User::new(2, "f6e5d4c3b2a1".to_string(), true),
+        ] {
+            users.insert(user.passkey.clone(), user);
+        }
+        let user_store = UserStore::new(users);
+
+        assert_eq!(user_store.get_user("a1b2c3d4e5f6").await.unwrap().id, 1);
+        assert!(user_store.get_user("f6e5d4c3b2a1").await.is_none());
+        assert!(user_store.get_user("000000000000").await.is_none());
+
+        // A reload from the backend drops users that have since been removed
+        user_store.replace(UserRecords::new()).await;
+        assert!(user_store.get_user("a1b2c3d4e5f6").await.is_none());
+    }
+
+    #[test]
+    fn counter_delta_survives_resets() {
+        assert_eq!(counter_delta(1000, 1500), 500);
+        assert_eq!(counter_delta(1500, 1500), 0);
+
+        // A client that restarted reports everything since the restart
+        assert_eq!(counter_delta(1500, 200), 200);
+        assert_eq!(counter_delta(1500, 0), 0);
+
+        // Totals well past 4 GiB
+        assert_eq!(counter_delta(4 << 40, 6 << 40), 2 << 40);
+    }
+
+    #[tokio::test]
+    async fn torrent_store_balance_passes_four_gib() {
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut torrents = TorrentRecords::new();
+        torrents.insert(
+            info_hash,
+            Torrent::new(info_hash, 0, 0, 0, u64::from(u32::MAX)),
+        );
+        let torrent_store = TorrentStore::new(torrents);
+
+        torrent_store.add_traffic(info_hash, 3 << 40, 2 << 40).await;
+        let balance = torrent_store.torrents.read().await[&info_hash].balance;
+        assert_eq!(balance, u64::from(u32::MAX) + (5 << 40));
+
+        // Saturates rather than wrapping around
+        torrent_store.add_traffic(info_hash, u64::MAX, 0).await;
+        let balance = torrent_store.torrents.read().await[&info_hash].balance;
+        assert_eq!(balance, u64::MAX);
+    }
+
+    #[tokio::test]
+    async fn memory_peer_storage_transfer_delta() {
+        let peer_store = PeerStore::new();
+        let (torrent_store, stats) = (TorrentStore::default(), GlobalStatistics::new());
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer = |uploaded, downloaded| {
+            Peer::V4(Peerv4 {
+                peer_id: PeerId(*b"ABCDEFGHIJKLMNOPQRST"),
+                ip: Ipv4Addr::LOCALHOST,
+                port: 6893,
+                last_announced: Instant::now(),
+                user_id: None,
+                uploaded,
+                downloaded,
+            })
+        };
+        let announce = |peer, event, left| {
+            peer_store.announce(info_hash, peer, event, left, &torrent_store, &stats)
+        };
+        let traffic = |announced: Announced| (announced.uploaded, announced.downloaded);
+
+        // Unknown peers only set the baseline
+        assert_eq!(
+            traffic(announce(peer(500, 500), &Event::Started, 1000).await),
+            (0, 0)
+        );
+
+        // A client retrying the same announce has its traffic counted once
+        let (first, second) = futures::join!(
+            announce(peer(800, 600), &Event::None, 1000),
+            announce(peer(800, 600), &Event::None, 1000)
+        );
+        let (first, second) = (traffic(first), traffic(second));
+        assert_eq!((first.0 + second.0, first.1 + second.1), (300, 100));
+
+        // Completing carries the latest totals over to the seeders
+        assert_eq!(
+            traffic(announce(peer(900, 1000), &Event::Completed, 0).await),
+            (100, 400)
+        );
+        assert_eq!(
+            traffic(announce(peer(900, 1000), &Event::None, 0).await),
+            (0, 0)
+        );
+
+        // Client restarted and its counters with it
+        assert_eq!(
+            traffic(announce(peer(50, 0), &Event::None, 0).await),
+            (50, 0)
+        );
+
+        // A stopping peer's final totals still count
+        assert_eq!(
+            traffic(announce(peer(70, 0), &Event::Stopped, 0).await),
+            (20, 0)
+        );
+        assert!(peer_store.get_swarm(info_hash).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn user_store_transfers_accumulate() {
+        let user_store = UserStore::default();
+        user_store.add_transfer(1, 100, 10).await;
+        user_store.add_transfer(1, 50, 5).await;
+        user_store.add_transfer(2, 1, 1).await;
+
+        let mut transfers = user_store.take_transfers().await;
+        transfers.sort_by_key(|t| t.user_id);
+        assert_eq!(transfers.len(), 2);
+        assert_eq!((transfers[0].uploaded, transfers[0].downloaded), (150, 15));
+        assert!(user_store.take_transfers().await.is_empty());
+
+        // Transfers that failed to flush are merged with anything new
+        user_store.restore_transfers(transfers).await;
+        user_store.add_transfer(1, 1, 1).await;
+        let transfers = user_store.take_transfers().await;
+        let user1 = transfers.iter().find(|t| t.user_id == 1).unwrap();
+        assert_eq!((user1.uploaded, user1.downloaded), (151, 16));
+    }
+
+    #[tokio::test]
+    async fn torrent_store_register_respects_limit() {
+        let info_hash1 = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let info_hash2 = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let torrent_store = TorrentStore::default();
+
+        assert!(torrent_store.register(info_hash1, 1).await);
+        torrent_store
+            .apply(
+                info_hash1,
+                Transition::new(PeerState::Absent, PeerState::Leeching),
+            )
+            .await;
+
+        // Known torrents are left as they are, even at the limit
+        assert!(torrent_store.register(info_hash1, 1).await);
+        assert_eq!(torrent_store.get_announce_stats(info_hash1).await, (0, 1));
+
+        assert!(!torrent_store.register(info_hash2, 1).await);
+        assert_eq!(torrent_store.torrents.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn peer_store_spreads_swarms_over_shards() {
+        let peer_store = PeerStore::new();
+        for i in 0..=255u8 {
+            let peer = Peer::V4(Peerv4 {
+                peer_id: PeerId(*b"-TR2940-000000000001"),
+                ip: Ipv4Addr::new(10, 0, 0, i),
+                port: 6881,
+                last_announced: Instant::now(),
+                user_id: None,
+                uploaded: 0,
+                downloaded: 0,
+            });
+            peer_store.put_leecher(InfoHash([i; 20]), peer).await;
+        }
+
+        for i in 0..=255u8 {
+            let swarm = peer_store.get_swarm(InfoHash([i; 20])).await.unwrap();
+            assert_eq!(swarm.leechers.len(), 1);
+        }
+
+        let mut used_shards = 0;
+        for shard in peer_store.shards() {
+            let records = &shard.read().await.records;
+            if !records.is_empty() {
+                used_shards += 1;
+                assert_eq!(records.len(), 256 / NUM_SHARDS);
+            }
+        }
+        assert_eq!(used_shards, NUM_SHARDS);
+    }
+
+    // Not a correctness test; compares announce throughput with a single
+    // lock against the sharded store. Run it in release mode with
+    // cargo test --release peer_store_throughput -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn peer_store_throughput() {
+        const THREADS: usize = 8;
+        const SWARMS: usize = 4096;
+        const ANNOUNCES: usize = 100_000;
+
+        let info_hashes: Arc<Vec<InfoHash>> =
+            Arc::new((0..SWARMS).map(|_| InfoHash(rand::random())).collect());
+
+        for &num_shards in &[1, NUM_SHARDS] {
+            let peer_store = PeerStore::with_shards(num_shards);
+            let started = Instant::now();
+
+            let workers: Vec<_> = (0..THREADS)
+                .map(|thread| {
+                    let peer_store = peer_store.clone();
+                    let info_hashes = info_hashes.clone();
+                    std::thread::spawn(move || {
+                        futures::executor::block_on(async {
+                            for i in 0..ANNOUNCES {
+                                let info_hash = info_hashes[(i * THREADS + thread) % SWARMS];
+                                let peer = Peer::V4(Peerv4 {
+                                    peer_id: PeerId(*b"-TR2940-000000000001"),
+                                    ip: Ipv4Addr::new(10, thread as u8, 0, (i % 50) as u8),
+                                    port: 6881,
+                                    last_announced: Instant::now(),
+                                    user_id: None,
+                                    uploaded: 0,
+                                    downloaded: 0,
+                                });
+                                peer_store.put_leecher(info_hash, peer).await;
+                                peer_store.get_peers(info_hash, 30).await;
+                            }
+                        })
+                    })
+                })
+                .collect();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+
+            let elapsed = started.elapsed().as_secs_f64();
+            println!(
+                "{} shard(s): {:.0} announces/s",
+                num_shards,
+                (THREADS * ANNOUNCES) as f64 / elapsed
+            );
+        }
+    }
 }
@@ -1,54 +1,314 @@
-use crate::storage;
+use crate::bittorrent::InfoHash;
+use crate::errors::InternalError;
+use crate::storage::{
+    Snatch, Snatcher, Torrent, TorrentBackend, TorrentRecords, User, UserRecords, UserTransfer,
+};
 use mysql::prelude::*;
 use mysql::*;
+use std::collections::HashSet;
 
-pub fn get_torrents(pool: Pool) -> Result<storage::TorrentRecords> {
-    let mut conn = pool.get_conn()?;
+pub struct MySqlBackend {
+    pool: Pool,
+}
+
+impl MySqlBackend {
+    // The password is kept out of the database URL so that
+    // it doesn't end up in the logs alongside the path
+    pub fn new(path: &str, password: Option<String>) -> Result<MySqlBackend> {
+        let opts = OptsBuilder::from_opts(Opts::from_url(path)?);
+        let opts = match password {
+            Some(password) => opts.pass(Some(password)),
+            None => opts,
+        };
+
+        Ok(MySqlBackend {
+            pool: Pool::new(opts)?,
+        })
+    }
+
+    fn query_torrents(&self) -> Result<TorrentRecords> {
+        let mut conn = self.pool.get_conn()?;
+
+        let mut torrents = TorrentRecords::new();
+
+        // Info hashes are stored in their hex form; rows
+        // that can't be decoded are skipped rather than fatal
+        let selected_torrents = conn.query_map(
+            "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents",
+            |(info_hash, complete, downloaded, incomplete, balance): (Vec<u8>, _, _, _, _)| {
+                match InfoHash::from_bytes(&info_hash) {
+                    Some(info_hash) => Some(Torrent {
+                        info_hash,
+                        complete,
+                        downloaded,
+                        incomplete,
+                        balance,
+                    }),
+                    None => {
+                        warn!(
+                            "Skipping torrent with invalid info hash: {}",
+                            String::from_utf8_lossy(&info_hash)
+                        );
+                        None
+                    }
+                }
+            },
+        )?;
+
+        for sel in selected_torrents.into_iter().flatten() {
+            torrents.insert(sel.info_hash, sel);
+        }
+
+        Ok(torrents)
+    }
+
+    // Older releases stored info hashes as the raw 20 bytes sent by
+    // clients, which flushing in hex would otherwise duplicate. Those
+    // rows are rewritten in place, or dropped if a hex row already
+    // exists since that one was flushed more recently.
+    fn rewrite_legacy_info_hashes(&self) -> Result<usize> {
+        let mut conn = self.pool.get_conn()?;
+        let stored: Vec<Vec<u8>> = conn.query("SELECT info_hash FROM torrents")?;
+        let hex: HashSet<&[u8]> = stored
+            .iter()
+            .filter(|info_hash| info_hash.len() == 40)
+            .map(|info_hash| info_hash.as_slice())
+            .collect();
+
+        let mut tx = conn.start_transaction(TxOpts::default())?;
+        let mut rewritten = 0;
+
+        for raw in stored.iter().filter(|info_hash| info_hash.len() == 20) {
+            let info_hash = InfoHash::from_bytes(raw).unwrap().to_string();
+
+            if hex.contains(info_hash.as_bytes()) {
+                tx.exec_drop(
+                    "DELETE FROM torrents WHERE info_hash = :raw",
+                    params! { "raw" => raw },
+                )?;
+            } else {
+                tx.exec_drop(
+                    "UPDATE torrents SET info_hash = :info_hash WHERE info_hash = :raw",
+                    params! { "info_hash" => info_hash, "raw" => raw },
+                )?;
+            }
+
+            rewritten += 1;
+        }
+
+        tx.commit()?;
+        Ok(rewritten)
+    }
+
+    fn upsert_torrents(&self, torrents: &[Torrent]) -> Result<()> {
+        // Flushing should be accompanied by a lock on peer and torrent records
+        let mut conn = self.pool.get_conn()?;
+
+        let params = torrents.iter().map(|torrent| {
+            params! {
+                "info_hash" => torrent.info_hash.to_string(),
+                "complete" => torrent.complete,
+                "downloaded" => torrent.downloaded,
+                "incomplete" => torrent.incomplete,
+                "balance" => torrent.balance,
+            }
+        });
+
+        conn.exec_batch(
+            r"INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance)
+                        VALUES (:info_hash, :complete, :downloaded, :incomplete, :balance)
+                        ON DUPLICATE KEY UPDATE 
+                            complete=:complete, 
+                            downloaded=:downloaded, 
+                            incomplete=:incomplete, 
+                            balance=:balance",
+            params,
+        )?;
+
+        Ok(())
+    }
+
+    fn remove_torrents(&self, info_hashes: &[InfoHash]) -> Result<()> {
+        let mut conn = self.pool.get_conn()?;
+
+        let params = info_hashes.iter().map(|info_hash| {
+            params! {
+                "info_hash" => info_hash.to_string(),
+            }
+        });
+
+        conn.exec_batch(
+            r"DELETE FROM torrents WHERE info_hash = :info_hash",
+            params.clone(),
+        )?;
+        conn.exec_batch(r"DELETE FROM snatches WHERE info_hash = :info_hash", params)?;
+
+        Ok(())
+    }
 
-    let mut torrents = storage::TorrentRecords::new();
+    fn query_users(&self) -> Result<UserRecords> {
+        let mut conn = self.pool.get_conn()?;
 
-    let selected_torrents = conn.query_map(
-        "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents",
-        |(info_hash, complete, downloaded, incomplete, balance)| storage::Torrent {
-            info_hash,
-            complete,
-            downloaded,
-            incomplete,
-            balance,
-        },
-    )?;
+        let users = conn.query_map(
+            "SELECT id, passkey, revoked, uploaded, downloaded FROM users",
+            |(id, passkey, revoked, uploaded, downloaded): (_, String, _, _, _)| {
+                (
+                    passkey.clone(),
+                    User {
+                        id,
+                        passkey,
+                        revoked,
+                        uploaded,
+                        downloaded,
+                    },
+                )
+            },
+        )?;
 
-    for sel in selected_torrents {
-        torrents.insert(sel.info_hash.clone(), sel);
+        Ok(users.into_iter().collect())
     }
 
-    Ok(torrents)
+    fn add_user_transfers(&self, transfers: &[UserTransfer]) -> Result<()> {
+        let mut conn = self.pool.get_conn()?;
+
+        let params = transfers.iter().map(|transfer| {
+            params! {
+                "id" => transfer.user_id,
+                "uploaded" => transfer.uploaded,
+                "downloaded" => transfer.downloaded,
+            }
+        });
+
+        conn.exec_batch(
+            r"UPDATE users SET
+                        uploaded = uploaded + :uploaded,
+                        downloaded = downloaded + :downloaded
+                        WHERE id = :id",
+            params,
+        )?;
+
+        Ok(())
+    }
+
+    fn query_snatches(&self) -> Result<Vec<Snatch>> {
+        let mut conn = self.pool.get_conn()?;
+
+        let snatches = conn.query_map(
+            "SELECT info_hash, kind, user_id, peer_id, completed_at FROM snatches",
+            |(info_hash, kind, user_id, peer_id, completed_at): (String, String, _, String, _)| {
+                match (
+                    InfoHash::from_bytes(info_hash.as_bytes()),
+                    Snatcher::from_columns(&kind, user_id, &peer_id),
+                ) {
+                    (Some(info_hash), Some(snatcher)) => Some(Snatch {
+                        info_hash,
+                        snatcher,
+                        completed_at,
+                    }),
+                    _ => {
+                        warn!("Skipping invalid snatch row: {}", info_hash);
+                        None
+                    }
+                }
+            },
+        )?;
+
+        Ok(snatches.into_iter().flatten().collect())
+    }
+
+    fn insert_snatches(&self, snatches: &[Snatch]) -> Result<()> {
+        let mut conn = self.pool.get_conn()?;
+
+        let params = snatches.iter().map(|snatch| {
+            let (kind, user_id, peer_id) = snatch.snatcher.to_columns();
+            params! {
+                "info_hash" => snatch.info_hash.to_string(),
+                "kind" => kind,
+                "user_id" => user_id,
+                "peer_id" => peer_id,
+                "completed_at" => snatch.completed_at,
+            }
+        });
+
+        conn.exec_batch(
+            r"INSERT IGNORE INTO snatches (info_hash, kind, user_id, peer_id, completed_at)
+                        VALUES (:info_hash, :kind, :user_id, :peer_id, :completed_at)",
+            params,
+        )?;
+
+        Ok(())
+    }
 }
 
-pub fn flush_torrents(pool: Pool, torrents: Vec<storage::Torrent>) -> Result<()> {
-    // Flushing should be accompanied by a lock on peer and torrent records
-    let mut conn = pool.get_conn()?;
-
-    let params = torrents.iter().map(|torrent| {
-        params! {
-            "info_hash" => &torrent.info_hash,
-            "complete" => torrent.complete,
-            "downloaded" => torrent.downloaded,
-            "incomplete" => torrent.incomplete,
-            "balance" => torrent.balance,
+impl TorrentBackend for MySqlBackend {
+    fn load_torrents(&self) -> std::result::Result<TorrentRecords, InternalError> {
+        match self.rewrite_legacy_info_hashes() {
+            Ok(0) => {}
+            Ok(rewritten) => info!("Rewrote {} legacy info hashes to hex", rewritten),
+            Err(e) => {
+                error!("MySQL: {}", e);
+                return Err(InternalError::StorageTorrentLoad);
+            }
         }
-    });
-
-    conn.exec_batch(
-        r"INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance)
-                    VALUES (:info_hash, :complete, :downloaded, :incomplete, :balance)
-                    ON DUPLICATE KEY UPDATE 
-                        complete=:complete, 
-                        downloaded=:downloaded, 
-                        incomplete=:incomplete, 
-                        balance=:balance",
-        params,
-    )?;
-
-    Ok(())
+
+        self.query_torrents().map_err(|e| {
+            error!("MySQL: {}", e);
+            InternalError::StorageTorrentLoad
+        })
+    }
+
+    fn flush_torrents(&self, torrents: Vec<Torrent>) -> std::result::Result<(), InternalError> {
+        self.upsert_torrents(&torrents).map_err(|e| {
+            error!("MySQL: {}", e);
+            InternalError::StorageTorrentFlush
+        })
+    }
+
+    fn fetch_new_torrents(&self) -> std::result::Result<TorrentRecords, InternalError> {
+        self.query_torrents().map_err(|e| {
+            error!("MySQL: {}", e);
+            InternalError::StorageTorrentFetchNew
+        })
+    }
+
+    fn delete_torrents(
+        &self,
+        info_hashes: Vec<InfoHash>,
+    ) -> std::result::Result<(), InternalError> {
+        self.remove_torrents(&info_hashes).map_err(|e| {
+            error!("MySQL: {}", e);
+            InternalError::StorageTorrentDelete
+        })
+    }
+
+    fn load_users(&self) -> std::result::Result<UserRecords, InternalError> {
+        self.query_users().map_err(|e| {
+            error!("MySQL: {}", e);
+            InternalError::StorageUserLoad
+        })
+    }
+
+    fn flush_user_transfers(
+        &self,
+        transfers: Vec<UserTransfer>,
+    ) -> std::result::Result<(), InternalError> {
+        self.add_user_transfers(&transfers).map_err(|e| {
+            error!("MySQL: {}", e);
+            InternalError::StorageUserFlush
+        })
+    }
+
+    fn load_snatches(&self) -> std::result::Result<Vec<Snatch>, InternalError> {
+        self.query_snatches().map_err(|e| {
+            error!("MySQL: {}", e);
+            InternalError::StorageSnatchLoad
+        })
+    }
+
+    fn flush_snatches(&self, snatches: Vec<Snatch>) -> std::result::Result<(), InternalError> {
+        self.insert_snatches(&snatches).map_err(|e| {
+            error!("MySQL: {}", e);
+            InternalError::StorageSnatchFlush
+        })
+    }
 }
@@ -0,0 +1,415 @@
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+use postgres::{Client, Config, Error, NoTls};
+
+use crate::bittorrent::InfoHash;
+use crate::errors::InternalError;
+use crate::storage::{
+    Snatch, Snatcher, Torrent, TorrentBackend, TorrentRecords, User, UserRecords, UserTransfer,
+};
+
+// Same columns as the MySQL schema in schema.sql; Postgres has no
+// unsigned integers, so the counters are kept in BIGINT columns.
+const CREATE_TORRENTS: &str = "CREATE TABLE IF NOT EXISTS torrents (
+        info_hash VARCHAR(50) NOT NULL PRIMARY KEY,
+        complete BIGINT NOT NULL,
+        downloaded BIGINT NOT NULL,
+        incomplete BIGINT NOT NULL,
+        balance BIGINT NOT NULL
+)";
+
+const CREATE_USERS: &str = "CREATE TABLE IF NOT EXISTS users (
+        id BIGINT NOT NULL PRIMARY KEY,
+        passkey VARCHAR(32) NOT NULL UNIQUE,
+        revoked BOOLEAN NOT NULL DEFAULT FALSE,
+        uploaded BIGINT NOT NULL DEFAULT 0,
+        downloaded BIGINT NOT NULL DEFAULT 0
+)";
+
+const CREATE_SNATCHES: &str = "CREATE TABLE IF NOT EXISTS snatches (
+        info_hash VARCHAR(50) NOT NULL,
+        kind VARCHAR(4) NOT NULL,
+        user_id BIGINT NOT NULL DEFAULT 0,
+        peer_id VARCHAR(40) NOT NULL DEFAULT '',
+        completed_at BIGINT NOT NULL,
+        PRIMARY KEY (info_hash, kind, user_id, peer_id)
+)";
+
+// The synchronous client holds a single connection,
+// so every call takes its turn on it.
+pub struct PostgresBackend {
+    client: Mutex<Client>,
+}
+
+impl PostgresBackend {
+    // The password is kept out of the database URL so that
+    // it doesn't end up in the logs alongside the path
+    pub fn new(path: &str, password: Option<String>) -> Result<PostgresBackend, Error> {
+        let mut config: Config = path.parse()?;
+        if let Some(password) = password {
+            config.password(password);
+        }
+
+        let mut client = config.connect(NoTls)?;
+        client.batch_execute(CREATE_TORRENTS)?;
+        client.batch_execute(CREATE_USERS)?;
+        client.batch_execute(CREATE_SNATCHES)?;
+
+        Ok(PostgresBackend {
+            client: Mutex::new(client),
+        })
+    }
+
+    fn query_torrents(client: &mut Client) -> Result<TorrentRecords, Error> {
+        let mut torrents = TorrentRecords::new();
+
+        let rows = client.query(
+            "SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents",
+            &[],
+        )?;
+
+        // Info hashes are stored in their hex form; rows that can't
+        // be decoded or don't fit the counters are skipped rather than fatal
+        for row in rows {
+            let info_hash: String = row.get(0);
+            let counters = (
+                u32::try_from(row.get::<_, i64>(1)),
+                u32::try_from(row.get::<_, i64>(2)),
+                u32::try_from(row.get::<_, i64>(3)),
+                u64::try_from(row.get::<_, i64>(4)),
+            );
+
+            match (InfoHash::from_bytes(info_hash.as_bytes()), counters) {
+                (Some(info_hash), (Ok(complete), Ok(downloaded), Ok(incomplete), Ok(balance))) => {
+                    torrents.insert(
+                        info_hash,
+                        Torrent {
+                            info_hash,
+                            complete,
+                            downloaded,
+                            incomplete,
+                            balance,
+                        },
+                    );
+                }
+                _ => warn!("Skipping invalid torrent row: {}", info_hash),
+            }
+        }
+
+        Ok(torrents)
+    }
+
+    fn upsert_torrents(client: &mut Client, torrents: &[Torrent]) -> Result<(), Error> {
+        let mut tx = client.transaction()?;
+
+        let stmt = tx.prepare(
+            "INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance)
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (info_hash) DO UPDATE SET
+                        complete=EXCLUDED.complete,
+                        downloaded=EXCLUDED.downloaded,
+                        incomplete=EXCLUDED.incomplete,
+                        balance=EXCLUDED.balance",
+        )?;
+
+        for torrent in torrents {
+            tx.execute(
+                &stmt,
+                &[
+                    &torrent.info_hash.to_string(),
+                    &i64::from(torrent.complete),
+                    &i64::from(torrent.downloaded),
+                    &i64::from(torrent.incomplete),
+                    &i64::try_from(torrent.balance).unwrap_or(i64::MAX),
+                ],
+            )?;
+        }
+
+        tx.commit()
+    }
+
+    fn remove_torrents(client: &mut Client, info_hashes: &[InfoHash]) -> Result<(), Error> {
+        let mut tx = client.transaction()?;
+
+        let stmt = tx.prepare("DELETE FROM torrents WHERE info_hash = $1")?;
+        let snatches = tx.prepare("DELETE FROM snatches WHERE info_hash = $1")?;
+        for info_hash in info_hashes {
+            tx.execute(&stmt, &[&info_hash.to_string()])?;
+            tx.execute(&snatches, &[&info_hash.to_string()])?;
+        }
+
+        tx.commit()
+    }
+
+    fn query_users(client: &mut Client) -> Result<UserRecords, Error> {
+        let mut users = UserRecords::new();
+
+        let rows = client.query(
+            "SELECT id, passkey, revoked, uploaded, downloaded FROM users",
+            &[],
+        )?;
+
+        for row in rows {
+            let passkey: String = row.get(1);
+            match (
+                u32::try_from(row.get::<_, i64>(0)),
+                u64::try_from(row.get::<_, i64>(3)),
+                u64::try_from(row.get::<_, i64>(4)),
+            ) {
+                (Ok(id), Ok(uploaded), Ok(downloaded)) => {
+                    users.insert(
+                        passkey.clone(),
+                        User {
+                            id,
+                            passkey,
+                            revoked: row.get(2),
+                            uploaded,
+                            downloaded,
+                        },
+                    );
+                }
+                _ => warn!("Skipping invalid user row: {}", passkey),
+            }
+        }
+
+        Ok(users)
+    }
+
+    fn add_user_transfers(client: &mut Client, transfers: &[UserTransfer]) -> Result<(), Error> {
+        let mut tx = client.transaction()?;
+
+        let stmt = tx.prepare(
+            "UPDATE users SET
+                    uploaded = uploaded + $2,
+                    downloaded = downloaded + $3
+                    WHERE id = $1",
+        )?;
+        for transfer in transfers {
+            tx.execute(
+                &stmt,
+                &[
+                    &i64::from(transfer.user_id),
+                    &i64::try_from(transfer.uploaded).unwrap_or(i64::MAX),
+                    &i64::try_from(transfer.downloaded).unwrap_or(i64::MAX),
+                ],
+            )?;
+        }
+
+        tx.commit()
+    }
+
+    fn query_snatches(client: &mut Client) -> Result<Vec<Snatch>, Error> {
+        let rows = client.query(
+            "SELECT info_hash, kind, user_id, peer_id, completed_at FROM snatches",
+            &[],
+        )?;
+
+        let mut snatches = Vec::new();
+        for row in rows {
+            let info_hash: String = row.get(0);
+            let kind: String = row.get(1);
+            let peer_id: String = row.get(3);
+            let snatcher = u32::try_from(row.get::<_, i64>(2))
+                .ok()
+                .and_then(|user_id| Snatcher::from_columns(&kind, user_id, &peer_id));
+
+            match (
+                InfoHash::from_bytes(info_hash.as_bytes()),
+                snatcher,
+                u64::try_from(row.get::<_, i64>(4)),
+            ) {
+                (Some(info_hash), Some(snatcher), Ok(completed_at)) => snatches.push(Snatch {
+                    info_hash,
+                    snatcher,
+                    completed_at,
+                }),
+                _ => warn!("Skipping invalid snatch row: {}", info_hash),
+            }
+        }
+
+        Ok(snatches)
+    }
+
+    fn insert_snatches(client: &mut Client, snatches: &[Snatch]) -> Result<(), Error> {
+        let mut tx = client.transaction()?;
+
+        let stmt = tx.prepare(
+            "INSERT INTO snatches (info_hash, kind, user_id, peer_id, completed_at)
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT DO NOTHING",
+        )?;
+        for snatch in snatches {
+            let (kind, user_id, peer_id) = snatch.snatcher.to_columns();
+            tx.execute(
+                &stmt,
+                &[
+                    &snatch.info_hash.to_string(),
+                    &kind,
+                    &i64::from(user_id),
+                    &peer_id,
+                    &i64::try_from(snatch.completed_at).unwrap_or(i64::MAX),
+                ],
+            )?;
+        }
+
+        tx.commit()
+    }
+}
+
+impl TorrentBackend for PostgresBackend {
+    fn load_torrents(&self) -> Result<TorrentRecords, InternalError> {
+        let mut client = self
+            .client
+            .lock()
+            .map_err(|_| InternalError::StorageTorrentLoad)?;
+
+        PostgresBackend::query_torrents(&mut client).map_err(|e| {
+            error!("PostgreSQL: {}", e);
+            InternalError::StorageTorrentLoad
+        })
+    }
+
+    fn flush_torrents(&self, torrents: Vec<Torrent>) -> Result<(), InternalError> {
+        let mut client = self
+            .client
+            .lock()
+            .map_err(|_| InternalError::StorageTorrentFlush)?;
+
+        PostgresBackend::upsert_torrents(&mut client, &torrents).map_err(|e| {
+            error!("PostgreSQL: {}", e);
+            InternalError::StorageTorrentFlush
+        })
+    }
+
+    fn fetch_new_torrents(&self) -> Result<TorrentRecords, InternalError> {
+        let mut client = self
+            .client
+            .lock()
+            .map_err(|_| InternalError::StorageTorrentFetchNew)?;
+
+        PostgresBackend::query_torrents(&mut client).map_err(|e| {
+            error!("PostgreSQL: {}", e);
+            InternalError::StorageTorrentFetchNew
+        })
+    }
+
+    fn delete_torrents(&self, info_hashes: Vec<InfoHash>) -> Result<(), InternalError> {
+        let mut client = self
+            .client
+            .lock()
+            .map_err(|_| InternalError::StorageTorrentDelete)?;
+
+        PostgresBackend::remove_torrents(&mut client, &info_hashes).map_err(|e| {
+            error!("PostgreSQL: {}", e);
+            InternalError::StorageTorrentDelete
+        })
+    }
+
+    fn load_users(&self) -> Result<UserRecords, InternalError> {
+        let mut client = self
+            .client
+            .lock()
+            .map_err(|_| InternalError::StorageUserLoad)?;
+
+        PostgresBackend::query_users(&mut client).map_err(|e| {
+            error!("PostgreSQL: {}", e);
+            InternalError::StorageUserLoad
+        })
+    }
+
+    fn flush_user_transfers(&self, transfers: Vec<UserTransfer>) -> Result<(), InternalError> {
+        let mut client = self
+            .client
+            .lock()
+            .map_err(|_| InternalError::StorageUserFlush)?;
+
+        PostgresBackend::add_user_transfers(&mut client, &transfers).map_err(|e| {
+            error!("PostgreSQL: {}", e);
+            InternalError::StorageUserFlush
+        })
+    }
+
+    fn load_snatches(&self) -> Result<Vec<Snatch>, InternalError> {
+        let mut client = self
+            .client
+            .lock()
+            .map_err(|_| InternalError::StorageSnatchLoad)?;
+
+        PostgresBackend::query_snatches(&mut client).map_err(|e| {
+            error!("PostgreSQL: {}", e);
+            InternalError::StorageSnatchLoad
+        })
+    }
+
+    fn flush_snatches(&self, snatches: Vec<Snatch>) -> Result<(), InternalError> {
+        let mut client = self
+            .client
+            .lock()
+            .map_err(|_| InternalError::StorageSnatchFlush)?;
+
+        PostgresBackend::insert_snatches(&mut client, &snatches).map_err(|e| {
+            error!("PostgreSQL: {}", e);
+            InternalError::StorageSnatchFlush
+        })
+    }
+}
+
+// These need a running server, so they are ignored by default. Run them
+// with TYTO_TEST_POSTGRES holding a connection URL for a scratch database:
+// cargo test postgres_backend -- --ignored
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::conformance;
+
+    fn test_backend() -> PostgresBackend {
+        let path = std::env::var("TYTO_TEST_POSTGRES")
+            .expect("TYTO_TEST_POSTGRES must be set to a scratch database URL");
+        let backend = PostgresBackend::new(&path, None).unwrap();
+        backend
+            .client
+            .lock()
+            .unwrap()
+            .batch_execute("TRUNCATE torrents, users, snatches")
+            .unwrap();
+        backend
+    }
+
+    // The cases share tables, so they run one after the other
+    #[test]
+    #[ignore]
+    fn postgres_backend() {
+        let backend = test_backend();
+
+        conformance::flush_and_load(&backend, &backend);
+        conformance::fetch_new_and_delete(&backend, &|info_hash| {
+            backend
+                .client
+                .lock()
+                .unwrap()
+                .execute(
+                    "INSERT INTO torrents VALUES ($1, 0, 0, 0, 0)",
+                    &[&info_hash.to_string()],
+                )
+                .unwrap();
+        });
+
+        assert!(backend.load_users().unwrap().is_empty());
+        for user in conformance::user_records().values() {
+            backend
+                .client
+                .lock()
+                .unwrap()
+                .execute(
+                    "INSERT INTO users (id, passkey, revoked) VALUES ($1, $2, $3)",
+                    &[&(user.id as i64), &user.passkey, &user.revoked],
+                )
+                .unwrap();
+        }
+        conformance::users(&backend);
+
+        conformance::snatches(&backend, &backend);
+    }
+}
@@ -0,0 +1,521 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+
+use crate::bittorrent::{InfoHash, Peer, PeerId, Peerv4, Peerv6};
+use crate::errors::InternalError;
+use crate::state::State;
+use crate::storage::{PeerRecords, PeerStore, Swarm, UserId};
+
+// Snapshots open with these bytes and the format version. Ones written
+// before the header was added are taken to be the first version, which
+// held 32-bit transfer totals, and are converted as they are loaded.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TYTOPEER";
+const SNAPSHOT_VERSION: u32 = 2;
+
+// Instants are meaningless outside of the process that made them,
+// so announce times are written as seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotPeer {
+    peer_id: PeerId,
+    ip: IpAddr,
+    port: u16,
+    last_announced: u64,
+    user_id: Option<UserId>,
+    uploaded: u64,
+    downloaded: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotSwarm {
+    info_hash: InfoHash,
+    seeders: Vec<SnapshotPeer>,
+    leechers: Vec<SnapshotPeer>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PeerSnapshot {
+    swarms: Vec<SnapshotSwarm>,
+}
+
+// The first, unversioned layout
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacySnapshotPeer {
+    peer_id: PeerId,
+    ip: IpAddr,
+    port: u16,
+    last_announced: u64,
+    user_id: Option<UserId>,
+    uploaded: u32,
+    downloaded: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacySnapshotSwarm {
+    info_hash: InfoHash,
+    seeders: Vec<LegacySnapshotPeer>,
+    leechers: Vec<LegacySnapshotPeer>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacyPeerSnapshot {
+    swarms: Vec<LegacySnapshotSwarm>,
+}
+
+impl From<LegacySnapshotPeer> for SnapshotPeer {
+    fn from(peer: LegacySnapshotPeer) -> SnapshotPeer {
+        SnapshotPeer {
+            peer_id: peer.peer_id,
+            ip: peer.ip,
+            port: peer.port,
+            last_announced: peer.last_announced,
+            user_id: peer.user_id,
+            uploaded: u64::from(peer.uploaded),
+            downloaded: u64::from(peer.downloaded),
+        }
+    }
+}
+
+impl From<LegacyPeerSnapshot> for PeerSnapshot {
+    fn from(snapshot: LegacyPeerSnapshot) -> PeerSnapshot {
+        PeerSnapshot {
+            swarms: snapshot
+                .swarms
+                .into_iter()
+                .map(|sw| SnapshotSwarm {
+                    info_hash: sw.info_hash,
+                    seeders: sw.seeders.into_iter().map(SnapshotPeer::from).collect(),
+                    leechers: sw.leechers.into_iter().map(SnapshotPeer::from).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn encode(snapshot: &PeerSnapshot) -> Result<Vec<u8>, InternalError> {
+    let mut bytes = SNAPSHOT_MAGIC.to_vec();
+    bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut bytes, snapshot).map_err(|e| {
+        error!("Peer snapshot: {}", e);
+        InternalError::StoragePeerSave
+    })?;
+    Ok(bytes)
+}
+
+// Snapshots from a newer tracker are refused rather than misread
+fn decode(bytes: &[u8]) -> Result<PeerSnapshot, InternalError> {
+    let snapshot = match bytes.strip_prefix(&SNAPSHOT_MAGIC[..]) {
+        Some(rest) if rest.len() >= 4 => {
+            let (version, rest) = rest.split_at(4);
+            let version = u32::from_le_bytes([version[0], version[1], version[2], version[3]]);
+            if version != SNAPSHOT_VERSION {
+                error!(
+                    "Peer snapshot: unsupported format version {}, expected {}",
+                    version, SNAPSHOT_VERSION
+                );
+                return Err(InternalError::StoragePeerLoad);
+            }
+            bincode::deserialize(rest)
+        }
+        Some(_) => {
+            error!("Peer snapshot: truncated header");
+            return Err(InternalError::StoragePeerLoad);
+        }
+        None => {
+            info!("Converting unversioned peer snapshot");
+            bincode::deserialize::<LegacyPeerSnapshot>(bytes).map(PeerSnapshot::from)
+        }
+    };
+
+    snapshot.map_err(|e| {
+        error!("Peer snapshot: {}", e);
+        InternalError::StoragePeerLoad
+    })
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn snapshot_peer(peer: &Peer, now: SystemTime) -> SnapshotPeer {
+    let (peer_id, ip, port, last_announced) = match peer {
+        Peer::V4(p) => (p.peer_id, IpAddr::V4(p.ip), p.port, p.last_announced),
+        Peer::V6(p) => (p.peer_id, IpAddr::V6(p.ip), p.port, p.last_announced),
+    };
+    let user_id = peer.user_id();
+    let (uploaded, downloaded) = peer.transferred();
+
+    SnapshotPeer {
+        peer_id,
+        ip,
+        port,
+        last_announced: now
+            .checked_sub(last_announced.elapsed())
+            .map(unix_secs)
+            .unwrap_or(0),
+        user_id,
+        uploaded,
+        downloaded,
+    }
+}
+
+// Peers that would have been reaped while the tracker
+// was down are dropped instead of being restored
+fn restore_peer(peer: SnapshotPeer, now: SystemTime, peer_timeout: Duration) -> Option<Peer> {
+    let age = Duration::from_secs(unix_secs(now).saturating_sub(peer.last_announced));
+    if age >= peer_timeout {
+        return None;
+    }
+
+    // An Instant can't reach back before the machine booted;
+    // in that case the peer just gets a fresh announce time
+    let last_announced = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+
+    Some(match peer.ip {
+        IpAddr::V4(ip) => Peer::V4(Peerv4 {
+            peer_id: peer.peer_id,
+            ip,
+            port: peer.port,
+            last_announced,
+            user_id: peer.user_id,
+            uploaded: peer.uploaded,
+            downloaded: peer.downloaded,
+        }),
+        IpAddr::V6(ip) => Peer::V6(Peerv6 {
+            peer_id: peer.peer_id,
+            ip,
+            port: peer.port,
+            last_announced,
+            user_id: peer.user_id,
+            uploaded: peer.uploaded,
+            downloaded: peer.downloaded,
+        }),
+    })
+}
+
+fn take_snapshot(records: &PeerRecords, now: SystemTime) -> PeerSnapshot {
+    PeerSnapshot {
+        swarms: records
+            .iter()
+            .map(|(info_hash, swarm)| SnapshotSwarm {
+                info_hash: *info_hash,
+                seeders: swarm
+                    .seeders
+                    .iter()
+                    .map(|p| snapshot_peer(p, now))
+                    .collect(),
+                leechers: swarm
+                    .leechers
+                    .iter()
+                    .map(|p| snapshot_peer(p, now))
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+// Returns the restored records along with the number of peers dropped
+fn restore_snapshot(
+    snapshot: PeerSnapshot,
+    now: SystemTime,
+    peer_timeout: Duration,
+) -> (PeerRecords, usize) {
+    let mut records = PeerRecords::new();
+    let mut dropped = 0;
+
+    for snapshot_swarm in snapshot.swarms {
+        let mut swarm = Swarm::new();
+        let total = snapshot_swarm.seeders.len() + snapshot_swarm.leechers.len();
+
+        for peer in snapshot_swarm.seeders {
+            if let Some(peer) = restore_peer(peer, now, peer_timeout) {
+                swarm.add_seeder(peer);
+            }
+        }
+        for peer in snapshot_swarm.leechers {
+            if let Some(peer) = restore_peer(peer, now, peer_timeout) {
+                swarm.add_leecher(peer);
+            }
+        }
+
+        dropped += total - swarm.seeders.len() - swarm.leechers.len();
+        if !swarm.seeders.is_empty() || !swarm.leechers.is_empty() {
+            records.insert(snapshot_swarm.info_hash, swarm);
+        }
+    }
+
+    (records, dropped)
+}
+
+// Writes every swarm to the given path and returns the number of peers saved.
+// The snapshot goes to a temporary file first so that a crash part way
+// through never leaves a truncated snapshot behind.
+pub async fn save(peer_store: &PeerStore, path: &str) -> Result<usize, InternalError> {
+    // Shards are copied one at a time rather than
+    // holding up every announce until the whole store is read
+    let now = SystemTime::now();
+    let mut snapshot = PeerSnapshot { swarms: Vec::new() };
+    for shard in peer_store.shards() {
+        snapshot
+            .swarms
+            .extend(take_snapshot(&shard.read().await.records, now).swarms);
+    }
+    let num_peers = snapshot
+        .swarms
+        .iter()
+        .map(|sw| sw.seeders.len() + sw.leechers.len())
+        .sum();
+
+    let bytes = encode(&snapshot)?;
+
+    // Writing can take a while for large swarms, so it happens on
+    // the blocking thread pool instead of stalling the event loop
+    let tmp_path = format!("{}.tmp", path);
+    let path = path.to_string();
+    web::block(move || fs::write(&tmp_path, bytes).and_then(|_| fs::rename(&tmp_path, &path)))
+        .await
+        .map_err(|e| {
+            error!("Peer snapshot: {}", e);
+            InternalError::StoragePeerSave
+        })?;
+
+    Ok(num_peers)
+}
+
+// A missing snapshot isn't an error; the tracker
+// has simply never been shut down with one configured
+pub fn load(path: &str, peer_timeout: Duration) -> Result<PeerStore, InternalError> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            info!("No peer snapshot found at {}", path);
+            return Ok(PeerStore::new());
+        }
+        Err(e) => {
+            error!("Peer snapshot: {}", e);
+            return Err(InternalError::StoragePeerLoad);
+        }
+    };
+
+    let snapshot = decode(&bytes)?;
+
+    let (records, dropped) = restore_snapshot(snapshot, SystemTime::now(), peer_timeout);
+    let restored: usize = records
+        .values()
+        .map(|sw| sw.seeders.len() + sw.leechers.len())
+        .sum();
+    info!(
+        "Restored {} peers from snapshot, dropped {} expired peers.",
+        restored, dropped
+    );
+
+    Ok(PeerStore::with_records(records))
+}
+
+// Swaps in the swarms from the snapshot. The torrent counts loaded from
+// the database are from the last run and the global totals start at zero,
+// so both are set from the restored swarms before any announce comes in.
+pub async fn restore(
+    state: &mut State,
+    path: &str,
+    peer_timeout: Duration,
+) -> Result<(), InternalError> {
+    state.peer_store = load(path, peer_timeout)?;
+    state
+        .peer_store
+        .reconcile(&state.torrent_store, &state.stats)
+        .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    use actix_web::{test, App};
+
+    use crate::config::Config;
+    use crate::network::get_stats;
+    use crate::storage::{Torrent, TorrentRecords, TorrentStore};
+    use crate::util::temp_path;
+
+    fn peer(peer_id: &[u8; 20], last_announced: Instant) -> Peer {
+        Peer::V4(Peerv4 {
+            peer_id: PeerId(*peer_id),
+            ip: Ipv4Addr::new(10, 0, 0, 1),
+            port: 6881,
+            last_announced,
+            user_id: None,
+            uploaded: 0,
+            downloaded: 0,
+        })
+    }
+
+    #[actix_rt::test]
+    async fn peer_snapshot_round_trip() {
+        let path = temp_path("round-trip", "peers");
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let mut seeder = peer(b"-TR2940-000000000001", Instant::now());
+        seeder.set_user_id(Some(7));
+        if let Peer::V4(p) = &mut seeder {
+            p.uploaded = 6 << 40;
+            p.downloaded = 5 << 40;
+        }
+        let leecher = peer(b"-TR2940-000000000002", Instant::now());
+
+        let peer_store = PeerStore::new();
+        peer_store.put_seeder(info_hash, seeder.clone()).await;
+        peer_store.put_leecher(info_hash, leecher.clone()).await;
+
+        assert_eq!(save(&peer_store, &path).await.unwrap(), 2);
+
+        let restored = load(&path, Duration::from_secs(7200)).unwrap();
+        let swarm = restored.get_swarm(info_hash).await.unwrap();
+
+        let restored_seeder = swarm.seeders.get(&seeder).unwrap();
+        assert_eq!(restored_seeder.user_id(), Some(7));
+        assert_eq!(restored_seeder.transferred(), (6 << 40, 5 << 40));
+        assert!(swarm.leechers.contains(&leecher));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[actix_rt::test]
+    async fn peer_snapshot_restore_sets_counts() {
+        let path = temp_path("restore", "peers");
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let peer_store = PeerStore::new();
+        peer_store
+            .put_seeder(info_hash, peer(b"-TR2940-000000000001", Instant::now()))
+            .await;
+        peer_store
+            .put_leecher(info_hash, peer(b"-TR2940-000000000002", Instant::now()))
+            .await;
+        save(&peer_store, &path).await.unwrap();
+
+        // Counts as the database left them at the end of the last run
+        let mut torrents = TorrentRecords::new();
+        torrents.insert(info_hash, Torrent::new(info_hash, 9, 0, 9, 0));
+        let mut state = State::new(Config::default(), TorrentStore::new(torrents));
+        restore(&mut state, &path, Duration::from_secs(7200))
+            .await
+            .unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            state.torrent_store.get_announce_stats(info_hash).await,
+            (1, 1)
+        );
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/stats", web::get().to(get_stats)),
+        )
+        .await;
+        let req = test::TestRequest::with_uri("/stats").to_request();
+        let resp = test::read_response(&mut app, req).await;
+        let rendered = String::from_utf8(resp.to_vec()).unwrap();
+        assert!(rendered.contains(",\"total_seeders\":1,\"total_leechers\":1,"));
+    }
+
+    #[test]
+    fn peer_snapshot_versions() {
+        let info_hash = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let legacy = LegacyPeerSnapshot {
+            swarms: vec![LegacySnapshotSwarm {
+                info_hash,
+                seeders: vec![LegacySnapshotPeer {
+                    peer_id: PeerId(*b"-TR2940-000000000001"),
+                    ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                    port: 6881,
+                    last_announced: unix_secs(SystemTime::now()),
+                    user_id: Some(7),
+                    uploaded: u32::MAX,
+                    downloaded: 5,
+                }],
+                leechers: vec![],
+            }],
+        };
+
+        // Unversioned snapshots are read with 32-bit totals and widened
+        let snapshot = decode(&bincode::serialize(&legacy).unwrap()).unwrap();
+        let peer = &snapshot.swarms[0].seeders[0];
+        assert_eq!(snapshot.swarms[0].info_hash, info_hash);
+        assert_eq!(
+            (peer.user_id, peer.uploaded, peer.downloaded),
+            (Some(7), u64::from(u32::MAX), 5)
+        );
+
+        let mut bytes = encode(&snapshot).unwrap();
+        assert!(bytes.starts_with(SNAPSHOT_MAGIC));
+        assert_eq!(
+            decode(&bytes).unwrap().swarms[0].seeders[0].uploaded,
+            u64::from(u32::MAX)
+        );
+
+        // Versions this build doesn't know are refused
+        bytes[SNAPSHOT_MAGIC.len()] = 3;
+        assert!(decode(&bytes).is_err());
+        assert!(decode(&SNAPSHOT_MAGIC[..]).is_err());
+    }
+
+    #[test]
+    fn peer_snapshot_drops_expired_peers() {
+        let info_hash1 = InfoHash(*b"A1B2C3D4E5F6G7H8I9J0");
+        let info_hash2 = InfoHash(*b"B2C3D4E5F6G7H8I9J0K1");
+        let now = SystemTime::now();
+
+        let mut snapshot = PeerSnapshot {
+            swarms: vec![
+                SnapshotSwarm {
+                    info_hash: info_hash1,
+                    seeders: vec![snapshot_peer(
+                        &peer(b"-TR2940-000000000001", Instant::now()),
+                        now,
+                    )],
+                    leechers: vec![snapshot_peer(
+                        &peer(b"-TR2940-000000000002", Instant::now()),
+                        now,
+                    )],
+                },
+                SnapshotSwarm {
+                    info_hash: info_hash2,
+                    seeders: vec![snapshot_peer(
+                        &peer(b"-TR2940-000000000003", Instant::now()),
+                        now,
+                    )],
+                    leechers: vec![],
+                },
+            ],
+        };
+
+        // The leecher last announced an hour before the tracker went down
+        snapshot.swarms[0].leechers[0].last_announced -= 3600;
+
+        // Tracker comes back up 30 minutes later with a 75 minute timeout
+        let later = now + Duration::from_secs(1800);
+        let (records, dropped) = restore_snapshot(snapshot, later, Duration::from_secs(4500));
+
+        assert_eq!(dropped, 1);
+        assert_eq!(records.get(&info_hash1).unwrap().seeders.len(), 1);
+        assert!(records.get(&info_hash1).unwrap().leechers.is_empty());
+        assert_eq!(records.get(&info_hash2).unwrap().seeders.len(), 1);
+
+        // Swarms with nobody left in them aren't restored at all
+        let (records, dropped) = restore_snapshot(
+            take_snapshot(&records, later),
+            later + Duration::from_secs(4500),
+            Duration::from_secs(4500),
+        );
+        assert_eq!(dropped, 2);
+        assert!(records.is_empty());
+    }
+}
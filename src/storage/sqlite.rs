@@ -0,0 +1,410 @@
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, Result};
+
+use crate::bittorrent::InfoHash;
+use crate::errors::InternalError;
+use crate::storage::{
+    Snatch, Snatcher, Torrent, TorrentBackend, TorrentRecords, User, UserRecords, UserTransfer,
+};
+
+// Same columns as the MySQL schema in schema.sql
+const CREATE_TORRENTS: &str = "CREATE TABLE IF NOT EXISTS torrents (
+        info_hash VARCHAR(50) NOT NULL PRIMARY KEY,
+        complete INT NOT NULL,
+        downloaded INT NOT NULL,
+        incomplete INT NOT NULL,
+        balance BIGINT NOT NULL
+)";
+
+const CREATE_USERS: &str = "CREATE TABLE IF NOT EXISTS users (
+        id INT NOT NULL PRIMARY KEY,
+        passkey VARCHAR(32) NOT NULL UNIQUE,
+        revoked BOOLEAN NOT NULL DEFAULT FALSE,
+        uploaded BIGINT NOT NULL DEFAULT 0,
+        downloaded BIGINT NOT NULL DEFAULT 0
+)";
+
+const CREATE_SNATCHES: &str = "CREATE TABLE IF NOT EXISTS snatches (
+        info_hash VARCHAR(50) NOT NULL,
+        kind VARCHAR(4) NOT NULL,
+        user_id INT NOT NULL DEFAULT 0,
+        peer_id VARCHAR(40) NOT NULL DEFAULT '',
+        completed_at BIGINT NOT NULL,
+        PRIMARY KEY (info_hash, kind, user_id, peer_id)
+)";
+
+// SQLite connections can't be shared between threads,
+// so every call takes its turn on the one connection.
+pub struct SqliteBackend {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    // The database file is created along with the torrents,
+    // users and snatches tables if any are missing
+    pub fn new(path: &str) -> Result<SqliteBackend> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(CREATE_TORRENTS)?;
+        conn.execute_batch(CREATE_USERS)?;
+        conn.execute_batch(CREATE_SNATCHES)?;
+
+        Ok(SqliteBackend {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn query_torrents(conn: &Connection) -> Result<TorrentRecords> {
+        let mut torrents = TorrentRecords::new();
+
+        let mut stmt = conn
+            .prepare("SELECT info_hash, complete, downloaded, incomplete, balance FROM torrents")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get::<_, i64>(4)?.max(0) as u64,
+            ))
+        })?;
+
+        // Info hashes are stored in their hex form; rows
+        // that can't be decoded are skipped rather than fatal
+        for row in rows {
+            let (info_hash, complete, downloaded, incomplete, balance) = row?;
+            match InfoHash::from_bytes(info_hash.as_bytes()) {
+                Some(info_hash) => {
+                    torrents.insert(
+                        info_hash,
+                        Torrent {
+                            info_hash,
+                            complete,
+                            downloaded,
+                            incomplete,
+                            balance,
+                        },
+                    );
+                }
+                None => warn!("Skipping torrent with invalid info hash: {}", info_hash),
+            }
+        }
+
+        Ok(torrents)
+    }
+
+    // Balances past i64::MAX are stored as i64::MAX
+    fn upsert_torrents(conn: &mut Connection, torrents: &[Torrent]) -> Result<()> {
+        let tx = conn.transaction()?;
+
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO torrents (info_hash, complete, downloaded, incomplete, balance)
+                        VALUES (?1, ?2, ?3, ?4, ?5)
+                        ON CONFLICT (info_hash) DO UPDATE SET
+                            complete=excluded.complete,
+                            downloaded=excluded.downloaded,
+                            incomplete=excluded.incomplete,
+                            balance=excluded.balance",
+            )?;
+
+            for torrent in torrents {
+                stmt.execute(params![
+                    torrent.info_hash.to_string(),
+                    torrent.complete,
+                    torrent.downloaded,
+                    torrent.incomplete,
+                    i64::try_from(torrent.balance).unwrap_or(i64::MAX),
+                ])?;
+            }
+        }
+
+        tx.commit()
+    }
+
+    fn remove_torrents(conn: &mut Connection, info_hashes: &[InfoHash]) -> Result<()> {
+        let tx = conn.transaction()?;
+
+        {
+            let mut stmt = tx.prepare("DELETE FROM torrents WHERE info_hash = ?1")?;
+            let mut snatches = tx.prepare("DELETE FROM snatches WHERE info_hash = ?1")?;
+            for info_hash in info_hashes {
+                stmt.execute(params![info_hash.to_string()])?;
+                snatches.execute(params![info_hash.to_string()])?;
+            }
+        }
+
+        tx.commit()
+    }
+
+    fn query_users(conn: &Connection) -> Result<UserRecords> {
+        let mut stmt =
+            conn.prepare("SELECT id, passkey, revoked, uploaded, downloaded FROM users")?;
+        let rows = stmt.query_map([], |row| {
+            let passkey: String = row.get(1)?;
+            Ok((
+                passkey.clone(),
+                User {
+                    id: row.get(0)?,
+                    passkey,
+                    revoked: row.get(2)?,
+                    uploaded: row.get::<_, i64>(3)?.max(0) as u64,
+                    downloaded: row.get::<_, i64>(4)?.max(0) as u64,
+                },
+            ))
+        })?;
+
+        rows.collect()
+    }
+
+    // SQLite integers are signed, so totals past i64::MAX stop growing there
+    fn add_user_transfers(conn: &mut Connection, transfers: &[UserTransfer]) -> Result<()> {
+        let tx = conn.transaction()?;
+
+        {
+            let mut stmt = tx.prepare(
+                "UPDATE users SET
+                        uploaded = uploaded + ?2,
+                        downloaded = downloaded + ?3
+                        WHERE id = ?1",
+            )?;
+            for transfer in transfers {
+                stmt.execute(params![
+                    transfer.user_id,
+                    i64::try_from(transfer.uploaded).unwrap_or(i64::MAX),
+                    i64::try_from(transfer.downloaded).unwrap_or(i64::MAX),
+                ])?;
+            }
+        }
+
+        tx.commit()
+    }
+
+    fn query_snatches(conn: &Connection) -> Result<Vec<Snatch>> {
+        let mut stmt =
+            conn.prepare("SELECT info_hash, kind, user_id, peer_id, completed_at FROM snatches")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        })?;
+
+        let mut snatches = Vec::new();
+        for row in rows {
+            let (info_hash, kind, user_id, peer_id, completed_at) = row?;
+            match (
+                InfoHash::from_bytes(info_hash.as_bytes()),
+                Snatcher::from_columns(&kind, user_id, &peer_id),
+            ) {
+                (Some(info_hash), Some(snatcher)) => snatches.push(Snatch {
+                    info_hash,
+                    snatcher,
+                    completed_at: completed_at.max(0) as u64,
+                }),
+                _ => warn!("Skipping invalid snatch row: {}", info_hash),
+            }
+        }
+
+        Ok(snatches)
+    }
+
+    fn insert_snatches(conn: &mut Connection, snatches: &[Snatch]) -> Result<()> {
+        let tx = conn.transaction()?;
+
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO snatches (info_hash, kind, user_id, peer_id, completed_at)
+                        VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for snatch in snatches {
+                let (kind, user_id, peer_id) = snatch.snatcher.to_columns();
+                stmt.execute(params![
+                    snatch.info_hash.to_string(),
+                    kind,
+                    user_id,
+                    peer_id,
+                    i64::try_from(snatch.completed_at).unwrap_or(i64::MAX),
+                ])?;
+            }
+        }
+
+        tx.commit()
+    }
+}
+
+impl TorrentBackend for SqliteBackend {
+    fn load_torrents(&self) -> std::result::Result<TorrentRecords, InternalError> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| InternalError::StorageTorrentLoad)?;
+
+        SqliteBackend::query_torrents(&conn).map_err(|e| {
+            error!("SQLite: {}", e);
+            InternalError::StorageTorrentLoad
+        })
+    }
+
+    fn flush_torrents(&self, torrents: Vec<Torrent>) -> std::result::Result<(), InternalError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| InternalError::StorageTorrentFlush)?;
+
+        SqliteBackend::upsert_torrents(&mut conn, &torrents).map_err(|e| {
+            error!("SQLite: {}", e);
+            InternalError::StorageTorrentFlush
+        })
+    }
+
+    fn fetch_new_torrents(&self) -> std::result::Result<TorrentRecords, InternalError> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| InternalError::StorageTorrentFetchNew)?;
+
+        SqliteBackend::query_torrents(&conn).map_err(|e| {
+            error!("SQLite: {}", e);
+            InternalError::StorageTorrentFetchNew
+        })
+    }
+
+    fn delete_torrents(
+        &self,
+        info_hashes: Vec<InfoHash>,
+    ) -> std::result::Result<(), InternalError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| InternalError::StorageTorrentDelete)?;
+
+        SqliteBackend::remove_torrents(&mut conn, &info_hashes).map_err(|e| {
+            error!("SQLite: {}", e);
+            InternalError::StorageTorrentDelete
+        })
+    }
+
+    fn load_users(&self) -> std::result::Result<UserRecords, InternalError> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| InternalError::StorageUserLoad)?;
+
+        SqliteBackend::query_users(&conn).map_err(|e| {
+            error!("SQLite: {}", e);
+            InternalError::StorageUserLoad
+        })
+    }
+
+    fn flush_user_transfers(
+        &self,
+        transfers: Vec<UserTransfer>,
+    ) -> std::result::Result<(), InternalError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| InternalError::StorageUserFlush)?;
+
+        SqliteBackend::add_user_transfers(&mut conn, &transfers).map_err(|e| {
+            error!("SQLite: {}", e);
+            InternalError::StorageUserFlush
+        })
+    }
+
+    fn load_snatches(&self) -> std::result::Result<Vec<Snatch>, InternalError> {
+        let conn = self
+            .conn
+            .lock()
+            .map_err(|_| InternalError::StorageSnatchLoad)?;
+
+        SqliteBackend::query_snatches(&conn).map_err(|e| {
+            error!("SQLite: {}", e);
+            InternalError::StorageSnatchLoad
+        })
+    }
+
+    fn flush_snatches(&self, snatches: Vec<Snatch>) -> std::result::Result<(), InternalError> {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| InternalError::StorageSnatchFlush)?;
+
+        SqliteBackend::insert_snatches(&mut conn, &snatches).map_err(|e| {
+            error!("SQLite: {}", e);
+            InternalError::StorageSnatchFlush
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::conformance;
+    use crate::util::temp_path;
+
+    #[test]
+    fn sqlite_backend_flush_and_load() {
+        let path = temp_path("flush", "db");
+        let backend = SqliteBackend::new(&path).unwrap();
+
+        // Read back through a second connection so that
+        // torrents have to have been written to the file
+        conformance::flush_and_load(&backend, &SqliteBackend::new(&path).unwrap());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn sqlite_backend_fetch_new_and_delete() {
+        let path = temp_path("delete", "db");
+        let backend = SqliteBackend::new(&path).unwrap();
+
+        conformance::fetch_new_and_delete(&backend, &|info_hash| {
+            Connection::open(&path)
+                .unwrap()
+                .execute(
+                    "INSERT INTO torrents VALUES (?1, 0, 0, 0, 0)",
+                    params![info_hash.to_string()],
+                )
+                .unwrap();
+        });
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn sqlite_backend_users() {
+        let path = temp_path("users", "db");
+        let backend = SqliteBackend::new(&path).unwrap();
+        assert!(backend.load_users().unwrap().is_empty());
+
+        let conn = Connection::open(&path).unwrap();
+        for user in conformance::user_records().values() {
+            conn.execute(
+                "INSERT INTO users (id, passkey, revoked) VALUES (?1, ?2, ?3)",
+                params![user.id, user.passkey, user.revoked],
+            )
+            .unwrap();
+        }
+
+        conformance::users(&backend);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn sqlite_backend_snatches() {
+        let path = temp_path("snatches", "db");
+        let backend = SqliteBackend::new(&path).unwrap();
+
+        conformance::snatches(&backend, &SqliteBackend::new(&path).unwrap());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,3 +1,5 @@
+use percent_encoding::percent_decode;
+
 use crate::errors::ClientError;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -27,9 +29,62 @@ pub fn event_to_string(event: Event) -> &'static str {
     }
 }
 
+// Splits a query string into keys and raw values. Unlike form_urlencoded,
+// values are not forced through UTF-8, which would mangle binary
+// parameters such as info_hash and peer_id.
+pub fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                String::from_utf8_lossy(&decode_component(key)).into_owned(),
+                decode_component(value),
+            )
+        })
+        .collect()
+}
+
+fn decode_component(component: &str) -> Vec<u8> {
+    let unplussed = component.replace('+', " ");
+    percent_decode(unplussed.as_bytes()).collect()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    hex.chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+// A path for a test file that is unique to this test process,
+// with anything left behind by an earlier run cleared away
+#[cfg(test)]
+pub fn temp_path(name: &str, extension: &str) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "tyto-{}-{}.{}",
+        name,
+        std::process::id(),
+        extension
+    ));
+    let _ = std::fs::remove_file(&path);
+    path.to_str().unwrap().to_string()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{event_to_string, string_to_event, Event};
+    use super::{event_to_string, from_hex, parse_query, string_to_event, to_hex, Event};
 
     #[test]
     fn event_string_to_event_good() {
@@ -51,4 +106,24 @@ mod tests {
         let event = Event::Completed;
         assert_eq!(event_to_string(event), "completed");
     }
+
+    #[test]
+    fn query_binary_values() {
+        let query = "info_hash=%90%28%9F%D3M%FC&peer_id=-TR2940-abc+def&empty";
+        let pairs = parse_query(query);
+
+        assert_eq!(pairs[0].0, "info_hash");
+        assert_eq!(pairs[0].1, vec![0x90, 0x28, 0x9f, 0xd3, b'M', 0xfc]);
+        assert_eq!(pairs[1].1, b"-TR2940-abc def".to_vec());
+        assert_eq!(pairs[2], ("empty".to_string(), vec![]));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = vec![0x00, 0x2f, 0xa9, 0xff];
+        assert_eq!(to_hex(&bytes), "002fa9ff");
+        assert_eq!(from_hex(b"002fa9ff").unwrap(), bytes);
+        assert!(from_hex(b"00z").is_none());
+        assert!(from_hex(b"zz").is_none());
+    }
 }